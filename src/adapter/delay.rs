@@ -1,16 +1,22 @@
-use crate::priv_prelude::*;
+use crate::{
+    priv_prelude::*,
+    adapter::Direction,
+};
 
 /// `Sink`/`Stream` adapter which adds a time delay to items sent/received through the
 /// `Sink`/`Stream`.
 ///
-/// Can be created via [`SinkStreamExt::with_delay`](crate::SinkStreamExt::with_delay).
+/// Can be created via [`SinkStreamExt::with_delay`](crate::SinkStreamExt::with_delay). Use
+/// [`handle`](crate::adapter::Delay::handle) to change the delay parameters while items are
+/// flowing. Use [`direction`](crate::adapter::Delay::direction) to only delay items travelling
+/// one way.
 #[pin_project]
 pub struct Delay<S, T>
 where
     S: Stream + Sink<T>,
 {
-    min_delay: Duration,
-    mean_additional_delay: Duration,
+    params: Arc<Mutex<DelayParams>>,
+    direction_opt: Option<Direction>,
     stream_finished: bool,
     #[pin]
     stream: S,
@@ -20,8 +26,30 @@ where
     sink_queue: DelayQueue<T>,
 }
 
+/// A handle to a [`Delay`](crate::adapter::Delay) which can be used to change its parameters at
+/// runtime. Changes only affect items sent/received after the change is made.
+#[derive(Clone)]
+pub struct DelayHandle {
+    params: Arc<Mutex<DelayParams>>,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct DelayParams {
+    pub min_delay: Duration,
+    pub mean_additional_delay: Duration,
+}
+
+impl DelayParams {
+    pub fn sample_delay(self) -> Duration {
+        self.min_delay + adapter::expovariate_duration(
+            self.mean_additional_delay,
+            &mut rand::thread_rng(),
+        )
+    }
+}
+
 #[pin_project]
-struct DelayQueue<T> {
+pub(crate) struct DelayQueue<T> {
     #[pin]
     sleep_opt: Option<tokio::time::Sleep>,
     pending: BTreeMap<Instant, VecDeque<T>>,
@@ -35,6 +63,10 @@ impl<T> DelayQueue<T> {
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
     pub fn push(self: Pin<&mut Self>, delay: Duration, value: T) {
         let mut this = self.project();
         let instant = Instant::now() + delay;
@@ -88,15 +120,54 @@ where
     /// Creates a new [`Delay`]. See the documentation for
     /// [`SinkStreamExt::with_delay`](crate::SinkStreamExt::with_delay).
     pub fn new(stream: S, min_delay: Duration, mean_additional_delay: Duration) -> Delay<S, T> {
+        let params = DelayParams { min_delay, mean_additional_delay };
         Delay {
-            min_delay,
-            mean_additional_delay,
+            params: Arc::new(Mutex::new(params)),
+            direction_opt: None,
             stream,
             stream_finished: false,
             stream_queue: DelayQueue::new(),
             sink_queue: DelayQueue::new(),
         }
     }
+
+    /// Only delay items travelling in the given direction. Items travelling the other way pass
+    /// through immediately.
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction_opt = Some(direction);
+        self
+    }
+
+    /// Gets a handle which can be used to change the delay parameters of this `Delay`.
+    pub fn handle(&self) -> DelayHandle {
+        DelayHandle::new(self.params.clone())
+    }
+}
+
+impl DelayHandle {
+    pub(crate) fn new(params: Arc<Mutex<DelayParams>>) -> DelayHandle {
+        DelayHandle { params }
+    }
+
+    /// The minimum delay applied to all items.
+    pub fn min_delay(&self) -> Duration {
+        self.params.lock().unwrap().min_delay
+    }
+
+    /// The average randomized delay applied to all items in addition to the minimum delay.
+    pub fn mean_additional_delay(&self) -> Duration {
+        self.params.lock().unwrap().mean_additional_delay
+    }
+
+    /// Sets the minimum delay applied to all items.
+    pub fn set_min_delay(&self, min_delay: Duration) {
+        self.params.lock().unwrap().min_delay = min_delay;
+    }
+
+    /// Sets the average randomized delay applied to all items in addition to the minimum delay.
+    pub fn set_mean_additional_delay(&self, mean_additional_delay: Duration) {
+        self.params.lock().unwrap().mean_additional_delay = mean_additional_delay;
+    }
 }
 
 impl<S, T> Stream for Delay<S, T>
@@ -107,14 +178,14 @@ where
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
+        if *this.direction_opt == Some(Direction::Sent) {
+            return this.stream.poll_next(cx);
+        }
         if !*this.stream_finished {
             loop {
                 match this.stream.as_mut().poll_next(cx) {
                     Poll::Ready(Some(value)) => {
-                        let delay = *this.min_delay + adapter::expovariate_duration(
-                            *this.mean_additional_delay,
-                            &mut rand::thread_rng(),
-                        );
+                        let delay = this.params.lock().unwrap().sample_delay();
                         this.stream_queue.as_mut().push(delay, value);
                     },
                     Poll::Ready(None) => {
@@ -145,16 +216,20 @@ where
 {
     type Error = <S as Sink<T>>::Error;
 
-    fn poll_ready(self: Pin<&mut Self>, _cx: &mut task::Context) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.project();
+        if *this.direction_opt == Some(Direction::Received) {
+            return this.stream.poll_ready(cx);
+        }
         Poll::Ready(Ok(()))
     }
 
     fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
         let this = self.project();
-        let delay = *this.min_delay + adapter::expovariate_duration(
-            *this.mean_additional_delay,
-            &mut rand::thread_rng(),
-        );
+        if *this.direction_opt == Some(Direction::Received) {
+            return this.stream.start_send(item);
+        }
+        let delay = this.params.lock().unwrap().sample_delay();
         this.sink_queue.push(delay, item);
         Ok(())
     }
@@ -0,0 +1,282 @@
+use {
+    crate::{
+        priv_prelude::*,
+        adapter::{
+            Direction, PacketMatch,
+            process::{process_adapter, PacketOutput, ProcessPackets},
+        },
+        packet::IpProtocol,
+    },
+    std::ops::Range,
+};
+
+process_adapter! {
+    /// `Sink`/`Stream` adapter which injects faults into specific packets according to a list of
+    /// [`FaultRule`]s.
+    ///
+    /// Each rule counts the packets it matches separately for each flow, where a flow is
+    /// identified by its protocol and the addresses and ports of its two ends, so that packets
+    /// travelling in both directions belong to the same flow. When a rule's
+    /// [`Occurrence`] says it should fire, its action is applied to the packet and no later rules
+    /// are checked. Packets which don't cause any rule to fire pass through unaffected.
+    ///
+    /// Can be created via
+    /// [`SinkStreamExt::with_fault_injection`](crate::SinkStreamExt::with_fault_injection).
+    pub struct FaultInjector<S>(FaultInjectorProcessor)
+}
+
+/// A rule for a [`FaultInjector`](crate::adapter::FaultInjector).
+#[derive(Debug, Clone)]
+pub struct FaultRule {
+    packet_match: PacketMatch,
+    occurrence: Occurrence,
+    action: FaultAction,
+}
+
+/// Which of the packets matched by a [`FaultRule`](crate::adapter::FaultRule) in a flow it fires
+/// on. Occurrences are counted from one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Occurrence {
+    /// Fire on every matching packet.
+    Every,
+    /// Fire only on the nth matching packet.
+    Nth(u64),
+    /// Fire on every kth matching packet, ie. the kth, 2kth, 3kth, etc.
+    EveryKth(u64),
+    /// Fire on the first n matching packets.
+    FirstN(u64),
+}
+
+/// What a [`FaultInjector`](crate::adapter::FaultInjector) does to a packet when a rule fires.
+#[derive(Clone)]
+pub enum FaultAction {
+    /// Drop the packet.
+    Drop,
+    /// Forward the packet after the given delay. Later packets are not held back, so this can
+    /// reorder packets.
+    Delay(Duration),
+    /// Forward the packet twice.
+    Duplicate,
+    /// Flip a random bit in the packet's transport payload so that its checksum no longer matches.
+    /// TCP, UDP and ICMP packets with no payload have a bit flipped in their checksum instead, so
+    /// headers which say how the packet is parsed are never touched. Packets of other protocols
+    /// with no payload have a bit flipped in one of the IPv4 header fields which don't affect how
+    /// the packet is parsed or where it's delivered, so that the header checksum no longer
+    /// matches. IPv6 headers aren't covered by a checksum, so such IPv6 packets are forwarded
+    /// unchanged.
+    Corrupt,
+    /// Modify the packet using the given function before forwarding it. The function is
+    /// responsible for keeping the packet well-formed.
+    Modify(Arc<dyn Fn(&mut IpPacket) + Send + Sync>),
+}
+
+/// A handle to a [`FaultInjector`](crate::adapter::FaultInjector) which can be used to read how
+/// many times each rule has fired.
+#[derive(Clone)]
+pub struct FaultInjectorHandle {
+    fire_counts: Arc<Mutex<Vec<u64>>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct FlowKey {
+    protocol: IpProtocol,
+    ends: [(IpAddr, u16); 2],
+}
+
+struct FaultInjectorProcessor {
+    rules: Vec<FaultRule>,
+    match_counts: HashMap<(usize, FlowKey), u64>,
+    fire_counts: Arc<Mutex<Vec<u64>>>,
+}
+
+impl fmt::Debug for FaultAction {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FaultAction::Drop => write!(formatter, "Drop"),
+            FaultAction::Delay(delay) => write!(formatter, "Delay({:?})", delay),
+            FaultAction::Duplicate => write!(formatter, "Duplicate"),
+            FaultAction::Corrupt => write!(formatter, "Corrupt"),
+            FaultAction::Modify(_) => write!(formatter, "Modify(..)"),
+        }
+    }
+}
+
+impl FaultAction {
+    /// Creates a [`FaultAction::Modify`](crate::adapter::FaultAction::Modify) from a function.
+    pub fn modify<F>(modify: F) -> FaultAction
+    where
+        F: Fn(&mut IpPacket) + Send + Sync + 'static,
+    {
+        FaultAction::Modify(Arc::new(modify))
+    }
+}
+
+impl Occurrence {
+    fn fires(self, count: u64) -> bool {
+        match self {
+            Occurrence::Every => true,
+            Occurrence::Nth(n) => count == n,
+            Occurrence::EveryKth(k) => k != 0 && count.is_multiple_of(k),
+            Occurrence::FirstN(n) => count <= n,
+        }
+    }
+}
+
+impl FaultRule {
+    /// Creates a rule which applies `action` to every packet matching `packet_match`. Use
+    /// [`occurrence`](crate::adapter::FaultRule::occurrence) to only fire on some of them.
+    pub fn new(packet_match: PacketMatch, action: FaultAction) -> FaultRule {
+        FaultRule {
+            packet_match,
+            occurrence: Occurrence::Every,
+            action,
+        }
+    }
+
+    /// Sets which of the matching packets in each flow the rule fires on.
+    pub fn occurrence(mut self, occurrence: Occurrence) -> FaultRule {
+        self.occurrence = occurrence;
+        self
+    }
+}
+
+impl FlowKey {
+    fn new(packet: &IpPacket) -> FlowKey {
+        let (source_port, destination_port) = packet.ports().unwrap_or((0, 0));
+        let mut ends = [
+            (packet.source_addr(), source_port),
+            (packet.destination_addr(), destination_port),
+        ];
+        ends.sort();
+        FlowKey {
+            protocol: packet.protocol(),
+            ends,
+        }
+    }
+}
+
+impl<S> FaultInjector<S>
+where
+    S: IpSinkStream,
+{
+    /// Creates a new [`FaultInjector`]. See the documentation for
+    /// [`SinkStreamExt::with_fault_injection`](crate::SinkStreamExt::with_fault_injection).
+    pub fn new(stream: S, rules: Vec<FaultRule>) -> FaultInjector<S> {
+        let processor = FaultInjectorProcessor {
+            fire_counts: Arc::new(Mutex::new(vec![0; rules.len()])),
+            rules,
+            match_counts: HashMap::new(),
+        };
+        FaultInjector {
+            process: adapter::process::Process::new(stream, processor),
+        }
+    }
+
+    /// Gets a handle which can be used to read how many times each rule has fired.
+    pub fn handle(&self) -> FaultInjectorHandle {
+        FaultInjectorHandle {
+            fire_counts: self.process.processor().fire_counts.clone(),
+        }
+    }
+}
+
+impl FaultInjectorHandle {
+    /// The number of times that each rule has fired, in the same order as the rules.
+    pub fn fire_counts(&self) -> Vec<u64> {
+        self.fire_counts.lock().unwrap().clone()
+    }
+}
+
+/// The offsets of the IPv4 header bytes which `corrupt` may flip in a packet whose transport
+/// protocol it doesn't know: the type of service, identification, TTL and header checksum. The
+/// version, lengths, fragmentation fields and protocol are left alone so that the packet can still
+/// be parsed, and the addresses are left alone so that it still reaches the same host.
+const CORRUPTIBLE_IPV4_HEADER_BYTES: [usize; 6] = [1, 4, 5, 8, 10, 11];
+
+/// Returns where the transport payload of `packet` starts and where its transport checksum is, or
+/// `None` if its transport protocol isn't known or its transport header is truncated.
+fn transport_layout(packet: &IpPacket) -> Option<(usize, Range<usize>)> {
+    let header_start = packet.transport_header_offset();
+    let (header_len, checksum_offset) = match packet.protocol() {
+        IpProtocol::Tcp => {
+            let data_offset = *packet.as_bytes().get(header_start + 12)?;
+            ((data_offset >> 4) as usize * 4, 16)
+        },
+        IpProtocol::Udp => (8, 6),
+        IpProtocol::Icmp => (8, 2),
+        IpProtocol::Other(_) => return None,
+    };
+    let payload_start = header_start + header_len;
+    if payload_start > packet.len() {
+        return None;
+    }
+    let checksum_start = header_start + checksum_offset;
+    Some((payload_start, checksum_start..(checksum_start + 2)))
+}
+
+fn corrupt(packet: &mut IpPacket) {
+    let mut rng = rand::thread_rng();
+    let index = match transport_layout(packet) {
+        Some((payload_start, _)) if payload_start < packet.len() => {
+            rng.gen_range(payload_start..packet.len())
+        },
+        Some((_, checksum_range)) => rng.gen_range(checksum_range),
+        None => {
+            let payload_start = cmp::min(packet.transport_header_offset(), packet.len());
+            if payload_start < packet.len() {
+                rng.gen_range(payload_start..packet.len())
+            } else {
+                match packet.version_ref() {
+                    IpPacketVersion::V4(_) => {
+                        CORRUPTIBLE_IPV4_HEADER_BYTES[rng.gen_range(0..CORRUPTIBLE_IPV4_HEADER_BYTES.len())]
+                    },
+                    IpPacketVersion::V6(_) => return,
+                }
+            }
+        },
+    };
+    let bit = rng.gen_range(0..8);
+    packet.as_bytes_mut()[index] ^= 1 << bit;
+}
+
+impl ProcessPackets for FaultInjectorProcessor {
+    fn process(&mut self, direction: Direction, mut packet: Box<IpPacket>, output: &mut PacketOutput) {
+        let flow_key = FlowKey::new(&packet);
+        let mut fired_index_opt = None;
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.packet_match.matches(direction, &packet) {
+                continue;
+            }
+            let count = self.match_counts.entry((index, flow_key)).or_insert(0);
+            *count += 1;
+            if rule.occurrence.fires(*count) {
+                fired_index_opt = Some(index);
+                break;
+            }
+        }
+        let Some(index) = fired_index_opt else {
+            output.forward(packet);
+            return;
+        };
+        self.fire_counts.lock().unwrap()[index] += 1;
+        if log_enabled!(Level::Debug) {
+            debug!("fault rule #{} fired on {:?}", index, packet);
+        }
+        match &self.rules[index].action {
+            FaultAction::Drop => (),
+            FaultAction::Delay(delay) => output.forward_after(*delay, packet),
+            FaultAction::Duplicate => {
+                output.forward(packet.clone());
+                output.forward(packet);
+            },
+            FaultAction::Corrupt => {
+                corrupt(&mut packet);
+                output.forward(packet);
+            },
+            FaultAction::Modify(modify) => {
+                modify(&mut packet);
+                output.forward(packet);
+            },
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{
+        Direction, DropReason, PacketMatch, StatsHandle,
+        process::{process_adapter, PacketOutput, ProcessPackets},
+    },
+    packet::{Icmpv4Packet, Icmpv6Packet},
+};
+
+process_adapter! {
+    /// `Sink`/`Stream` adapter which filters packets according to an ordered list of
+    /// [`FirewallRule`]s.
+    ///
+    /// Each packet is checked against the rules in order and the action of the first matching rule
+    /// is applied. Packets which don't match any rule are accepted unless a different
+    /// [`default_action`](crate::adapter::Firewall::default_action) is set. Rejections are sent
+    /// from the rejected packet's destination address.
+    ///
+    /// Can be created via [`SinkStreamExt::with_firewall`](crate::SinkStreamExt::with_firewall).
+    pub struct Firewall<S>(FirewallProcessor)
+}
+
+/// A rule for a [`Firewall`](crate::adapter::Firewall).
+#[derive(Debug, Clone)]
+pub struct FirewallRule {
+    packet_match: PacketMatch,
+    action: FirewallAction,
+}
+
+/// What a [`Firewall`](crate::adapter::Firewall) does with a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirewallAction {
+    /// Let the packet through.
+    Accept,
+    /// Silently drop the packet.
+    Drop,
+    /// Drop the packet and reply with an ICMP port unreachable error, or with a protocol
+    /// unreachable error if it's neither TCP nor UDP. ICMPv6 has no protocol unreachable code, so
+    /// IPv6 packets which are neither TCP nor UDP are answered with communication
+    /// administratively prohibited instead.
+    RejectWithIcmpUnreachable,
+    /// Drop the packet and, if it's an IPv4 TCP packet, reply with a TCP reset. Other packets,
+    /// including IPv6 TCP packets, are rejected as with
+    /// [`RejectWithIcmpUnreachable`](crate::adapter::FirewallAction::RejectWithIcmpUnreachable).
+    RejectWithTcpReset,
+}
+
+/// A handle to a [`Firewall`](crate::adapter::Firewall) which can be used to read how many
+/// packets each rule has matched.
+#[derive(Clone)]
+pub struct FirewallHandle {
+    hit_counts: Arc<Mutex<Vec<u64>>>,
+}
+
+struct FirewallProcessor {
+    rules: Vec<FirewallRule>,
+    default_action: FirewallAction,
+    hit_counts: Arc<Mutex<Vec<u64>>>,
+    drop_stats_opt: Option<StatsHandle>,
+}
+
+impl FirewallRule {
+    /// Creates a rule which applies `action` to all packets matching `packet_match`.
+    pub fn new(packet_match: PacketMatch, action: FirewallAction) -> FirewallRule {
+        FirewallRule { packet_match, action }
+    }
+}
+
+impl<S> Firewall<S>
+where
+    S: IpSinkStream,
+{
+    /// Creates a new [`Firewall`]. See the documentation for
+    /// [`SinkStreamExt::with_firewall`](crate::SinkStreamExt::with_firewall).
+    pub fn new(stream: S, rules: Vec<FirewallRule>) -> Firewall<S> {
+        let processor = FirewallProcessor {
+            hit_counts: Arc::new(Mutex::new(vec![0; rules.len()])),
+            rules,
+            default_action: FirewallAction::Accept,
+            drop_stats_opt: None,
+        };
+        Firewall {
+            process: adapter::process::Process::new(stream, processor),
+        }
+    }
+
+    /// Sets the action applied to packets which don't match any rule. Defaults to
+    /// [`FirewallAction::Accept`].
+    pub fn default_action(mut self, action: FirewallAction) -> Self {
+        self.process.processor_mut().default_action = action;
+        self
+    }
+
+    /// Records packets dropped or rejected by this `Firewall` against the given
+    /// [`Stats`](crate::adapter::Stats) handle as
+    /// [`DropReason::Filtered`](crate::adapter::DropReason::Filtered).
+    pub fn count_drops(mut self, stats: &StatsHandle) -> Self {
+        self.process.processor_mut().drop_stats_opt = Some(stats.clone());
+        self
+    }
+
+    /// Gets a handle which can be used to read the rules' hit counters.
+    pub fn handle(&self) -> FirewallHandle {
+        FirewallHandle {
+            hit_counts: self.process.processor().hit_counts.clone(),
+        }
+    }
+}
+
+impl FirewallHandle {
+    /// The number of packets that each rule has matched, in the same order as the rules.
+    pub fn hit_counts(&self) -> Vec<u64> {
+        self.hit_counts.lock().unwrap().clone()
+    }
+
+    /// Resets all hit counters to zero.
+    pub fn reset_hit_counts(&self) {
+        for hit_count in self.hit_counts.lock().unwrap().iter_mut() {
+            *hit_count = 0;
+        }
+    }
+}
+
+impl ProcessPackets for FirewallProcessor {
+    fn process(&mut self, direction: Direction, packet: Box<IpPacket>, output: &mut PacketOutput) {
+        let action = match self.rules.iter().position(|rule| rule.packet_match.matches(direction, &packet)) {
+            Some(index) => {
+                self.hit_counts.lock().unwrap()[index] += 1;
+                self.rules[index].action
+            },
+            None => self.default_action,
+        };
+        if action != FirewallAction::Accept {
+            if let Some(stats) = &self.drop_stats_opt {
+                stats.record_drop(direction, DropReason::Filtered, &packet);
+            }
+        }
+        match action {
+            FirewallAction::Accept => output.forward(packet),
+            FirewallAction::Drop => (),
+            FirewallAction::RejectWithIcmpUnreachable => {
+                if let Some(reply) = icmp_unreachable_reply(&packet) {
+                    output.reply(reply);
+                }
+            },
+            FirewallAction::RejectWithTcpReset => {
+                if let IpPacketVersion::V4(ipv4_packet) = packet.version_ref() {
+                    if let Ipv4PacketProtocol::Tcp(tcp_packet) = ipv4_packet.protocol_ref() {
+                        if let Some(reply) = tcp_reset_reply(tcp_packet) {
+                            output.reply(reply);
+                        }
+                        return;
+                    }
+                }
+                if let Some(reply) = icmp_unreachable_reply(&packet) {
+                    output.reply(reply);
+                }
+            },
+        }
+    }
+}
+
+fn icmp_unreachable_reply(packet: &IpPacket) -> Option<Box<IpPacket>> {
+    match packet.version_ref() {
+        IpPacketVersion::V4(packet) => {
+            // 3 is port unreachable, 2 is protocol unreachable.
+            let code = match packet.protocol_ref() {
+                Ipv4PacketProtocol::Icmp(icmp_packet) if icmp_packet.is_error() => return None,
+                Ipv4PacketProtocol::Tcp(_) | Ipv4PacketProtocol::Udp(_) => 3,
+                _ => 2,
+            };
+            let reply = Icmpv4Packet::new_destination_unreachable(packet.destination_addr(), code, packet);
+            Some(reply.ip_packet_box())
+        },
+        IpPacketVersion::V6(packet) => {
+            // 4 is port unreachable, 1 is communication administratively prohibited.
+            let code = match packet.protocol_ref() {
+                Ipv6PacketProtocol::Icmp(icmp_packet) if icmp_packet.is_error() => return None,
+                Ipv6PacketProtocol::Unknown { protocol_number: 6 | 17 } => 4,
+                _ => 1,
+            };
+            let reply = Icmpv6Packet::new_destination_unreachable(packet.destination_addr(), code, packet);
+            Some(reply.ip_packet_box())
+        },
+    }
+}
+
+fn tcp_reset_reply(packet: &Tcpv4Packet) -> Option<Box<IpPacket>> {
+    let flags = packet.flags();
+    if flags.rst {
+        return None;
+    }
+    let mut reset = Tcpv4Packet::new();
+    reset.set_source_addr(packet.destination_addr());
+    reset.set_destination_addr(packet.source_addr());
+    if flags.ack {
+        reset.set_seq_number(packet.ack_number());
+        reset.set_flags(TcpPacketFlags {
+            rst: true,
+            .. TcpPacketFlags::default()
+        });
+    } else {
+        let segment_len = packet.data().len() + usize::from(flags.syn) + usize::from(flags.fin);
+        reset.set_ack_number(packet.seq_number().wrapping_add(segment_len as u32));
+        reset.set_flags(TcpPacketFlags {
+            rst: true,
+            ack: true,
+            .. TcpPacketFlags::default()
+        });
+    }
+    Some(reset.ip_packet_box())
+}
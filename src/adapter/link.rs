@@ -0,0 +1,287 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{
+        Direction, DelayHandle, DropReason, EcnMarking, LossHandle, StatsHandle,
+        delay::{DelayParams, DelayQueue},
+        loss::{advance_jitter, Jitter, LossParams},
+        rate_limit::RateQueue,
+    },
+};
+
+/// `Sink`/`Stream` adapter which simulates a link with separately configured delay, loss,
+/// bandwidth and queueing in each direction.
+///
+/// Packets travelling in each direction are first subject to loss, then wait in a
+/// bandwidth-limited queue, then are delayed. When wrapping an [`IpIface`](crate::IpIface),
+/// packets [`Received`](crate::adapter::Direction::Received) from the iface are travelling
+/// upstream, out of the machine, and packets [`Sent`](crate::adapter::Direction::Sent) to the
+/// iface are travelling downstream, into the machine.
+///
+/// Can be created via [`SinkStreamExt::with_link`](crate::SinkStreamExt::with_link). Use
+/// [`delay_handle`](crate::adapter::Link::delay_handle) and
+/// [`loss_handle`](crate::adapter::Link::loss_handle) to change the parameters for either
+/// direction while packets are flowing.
+#[pin_project]
+pub struct Link<S> {
+    #[pin]
+    stream: S,
+    stream_finished: bool,
+    drop_stats_opt: Option<StatsHandle>,
+    sent: LinkDirection,
+    received: LinkDirection,
+}
+
+/// The configuration for one direction of a [`Link`](crate::adapter::Link). By default packets
+/// are not delayed, dropped or rate limited.
+#[derive(Debug, Clone)]
+pub struct LinkConfig {
+    min_delay: Duration,
+    mean_additional_delay: Duration,
+    loss_rate: f64,
+    jitter_period: Duration,
+    rate_limit_opt: Option<(u64, usize)>,
+    ecn_marking_opt: Option<EcnMarking>,
+}
+
+struct LinkDirection {
+    loss_params: Arc<Mutex<LossParams>>,
+    jitter: Jitter,
+    rate_queue_opt: Option<RateQueue>,
+    delay_params: Arc<Mutex<DelayParams>>,
+    delay_queue: Pin<Box<DelayQueue<Box<IpPacket>>>>,
+}
+
+impl LinkConfig {
+    /// Creates a `LinkConfig` which passes packets through unaffected.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> LinkConfig {
+        LinkConfig {
+            min_delay: Duration::ZERO,
+            mean_additional_delay: Duration::ZERO,
+            loss_rate: 0.0,
+            jitter_period: Duration::ZERO,
+            rate_limit_opt: None,
+            ecn_marking_opt: None,
+        }
+    }
+
+    /// Delays packets. See the documentation for
+    /// [`SinkStreamExt::with_delay`](crate::SinkStreamExt::with_delay).
+    pub fn delay(mut self, min_delay: Duration, mean_additional_delay: Duration) -> LinkConfig {
+        self.min_delay = min_delay;
+        self.mean_additional_delay = mean_additional_delay;
+        self
+    }
+
+    /// Randomly drops packets. See the documentation for
+    /// [`SinkStreamExt::with_loss`](crate::SinkStreamExt::with_loss).
+    pub fn loss(mut self, loss_rate: f64, jitter_period: Duration) -> LinkConfig {
+        assert!(0.0 <= loss_rate);
+        assert!(loss_rate <= 1.0);
+        self.loss_rate = loss_rate;
+        self.jitter_period = jitter_period;
+        self
+    }
+
+    /// Limits the bandwidth to `bits_per_second`, queueing up to `queue_len` packets. See the
+    /// documentation for [`SinkStreamExt::with_rate_limit`](crate::SinkStreamExt::with_rate_limit).
+    pub fn rate_limit(mut self, bits_per_second: u64, queue_len: usize) -> LinkConfig {
+        self.rate_limit_opt = Some((bits_per_second, queue_len));
+        self
+    }
+
+    /// Marks ECN-capable packets in the rate-limited queue when it becomes congested. Has no
+    /// effect unless [`rate_limit`](crate::adapter::LinkConfig::rate_limit) is also set. See
+    /// [`RateLimit::ecn_marking`](crate::adapter::RateLimit::ecn_marking).
+    pub fn ecn_marking(mut self, ecn_marking: EcnMarking) -> LinkConfig {
+        self.ecn_marking_opt = Some(ecn_marking);
+        self
+    }
+}
+
+impl LinkDirection {
+    fn new(config: LinkConfig) -> LinkDirection {
+        let LinkConfig {
+            min_delay,
+            mean_additional_delay,
+            loss_rate,
+            jitter_period,
+            rate_limit_opt,
+            ecn_marking_opt,
+        } = config;
+        LinkDirection {
+            loss_params: Arc::new(Mutex::new(LossParams { loss_rate, jitter_period })),
+            jitter: Jitter::new(loss_rate, jitter_period),
+            rate_queue_opt: {
+                rate_limit_opt.map(|(bits_per_second, queue_len)| {
+                    let mut rate_queue = RateQueue::new(bits_per_second, queue_len);
+                    if let Some(ecn_marking) = ecn_marking_opt {
+                        rate_queue.set_ecn_marking(ecn_marking);
+                    }
+                    rate_queue
+                })
+            },
+            delay_params: Arc::new(Mutex::new(DelayParams { min_delay, mean_additional_delay })),
+            delay_queue: Box::pin(DelayQueue::new()),
+        }
+    }
+
+    /// Queues a packet. Gives the packet back, along with the reason, if it's dropped.
+    fn push(&mut self, packet: Box<IpPacket>) -> Result<(), (DropReason, Box<IpPacket>)> {
+        advance_jitter(&self.loss_params, &mut self.jitter);
+        if self.jitter.currently_dropping() {
+            return Err((DropReason::Loss, packet));
+        }
+        match &mut self.rate_queue_opt {
+            Some(rate_queue) => {
+                rate_queue.push(packet)?;
+            },
+            None => {
+                let delay = self.delay_params.lock().unwrap().sample_delay();
+                self.delay_queue.as_mut().push(delay, packet);
+            },
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self, cx: &mut task::Context) -> Poll<Option<Box<IpPacket>>> {
+        let mut rate_queue_empty = true;
+        if let Some(rate_queue) = &mut self.rate_queue_opt {
+            while let Poll::Ready(Some(packet)) = rate_queue.pop(cx) {
+                let delay = self.delay_params.lock().unwrap().sample_delay();
+                self.delay_queue.as_mut().push(delay, packet);
+            }
+            rate_queue_empty = rate_queue.is_empty();
+        }
+        match self.delay_queue.as_mut().pop(cx) {
+            Poll::Ready(None) if !rate_queue_empty => Poll::Pending,
+            poll => poll,
+        }
+    }
+}
+
+impl<S> Link<S>
+where
+    S: IpSinkStream,
+{
+    /// Creates a new [`Link`]. See the documentation for
+    /// [`SinkStreamExt::with_link`](crate::SinkStreamExt::with_link).
+    pub fn new(stream: S, sent: LinkConfig, received: LinkConfig) -> Link<S> {
+        Link {
+            stream,
+            stream_finished: false,
+            drop_stats_opt: None,
+            sent: LinkDirection::new(sent),
+            received: LinkDirection::new(received),
+        }
+    }
+
+    /// Records packets dropped by this `Link` against the given [`Stats`](crate::adapter::Stats)
+    /// handle.
+    pub fn count_drops(mut self, stats: &StatsHandle) -> Self {
+        self.drop_stats_opt = Some(stats.clone());
+        self
+    }
+
+    /// Gets a handle which can be used to change the delay of packets travelling in the given
+    /// direction.
+    pub fn delay_handle(&self, direction: Direction) -> DelayHandle {
+        DelayHandle::new(self.link_direction(direction).delay_params.clone())
+    }
+
+    /// Gets a handle which can be used to change the loss of packets travelling in the given
+    /// direction.
+    pub fn loss_handle(&self, direction: Direction) -> LossHandle {
+        LossHandle::new(self.link_direction(direction).loss_params.clone())
+    }
+
+    fn link_direction(&self, direction: Direction) -> &LinkDirection {
+        match direction {
+            Direction::Sent => &self.sent,
+            Direction::Received => &self.received,
+        }
+    }
+}
+
+fn record_drop(
+    drop_stats_opt: &Option<StatsHandle>,
+    direction: Direction,
+    reason: DropReason,
+    packet: &IpPacket,
+) {
+    if let Some(stats) = drop_stats_opt {
+        stats.record_drop(direction, reason, packet);
+    }
+}
+
+impl<S> Stream for Link<S>
+where
+    S: IpSinkStream,
+{
+    type Item = io::Result<Box<IpPacket>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Option<io::Result<Box<IpPacket>>>> {
+        let mut this = self.project();
+        if !*this.stream_finished {
+            loop {
+                match this.stream.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(Ok(packet))) => {
+                        if let Err((reason, packet)) = this.received.push(packet) {
+                            record_drop(this.drop_stats_opt, Direction::Received, reason, &packet);
+                        }
+                    },
+                    Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                    Poll::Ready(None) => {
+                        *this.stream_finished = true;
+                        break;
+                    },
+                    Poll::Pending => break,
+                }
+            }
+        }
+        match this.received.pop(cx) {
+            Poll::Ready(Some(packet)) => Poll::Ready(Some(Ok(packet))),
+            Poll::Ready(None) if *this.stream_finished => Poll::Ready(None),
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S> Sink<Box<IpPacket>> for Link<S>
+where
+    S: IpSinkStream,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut task::Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, packet: Box<IpPacket>) -> io::Result<()> {
+        let this = self.project();
+        if let Err((reason, packet)) = this.sent.push(packet) {
+            record_drop(this.drop_stats_opt, Direction::Sent, reason, &packet);
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        loop {
+            ready!(this.stream.as_mut().poll_ready(cx))?;
+            match this.sent.pop(cx) {
+                Poll::Pending => {
+                    ready!(this.stream.as_mut().poll_flush(cx))?;
+                    return Poll::Pending;
+                },
+                Poll::Ready(None) => return this.stream.poll_flush(cx),
+                Poll::Ready(Some(packet)) => this.stream.as_mut().start_send(packet)?,
+            }
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        this.stream.poll_close(cx)
+    }
+}
@@ -1,16 +1,41 @@
-use crate::priv_prelude::*;
+use crate::{
+    priv_prelude::*,
+    adapter::{CountDrops, Direction, DropReason, StatsHandle},
+};
 
 /// `Sink`/`Stream` adapter which randomly drops items.
 ///
-/// Can be created via [`SinkStreamExt::with_loss`](crate::SinkStreamExt::with_loss).
+/// Can be created via [`SinkStreamExt::with_loss`](crate::SinkStreamExt::with_loss). Use
+/// [`handle`](crate::adapter::Loss::handle) to change the loss parameters while items are
+/// flowing. Use [`direction`](crate::adapter::Loss::direction) to only drop items travelling
+/// one way.
+///
+/// `C` records dropped items. It's `()`, which records nothing, unless
+/// [`count_drops`](crate::adapter::Loss::count_drops) has been called.
 #[pin_project]
-pub struct Loss<S> {
+pub struct Loss<S, C = ()> {
     #[pin]
     stream: S,
+    params: Arc<Mutex<LossParams>>,
+    direction_opt: Option<Direction>,
+    drop_counter: C,
     jitter: Jitter,
 }
 
-struct Jitter {
+/// A handle to a [`Loss`](crate::adapter::Loss) which can be used to change its parameters at
+/// runtime.
+#[derive(Clone)]
+pub struct LossHandle {
+    params: Arc<Mutex<LossParams>>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct LossParams {
+    pub loss_rate: f64,
+    pub jitter_period: Duration,
+}
+
+pub(crate) struct Jitter {
     loss_rate: f64,
     jitter_period: Duration,
     currently_dropping: bool,
@@ -40,6 +65,15 @@ impl Jitter {
         self.set_next_switch_instant();
     }
 
+    pub fn set_params(&mut self, loss_rate: f64, jitter_period: Duration) {
+        if self.loss_rate == loss_rate && self.jitter_period == jitter_period {
+            return;
+        }
+        self.loss_rate = loss_rate;
+        self.jitter_period = jitter_period;
+        self.reset(Instant::now());
+    }
+
     pub fn advance(&mut self) {
         let now = Instant::now();
         if self.next_switch_instant + (self.jitter_period * 10) < now {
@@ -73,26 +107,101 @@ impl<S> Loss<S> {
     /// Creates a new [`Loss`]. See the documentation for
     /// [`SinkStreamExt::with_loss`](crate::SinkStreamExt::with_loss).
     pub fn new(stream: S, loss_rate: f64, jitter_period: Duration) -> Loss<S> {
+        let params = LossParams { loss_rate, jitter_period };
         Loss {
             stream,
+            params: Arc::new(Mutex::new(params)),
+            direction_opt: None,
+            drop_counter: (),
             jitter: Jitter::new(loss_rate, jitter_period),
         }
     }
 }
 
-impl<S> Stream for Loss<S>
+impl<S, C> Loss<S, C> {
+    /// Only drop items travelling in the given direction. Items travelling the other way are
+    /// never dropped.
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction_opt = Some(direction);
+        self
+    }
+
+    /// Gets a handle which can be used to change the loss parameters of this `Loss`.
+    pub fn handle(&self) -> LossHandle {
+        LossHandle::new(self.params.clone())
+    }
+}
+
+impl<S> Loss<S>
+where
+    S: IpSinkStream,
+{
+    /// Records packets dropped by this `Loss` against the given [`Stats`](crate::adapter::Stats)
+    /// handle as [`DropReason::Loss`](crate::adapter::DropReason::Loss).
+    pub fn count_drops(self, stats: &StatsHandle) -> Loss<S, StatsHandle> {
+        let Loss { stream, params, direction_opt, drop_counter: (), jitter } = self;
+        Loss {
+            stream,
+            params,
+            direction_opt,
+            drop_counter: stats.clone(),
+            jitter,
+        }
+    }
+}
+
+impl LossHandle {
+    pub(crate) fn new(params: Arc<Mutex<LossParams>>) -> LossHandle {
+        LossHandle { params }
+    }
+
+    /// The proportion of items being dropped.
+    pub fn loss_rate(&self) -> f64 {
+        self.params.lock().unwrap().loss_rate
+    }
+
+    /// The average period of switching between dropping and not dropping items.
+    pub fn jitter_period(&self) -> Duration {
+        self.params.lock().unwrap().jitter_period
+    }
+
+    /// Sets the proportion of items to drop. Must be between `0.0` and `1.0`.
+    pub fn set_loss_rate(&self, loss_rate: f64) {
+        assert!(0.0 <= loss_rate);
+        assert!(loss_rate <= 1.0);
+        self.params.lock().unwrap().loss_rate = loss_rate;
+    }
+
+    /// Sets the average period of switching between dropping and not dropping items.
+    pub fn set_jitter_period(&self, jitter_period: Duration) {
+        self.params.lock().unwrap().jitter_period = jitter_period;
+    }
+}
+
+pub(crate) fn advance_jitter(params: &Mutex<LossParams>, jitter: &mut Jitter) {
+    let LossParams { loss_rate, jitter_period } = *params.lock().unwrap();
+    jitter.set_params(loss_rate, jitter_period);
+    jitter.advance();
+}
+
+impl<S, C> Stream for Loss<S, C>
 where
     S: Stream,
+    C: CountDrops<S::Item>,
 {
     type Item = S::Item;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Option<S::Item>> {
         let mut this = self.project();
-        this.jitter.advance();
+        if *this.direction_opt == Some(Direction::Sent) {
+            return this.stream.poll_next(cx);
+        }
+        advance_jitter(this.params, this.jitter);
         loop {
             match this.stream.as_mut().poll_next(cx) {
                 Poll::Ready(Some(value)) => {
                     if this.jitter.currently_dropping() {
+                        this.drop_counter.count_drop(Direction::Received, DropReason::Loss, &value);
                         continue;
                     }
                     break Poll::Ready(Some(value));
@@ -104,10 +213,11 @@ where
     }
 }
 
-impl<S, T> Sink<T> for Loss<S>
+impl<S, C, T> Sink<T> for Loss<S, C>
 where
     S: Stream,
     S: Sink<T>,
+    C: CountDrops<T>,
 {
     type Error = <S as Sink<T>>::Error;
 
@@ -118,8 +228,12 @@ where
 
     fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
         let this = self.project();
-        this.jitter.advance();
+        if *this.direction_opt == Some(Direction::Received) {
+            return this.stream.start_send(item);
+        }
+        advance_jitter(this.params, this.jitter);
         if this.jitter.currently_dropping() {
+            this.drop_counter.count_drop(Direction::Sent, DropReason::Loss, &item);
             return Ok(());
         }
         this.stream.start_send(item)
@@ -136,9 +250,10 @@ where
     }
 }
 
-impl<S> FusedStream for Loss<S>
+impl<S, C> FusedStream for Loss<S, C>
 where
     S: FusedStream,
+    C: CountDrops<S::Item>,
 {
     fn is_terminated(&self) -> bool {
         self.stream.is_terminated()
@@ -0,0 +1,114 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{
+        Direction,
+        process::{process_adapter, PacketOutput, ProcessPackets},
+    },
+};
+
+process_adapter! {
+    /// `Sink`/`Stream` adapter which passes every packet through a function, with a separate
+    /// function for each direction.
+    ///
+    /// Can be created via [`SinkStreamExt::map_packets`](crate::SinkStreamExt::map_packets).
+    pub struct MapPackets<S, FS, FR>(MapProcessor<FS, FR>)
+    where
+        FS: FnMut(Box<IpPacket>) -> Box<IpPacket> + Send + 'static,
+        FR: FnMut(Box<IpPacket>) -> Box<IpPacket> + Send + 'static,
+}
+
+process_adapter! {
+    /// `Sink`/`Stream` adapter which replaces every packet with zero or more packets returned by
+    /// a function, with a separate function for each direction.
+    ///
+    /// Can be created via
+    /// [`SinkStreamExt::filter_map_packets`](crate::SinkStreamExt::filter_map_packets).
+    pub struct FilterMapPackets<S, FS, FR>(FilterMapProcessor<FS, FR>)
+    impl<IS, IR>
+    where
+        FS: FnMut(Box<IpPacket>) -> IS + Send + 'static,
+        FR: FnMut(Box<IpPacket>) -> IR + Send + 'static,
+        IS: IntoIterator<Item = Box<IpPacket>>,
+        IR: IntoIterator<Item = Box<IpPacket>>,
+}
+
+struct MapProcessor<FS, FR> {
+    sent: FS,
+    received: FR,
+}
+
+struct FilterMapProcessor<FS, FR> {
+    sent: FS,
+    received: FR,
+}
+
+impl<S, FS, FR> MapPackets<S, FS, FR>
+where
+    S: IpSinkStream,
+    FS: FnMut(Box<IpPacket>) -> Box<IpPacket> + Send + 'static,
+    FR: FnMut(Box<IpPacket>) -> Box<IpPacket> + Send + 'static,
+{
+    /// Creates a new [`MapPackets`]. See the documentation for
+    /// [`SinkStreamExt::map_packets`](crate::SinkStreamExt::map_packets).
+    pub fn new(stream: S, sent: FS, received: FR) -> MapPackets<S, FS, FR> {
+        let processor = MapProcessor { sent, received };
+        MapPackets {
+            process: adapter::process::Process::new(stream, processor),
+        }
+    }
+}
+
+impl<S, FS, FR, IS, IR> FilterMapPackets<S, FS, FR>
+where
+    S: IpSinkStream,
+    FS: FnMut(Box<IpPacket>) -> IS + Send + 'static,
+    FR: FnMut(Box<IpPacket>) -> IR + Send + 'static,
+    IS: IntoIterator<Item = Box<IpPacket>>,
+    IR: IntoIterator<Item = Box<IpPacket>>,
+{
+    /// Creates a new [`FilterMapPackets`]. See the documentation for
+    /// [`SinkStreamExt::filter_map_packets`](crate::SinkStreamExt::filter_map_packets).
+    pub fn new(stream: S, sent: FS, received: FR) -> FilterMapPackets<S, FS, FR> {
+        let processor = FilterMapProcessor { sent, received };
+        FilterMapPackets {
+            process: adapter::process::Process::new(stream, processor),
+        }
+    }
+}
+
+impl<FS, FR> ProcessPackets for MapProcessor<FS, FR>
+where
+    FS: FnMut(Box<IpPacket>) -> Box<IpPacket>,
+    FR: FnMut(Box<IpPacket>) -> Box<IpPacket>,
+{
+    fn process(&mut self, direction: Direction, packet: Box<IpPacket>, output: &mut PacketOutput) {
+        let packet = match direction {
+            Direction::Sent => (self.sent)(packet),
+            Direction::Received => (self.received)(packet),
+        };
+        output.forward(packet);
+    }
+}
+
+impl<FS, FR, IS, IR> ProcessPackets for FilterMapProcessor<FS, FR>
+where
+    FS: FnMut(Box<IpPacket>) -> IS,
+    FR: FnMut(Box<IpPacket>) -> IR,
+    IS: IntoIterator<Item = Box<IpPacket>>,
+    IR: IntoIterator<Item = Box<IpPacket>>,
+{
+    fn process(&mut self, direction: Direction, packet: Box<IpPacket>, output: &mut PacketOutput) {
+        match direction {
+            Direction::Sent => {
+                for packet in (self.sent)(packet) {
+                    output.forward(packet);
+                }
+            },
+            Direction::Received => {
+                for packet in (self.received)(packet) {
+                    output.forward(packet);
+                }
+            },
+        }
+    }
+}
@@ -5,11 +5,35 @@
 use crate::priv_prelude::*;
 
 mod delay;
+mod fault;
+mod firewall;
+mod link;
 mod loss;
+mod map;
+mod mss_clamp;
+mod mtu;
+mod packet_match;
+mod process;
+mod rate_limit;
+mod stats;
+mod switch;
+mod tap;
 
 pub use self::{
-    delay::Delay,
-    loss::Loss,
+    delay::{Delay, DelayHandle},
+    fault::{FaultAction, FaultInjector, FaultInjectorHandle, FaultRule, Occurrence},
+    firewall::{Firewall, FirewallAction, FirewallHandle, FirewallRule},
+    link::{Link, LinkConfig},
+    loss::{Loss, LossHandle},
+    map::{FilterMapPackets, MapPackets},
+    mss_clamp::MssClamp,
+    mtu::Mtu,
+    packet_match::PacketMatch,
+    process::Direction,
+    rate_limit::{EcnMarking, RateLimit},
+    stats::{CountDrops, DirectionStats, DropReason, PacketCount, Stats, StatsHandle, StatsSnapshot},
+    switch::{Switch, SwitchHandle},
+    tap::{Tap, TappedPacket},
 };
 
 pub(crate) fn expovariate_duration<R>(
@@ -0,0 +1,69 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{
+        Direction,
+        process::{process_adapter, PacketOutput, ProcessPackets},
+    },
+};
+
+process_adapter! {
+    /// `Sink`/`Stream` adapter which clamps the MSS (maximum segment size) option of TCP SYN and
+    /// SYN-ACK packets, as routers on PPPoE and VPN links do.
+    ///
+    /// Can optionally also cap the receive window advertised by all TCP packets. Only IPv4 packets
+    /// are modified.
+    ///
+    /// Can be created via [`SinkStreamExt::with_mss_clamp`](crate::SinkStreamExt::with_mss_clamp).
+    pub struct MssClamp<S>(MssClampProcessor)
+}
+
+struct MssClampProcessor {
+    max_mss: u16,
+    max_window_opt: Option<u16>,
+}
+
+impl<S> MssClamp<S>
+where
+    S: IpSinkStream,
+{
+    /// Creates a new [`MssClamp`]. See the documentation for
+    /// [`SinkStreamExt::with_mss_clamp`](crate::SinkStreamExt::with_mss_clamp).
+    pub fn new(stream: S, max_mss: u16) -> MssClamp<S> {
+        let processor = MssClampProcessor {
+            max_mss,
+            max_window_opt: None,
+        };
+        MssClamp {
+            process: adapter::process::Process::new(stream, processor),
+        }
+    }
+
+    /// Caps the window size field of all TCP packets at `max_window`. This is the raw field from
+    /// the TCP header, before any window scaling is applied.
+    pub fn max_window(mut self, max_window: u16) -> Self {
+        self.process.processor_mut().max_window_opt = Some(max_window);
+        self
+    }
+}
+
+impl ProcessPackets for MssClampProcessor {
+    fn process(&mut self, _direction: Direction, mut packet: Box<IpPacket>, output: &mut PacketOutput) {
+        if let IpPacketVersion::V4(ipv4_packet) = packet.version_mut() {
+            if let Ipv4PacketProtocol::Tcp(tcp_packet) = ipv4_packet.protocol_mut() {
+                if tcp_packet.flags().syn {
+                    if let Some(mss) = tcp_packet.mss() {
+                        if mss > self.max_mss {
+                            tcp_packet.set_mss(self.max_mss);
+                        }
+                    }
+                }
+                if let Some(max_window) = self.max_window_opt {
+                    if tcp_packet.window_size() > max_window {
+                        tcp_packet.set_window_size(max_window);
+                    }
+                }
+            }
+        }
+        output.forward(packet);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{
+        DropReason, StatsHandle,
+        process::{process_adapter, Direction, PacketOutput, ProcessPackets},
+    },
+    packet::{Icmpv4Packet, Icmpv6Packet},
+};
+
+process_adapter! {
+    /// `Sink`/`Stream` adapter which drops packets that are larger than a configured MTU.
+    ///
+    /// By default oversize packets are answered with an ICMPv4 "fragmentation needed" or ICMPv6
+    /// "packet too big" error sent back towards the packet's sender, as a router would do. The
+    /// error is sent from the oversize packet's destination address. IPv4 packets which don't have
+    /// the "don't fragment" flag set are dropped without a reply since fragmentation isn't
+    /// modelled.
+    ///
+    /// Can be created via [`SinkStreamExt::with_mtu`](crate::SinkStreamExt::with_mtu).
+    pub struct Mtu<S>(MtuProcessor)
+}
+
+struct MtuProcessor {
+    mtu: usize,
+    black_hole: bool,
+    drop_stats_opt: Option<StatsHandle>,
+}
+
+impl<S> Mtu<S>
+where
+    S: IpSinkStream,
+{
+    /// Creates a new [`Mtu`]. See the documentation for
+    /// [`SinkStreamExt::with_mtu`](crate::SinkStreamExt::with_mtu).
+    pub fn new(stream: S, mtu: usize) -> Mtu<S> {
+        let processor = MtuProcessor {
+            mtu,
+            black_hole: false,
+            drop_stats_opt: None,
+        };
+        Mtu {
+            process: adapter::process::Process::new(stream, processor),
+        }
+    }
+
+    /// Silently drops oversize packets without sending an ICMP error. This simulates a path MTU
+    /// discovery black hole.
+    pub fn black_hole(mut self) -> Self {
+        self.process.processor_mut().black_hole = true;
+        self
+    }
+
+    /// Records oversize packets against the given [`Stats`](crate::adapter::Stats) handle as
+    /// [`DropReason::TooBig`](crate::adapter::DropReason::TooBig).
+    pub fn count_drops(mut self, stats: &StatsHandle) -> Self {
+        self.process.processor_mut().drop_stats_opt = Some(stats.clone());
+        self
+    }
+}
+
+impl ProcessPackets for MtuProcessor {
+    fn process(&mut self, direction: Direction, packet: Box<IpPacket>, output: &mut PacketOutput) {
+        if packet.len() <= self.mtu {
+            output.forward(packet);
+            return;
+        }
+        if let Some(stats) = &self.drop_stats_opt {
+            stats.record_drop(direction, DropReason::TooBig, &packet);
+        }
+        if self.black_hole {
+            return;
+        }
+        match packet.version_ref() {
+            IpPacketVersion::V4(packet) => {
+                if !packet.dont_fragment() {
+                    return;
+                }
+                let mtu = u16::try_from(self.mtu).unwrap_or(u16::MAX);
+                let reply = Icmpv4Packet::new_fragmentation_needed(packet.destination_addr(), mtu, packet);
+                output.reply(reply.ip_packet_box());
+            },
+            IpPacketVersion::V6(packet) => {
+                let mtu = u32::try_from(self.mtu).unwrap_or(u32::MAX);
+                let reply = Icmpv6Packet::new_packet_too_big(packet.destination_addr(), mtu, packet);
+                output.reply(reply.ip_packet_box());
+            },
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use {
+    crate::{
+        priv_prelude::*,
+        adapter::Direction,
+        packet::IpProtocol,
+    },
+    std::ops::RangeInclusive,
+};
+
+/// A set of conditions which a packet can be checked against. Used by packet-level adapters such
+/// as [`Firewall`](crate::adapter::Firewall) to decide which packets a rule applies to.
+///
+/// A `PacketMatch` created with [`any`](crate::adapter::PacketMatch::any) matches every packet.
+/// Each condition added narrows down the set of matching packets.
+#[derive(Debug, Clone, Default)]
+pub struct PacketMatch {
+    direction_opt: Option<Direction>,
+    protocol_opt: Option<IpProtocol>,
+    source_network_opt: Option<IpNetwork>,
+    destination_network_opt: Option<IpNetwork>,
+    source_ports_opt: Option<RangeInclusive<u16>>,
+    destination_ports_opt: Option<RangeInclusive<u16>>,
+    tcp_flags_opt: Option<(TcpPacketFlags, TcpPacketFlags)>,
+}
+
+impl PacketMatch {
+    /// Creates a `PacketMatch` which matches every packet.
+    pub fn any() -> PacketMatch {
+        PacketMatch::default()
+    }
+
+    /// Only match packets travelling in the given direction.
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction_opt = Some(direction);
+        self
+    }
+
+    /// Only match packets carrying the given protocol.
+    pub fn protocol(mut self, protocol: IpProtocol) -> Self {
+        self.protocol_opt = Some(protocol);
+        self
+    }
+
+    /// Only match IPv4 packets with a source address in the given network.
+    pub fn source_ipv4_network(mut self, network: Ipv4Network) -> Self {
+        self.source_network_opt = Some(IpNetwork::V4(network));
+        self
+    }
+
+    /// Only match IPv6 packets with a source address in the given network.
+    pub fn source_ipv6_network(mut self, network: Ipv6Network) -> Self {
+        self.source_network_opt = Some(IpNetwork::V6(network));
+        self
+    }
+
+    /// Only match IPv4 packets with a destination address in the given network.
+    pub fn destination_ipv4_network(mut self, network: Ipv4Network) -> Self {
+        self.destination_network_opt = Some(IpNetwork::V4(network));
+        self
+    }
+
+    /// Only match IPv6 packets with a destination address in the given network.
+    pub fn destination_ipv6_network(mut self, network: Ipv6Network) -> Self {
+        self.destination_network_opt = Some(IpNetwork::V6(network));
+        self
+    }
+
+    /// Only match TCP and UDP packets with a source port in the given range.
+    pub fn source_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.source_ports_opt = Some(ports);
+        self
+    }
+
+    /// Only match TCP and UDP packets with a destination port in the given range.
+    pub fn destination_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.destination_ports_opt = Some(ports);
+        self
+    }
+
+    /// Only match TCP packets whose flags, masked with `mask`, equal `flags`. For example, to
+    /// match SYN-ACKs but not SYNs, use a mask with `syn` and `ack` set and flags with both set.
+    /// To match SYNs but not SYN-ACKs, use the same mask and flags with only `syn` set.
+    pub fn tcp_flags(mut self, mask: TcpPacketFlags, flags: TcpPacketFlags) -> Self {
+        self.tcp_flags_opt = Some((mask, flags));
+        self
+    }
+
+    /// Checks whether a packet travelling in the given direction matches.
+    pub fn matches(&self, direction: Direction, packet: &IpPacket) -> bool {
+        if let Some(expected_direction) = self.direction_opt {
+            if direction != expected_direction {
+                return false;
+            }
+        }
+        if let Some(protocol) = self.protocol_opt {
+            if packet.protocol() != protocol {
+                return false;
+            }
+        }
+        if let Some(network) = self.source_network_opt {
+            if !network.contains(packet.source_addr()) {
+                return false;
+            }
+        }
+        if let Some(network) = self.destination_network_opt {
+            if !network.contains(packet.destination_addr()) {
+                return false;
+            }
+        }
+        if self.source_ports_opt.is_some() || self.destination_ports_opt.is_some() {
+            let Some((source_port, destination_port)) = packet.ports() else {
+                return false;
+            };
+            if let Some(ports) = &self.source_ports_opt {
+                if !ports.contains(&source_port) {
+                    return false;
+                }
+            }
+            if let Some(ports) = &self.destination_ports_opt {
+                if !ports.contains(&destination_port) {
+                    return false;
+                }
+            }
+        }
+        if let Some((mask, flags)) = self.tcp_flags_opt {
+            let Some(packet_flags) = packet.tcp_flags() else {
+                return false;
+            };
+            if !packet_flags.masked_eq(mask, flags) {
+                return false;
+            }
+        }
+        true
+    }
+}
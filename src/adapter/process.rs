@@ -0,0 +1,288 @@
+use crate::{
+    priv_prelude::*,
+    adapter::delay::DelayQueue,
+};
+
+/// The direction a packet is travelling through an adapter.
+///
+/// When the adapter wraps an [`IpIface`](crate::IpIface), `Sent` packets are heading into the
+/// machine and `Received` packets are leaving it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// The packet was written to the adapter's `Sink` and is heading into the wrapped
+    /// `Sink`/`Stream`.
+    Sent,
+    /// The packet was read from the wrapped `Sink`/`Stream` and is heading out of the adapter's
+    /// `Stream`.
+    Received,
+}
+
+/// Something which inspects, modifies, drops or replies to packets passing through a
+/// [`Process`] adapter.
+pub(crate) trait ProcessPackets {
+    fn process(&mut self, direction: Direction, packet: Box<IpPacket>, output: &mut PacketOutput);
+}
+
+/// Where a [`ProcessPackets`] puts the packets it wants to pass on.
+pub(crate) struct PacketOutput {
+    forward: Vec<Box<IpPacket>>,
+    forward_delayed: Vec<(Duration, Box<IpPacket>)>,
+    reply: Vec<Box<IpPacket>>,
+}
+
+impl PacketOutput {
+    fn new() -> PacketOutput {
+        PacketOutput {
+            forward: Vec::new(),
+            forward_delayed: Vec::new(),
+            reply: Vec::new(),
+        }
+    }
+
+    /// Passes a packet on in the direction it was travelling.
+    pub fn forward(&mut self, packet: Box<IpPacket>) {
+        self.forward.push(packet);
+    }
+
+    /// Passes a packet on in the direction it was travelling once `delay` has elapsed.
+    pub fn forward_after(&mut self, delay: Duration, packet: Box<IpPacket>) {
+        self.forward_delayed.push((delay, packet));
+    }
+
+    /// Sends a packet back the way the processed packet came.
+    pub fn reply(&mut self, packet: Box<IpPacket>) {
+        self.reply.push(packet);
+    }
+}
+
+/// `Sink`/`Stream` adapter which passes every packet through a [`ProcessPackets`]. This contains
+/// the poll plumbing shared by the packet-level adapters, which wrap it using
+/// `process_adapter!`.
+#[pin_project]
+pub(crate) struct Process<S, P> {
+    #[pin]
+    stream: S,
+    processor: P,
+    output: PacketOutput,
+    stream_finished: bool,
+    stream_queue: VecDeque<Box<IpPacket>>,
+    sink_queue: VecDeque<Box<IpPacket>>,
+    stream_delay_queue: Pin<Box<DelayQueue<Box<IpPacket>>>>,
+    sink_delay_queue: Pin<Box<DelayQueue<Box<IpPacket>>>>,
+    stream_waker_opt: Option<task::Waker>,
+}
+
+impl<S, P> Process<S, P>
+where
+    S: IpSinkStream,
+    P: ProcessPackets,
+{
+    pub fn new(stream: S, processor: P) -> Process<S, P> {
+        Process {
+            stream,
+            processor,
+            output: PacketOutput::new(),
+            stream_finished: false,
+            stream_queue: VecDeque::new(),
+            sink_queue: VecDeque::new(),
+            stream_delay_queue: Box::pin(DelayQueue::new()),
+            sink_delay_queue: Box::pin(DelayQueue::new()),
+            stream_waker_opt: None,
+        }
+    }
+
+    pub fn processor(&self) -> &P {
+        &self.processor
+    }
+
+    pub fn processor_mut(&mut self) -> &mut P {
+        &mut self.processor
+    }
+
+    fn process(self: Pin<&mut Self>, direction: Direction, packet: Box<IpPacket>) {
+        let this = self.project();
+        this.processor.process(direction, packet, this.output);
+        {
+            let (forward_queue, reply_queue) = match direction {
+                Direction::Sent => (&mut *this.sink_queue, &mut *this.stream_queue),
+                Direction::Received => (&mut *this.stream_queue, &mut *this.sink_queue),
+            };
+            forward_queue.extend(this.output.forward.drain(..));
+            reply_queue.extend(this.output.reply.drain(..));
+            let forward_delay_queue = match direction {
+                Direction::Sent => this.sink_delay_queue,
+                Direction::Received => this.stream_delay_queue,
+            };
+            for (delay, packet) in this.output.forward_delayed.drain(..) {
+                forward_delay_queue.as_mut().push(delay, packet);
+            }
+        }
+        if !this.stream_queue.is_empty() || !this.stream_delay_queue.is_empty() {
+            if let Some(waker) = this.stream_waker_opt.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn poll_send_queued(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        while let Poll::Ready(Some(packet)) = this.sink_delay_queue.as_mut().pop(cx) {
+            this.sink_queue.push_back(packet);
+        }
+        loop {
+            if this.sink_queue.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            ready!(this.stream.as_mut().poll_ready(cx))?;
+            let packet = this.sink_queue.pop_front().unwrap();
+            this.stream.as_mut().start_send(packet)?;
+        }
+    }
+
+    /// Sends and flushes queued packets from within `poll_next`.
+    fn poll_send_replies(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_send_queued(cx))?;
+        self.project().stream.poll_flush(cx)
+    }
+}
+
+impl<S, P> Stream for Process<S, P>
+where
+    S: IpSinkStream,
+    P: ProcessPackets,
+{
+    type Item = io::Result<Box<IpPacket>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Option<io::Result<Box<IpPacket>>>> {
+        // Replies to received packets need to get sent even if nothing is writing to our sink.
+        if let Poll::Ready(Err(err)) = self.as_mut().poll_send_replies(cx) {
+            return Poll::Ready(Some(Err(err)));
+        }
+        loop {
+            let mut this = self.as_mut().project();
+            while let Poll::Ready(Some(packet)) = this.stream_delay_queue.as_mut().pop(cx) {
+                this.stream_queue.push_back(packet);
+            }
+            if let Some(packet) = this.stream_queue.pop_front() {
+                return Poll::Ready(Some(Ok(packet)));
+            }
+            if *this.stream_finished {
+                if this.stream_delay_queue.is_empty() {
+                    return Poll::Ready(None);
+                }
+                return Poll::Pending;
+            }
+            match this.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(packet))) => {
+                    self.as_mut().process(Direction::Received, packet);
+                    if !self.sink_queue.is_empty() {
+                        if let Poll::Ready(Err(err)) = self.as_mut().poll_send_replies(cx) {
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                    }
+                },
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => {
+                    *this.stream_finished = true;
+                },
+                Poll::Pending => {
+                    *this.stream_waker_opt = Some(cx.waker().clone());
+                    return Poll::Pending;
+                },
+            }
+        }
+    }
+}
+
+impl<S, P> Sink<Box<IpPacket>> for Process<S, P>
+where
+    S: IpSinkStream,
+    P: ProcessPackets,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        self.poll_send_queued(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, packet: Box<IpPacket>) -> io::Result<()> {
+        self.process(Direction::Sent, packet);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_send_queued(cx))?;
+        let this = self.project();
+        ready!(this.stream.poll_flush(cx))?;
+        if this.sink_delay_queue.is_empty() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        self.project().stream.poll_close(cx)
+    }
+}
+
+/// Defines a public adapter type which wraps a [`Process`] and forwards its `Sink`/`Stream`
+/// implementations. Type parameters listed after `impl` are only added to the trait impls, for
+/// use in the `where` bounds.
+macro_rules! process_adapter(
+    (
+        $(#[$attr:meta])*
+        pub struct $name:ident<S $(, $param:ident)*>($processor:ty)
+        $(impl<$($impl_param:ident),*>)?
+        $(where $($bound:tt)*)?
+    ) => (
+        $(#[$attr])*
+        #[pin_project]
+        pub struct $name<S $(, $param)*> {
+            #[pin]
+            process: adapter::process::Process<S, $processor>,
+        }
+
+        impl<S $(, $param)* $($(, $impl_param)*)?> Stream for $name<S $(, $param)*>
+        where
+            S: IpSinkStream,
+            $($($bound)*)?
+        {
+            type Item = io::Result<Box<IpPacket>>;
+
+            fn poll_next(
+                self: Pin<&mut Self>,
+                cx: &mut task::Context,
+            ) -> Poll<Option<io::Result<Box<IpPacket>>>> {
+                self.project().process.poll_next(cx)
+            }
+        }
+
+        impl<S $(, $param)* $($(, $impl_param)*)?> Sink<Box<IpPacket>> for $name<S $(, $param)*>
+        where
+            S: IpSinkStream,
+            $($($bound)*)?
+        {
+            type Error = io::Error;
+
+            fn poll_ready(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+                self.project().process.poll_ready(cx)
+            }
+
+            fn start_send(self: Pin<&mut Self>, packet: Box<IpPacket>) -> io::Result<()> {
+                self.project().process.start_send(packet)
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+                self.project().process.poll_flush(cx)
+            }
+
+            fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+                self.project().process.poll_close(cx)
+            }
+        }
+    );
+);
+
+pub(crate) use process_adapter;
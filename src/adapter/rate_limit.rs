@@ -0,0 +1,282 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{Direction, DropReason, StatsHandle},
+    packet::EcnCodepoint,
+};
+
+/// `Sink`/`Stream` adapter which limits the bandwidth of packets sent/received through the
+/// `Sink`/`Stream`.
+///
+/// Packets are held in a queue while they wait for the link to become free. Packets which arrive
+/// while the queue is full are dropped. Use [`ecn_marking`](crate::adapter::RateLimit::ecn_marking)
+/// to also signal congestion before the queue fills up.
+///
+/// Can be created via [`SinkStreamExt::with_rate_limit`](crate::SinkStreamExt::with_rate_limit).
+/// Use [`direction`](crate::adapter::RateLimit::direction) to only limit packets travelling one
+/// way.
+#[pin_project]
+pub struct RateLimit<S> {
+    #[pin]
+    stream: S,
+    direction_opt: Option<Direction>,
+    drop_stats_opt: Option<StatsHandle>,
+    stream_finished: bool,
+    stream_queue: RateQueue,
+    sink_queue: RateQueue,
+}
+
+/// How a rate-limited queue signals congestion based on queue delay, ie. how long an arriving
+/// packet will wait before it starts being transmitted.
+///
+/// When the queue decides to signal congestion, packets which are ECN-capable (marked `ECT(0)`
+/// or `ECT(1)`) have their ECN codepoint set to `CE` and are queued as normal. Other packets are
+/// dropped instead, and recorded as
+/// [`DropReason::Congestion`](crate::adapter::DropReason::Congestion). Packets already marked
+/// `CE` are left alone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EcnMarking {
+    /// Signal congestion with a probability which rises linearly from zero, when the queue delay
+    /// is `min_delay`, to one, when the queue delay is `max_delay`. This resembles classic RED.
+    Ramp {
+        min_delay: Duration,
+        max_delay: Duration,
+    },
+    /// Signal congestion for every packet which arrives while the queue delay is at least
+    /// `threshold`. This is the shallow step marking used by L4S queues.
+    Step {
+        threshold: Duration,
+    },
+}
+
+/// A FIFO queue which releases packets no faster than a link of the given bandwidth could
+/// transmit them.
+pub(crate) struct RateQueue {
+    bits_per_second: u64,
+    queue_len: usize,
+    ecn_marking_opt: Option<EcnMarking>,
+    link_free_instant: Instant,
+    sleep_opt: Option<Pin<Box<tokio::time::Sleep>>>,
+    pending: VecDeque<(Instant, Box<IpPacket>)>,
+}
+
+impl EcnMarking {
+    fn signals_congestion(self, queue_delay: Duration) -> bool {
+        match self {
+            EcnMarking::Ramp { min_delay, max_delay } => {
+                if queue_delay < min_delay {
+                    return false;
+                }
+                if queue_delay >= max_delay {
+                    return true;
+                }
+                let probability = {
+                    (queue_delay - min_delay).as_secs_f64() / (max_delay - min_delay).as_secs_f64()
+                };
+                rand::thread_rng().gen_bool(probability)
+            },
+            EcnMarking::Step { threshold } => queue_delay >= threshold,
+        }
+    }
+}
+
+impl RateQueue {
+    pub fn new(bits_per_second: u64, queue_len: usize) -> RateQueue {
+        assert!(bits_per_second > 0);
+        RateQueue {
+            bits_per_second,
+            queue_len,
+            ecn_marking_opt: None,
+            link_free_instant: Instant::now(),
+            sleep_opt: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn set_ecn_marking(&mut self, ecn_marking: EcnMarking) {
+        self.ecn_marking_opt = Some(ecn_marking);
+    }
+
+    /// Queues a packet. Gives the packet back, along with the reason, if it's dropped.
+    pub fn push(&mut self, mut packet: Box<IpPacket>) -> Result<(), (DropReason, Box<IpPacket>)> {
+        if self.pending.len() >= self.queue_len {
+            return Err((DropReason::QueueFull, packet));
+        }
+        let now = Instant::now();
+        if let Some(ecn_marking) = self.ecn_marking_opt {
+            let queue_delay = self.link_free_instant.saturating_duration_since(now);
+            if ecn_marking.signals_congestion(queue_delay) {
+                match packet.ecn() {
+                    EcnCodepoint::NotEct => return Err((DropReason::Congestion, packet)),
+                    EcnCodepoint::Ect0 | EcnCodepoint::Ect1 => packet.set_ecn(EcnCodepoint::Ce),
+                    EcnCodepoint::Ce => (),
+                }
+            }
+        }
+        let transmit_time = Duration::from_secs_f64(
+            (packet.len() * 8) as f64 / self.bits_per_second as f64,
+        );
+        let departure_instant = cmp::max(now, self.link_free_instant) + transmit_time;
+        self.link_free_instant = departure_instant;
+        self.pending.push_back((departure_instant, packet));
+        Ok(())
+    }
+
+    pub fn pop(&mut self, cx: &mut task::Context) -> Poll<Option<Box<IpPacket>>> {
+        let departure_instant = match self.pending.front() {
+            None => return Poll::Ready(None),
+            Some((departure_instant, _packet)) => *departure_instant,
+        };
+        if departure_instant > Instant::now() {
+            match &mut self.sleep_opt {
+                Some(sleep) => sleep.as_mut().reset(departure_instant.into()),
+                None => {
+                    let sleep = tokio::time::sleep_until(departure_instant.into());
+                    self.sleep_opt = Some(Box::pin(sleep));
+                },
+            }
+            let sleep = self.sleep_opt.as_mut().unwrap();
+            ready!(sleep.as_mut().poll(cx));
+        }
+        let (_departure_instant, packet) = self.pending.pop_front().unwrap();
+        Poll::Ready(Some(packet))
+    }
+}
+
+impl<S> RateLimit<S>
+where
+    S: IpSinkStream,
+{
+    /// Creates a new [`RateLimit`]. See the documentation for
+    /// [`SinkStreamExt::with_rate_limit`](crate::SinkStreamExt::with_rate_limit).
+    pub fn new(stream: S, bits_per_second: u64, queue_len: usize) -> RateLimit<S> {
+        RateLimit {
+            stream,
+            direction_opt: None,
+            drop_stats_opt: None,
+            stream_finished: false,
+            stream_queue: RateQueue::new(bits_per_second, queue_len),
+            sink_queue: RateQueue::new(bits_per_second, queue_len),
+        }
+    }
+
+    /// Only limit packets travelling in the given direction. Packets travelling the other way
+    /// pass through immediately.
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction_opt = Some(direction);
+        self
+    }
+
+    /// Signal congestion by marking ECN-capable packets once the queue delay grows, rather than
+    /// waiting until the queue is full and dropping them. See [`EcnMarking`].
+    pub fn ecn_marking(mut self, ecn_marking: EcnMarking) -> Self {
+        self.stream_queue.set_ecn_marking(ecn_marking);
+        self.sink_queue.set_ecn_marking(ecn_marking);
+        self
+    }
+
+    /// Records packets dropped by this `RateLimit` against the given
+    /// [`Stats`](crate::adapter::Stats) handle as
+    /// [`DropReason::QueueFull`](crate::adapter::DropReason::QueueFull) or
+    /// [`DropReason::Congestion`](crate::adapter::DropReason::Congestion).
+    pub fn count_drops(mut self, stats: &StatsHandle) -> Self {
+        self.drop_stats_opt = Some(stats.clone());
+        self
+    }
+}
+
+fn record_drop(
+    drop_stats_opt: &Option<StatsHandle>,
+    direction: Direction,
+    reason: DropReason,
+    packet: &IpPacket,
+) {
+    if let Some(stats) = drop_stats_opt {
+        stats.record_drop(direction, reason, packet);
+    }
+}
+
+impl<S> Stream for RateLimit<S>
+where
+    S: IpSinkStream,
+{
+    type Item = io::Result<Box<IpPacket>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Option<io::Result<Box<IpPacket>>>> {
+        let mut this = self.project();
+        if *this.direction_opt == Some(Direction::Sent) {
+            return this.stream.poll_next(cx);
+        }
+        if !*this.stream_finished {
+            loop {
+                match this.stream.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(Ok(packet))) => {
+                        if let Err((reason, packet)) = this.stream_queue.push(packet) {
+                            record_drop(this.drop_stats_opt, Direction::Received, reason, &packet);
+                        }
+                    },
+                    Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                    Poll::Ready(None) => {
+                        *this.stream_finished = true;
+                        break;
+                    },
+                    Poll::Pending => break,
+                }
+            }
+        }
+        match this.stream_queue.pop(cx) {
+            Poll::Ready(Some(packet)) => Poll::Ready(Some(Ok(packet))),
+            Poll::Ready(None) if *this.stream_finished => Poll::Ready(None),
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S> Sink<Box<IpPacket>> for RateLimit<S>
+where
+    S: IpSinkStream,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        let this = self.project();
+        if *this.direction_opt == Some(Direction::Received) {
+            return this.stream.poll_ready(cx);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, packet: Box<IpPacket>) -> io::Result<()> {
+        let this = self.project();
+        if *this.direction_opt == Some(Direction::Received) {
+            return this.stream.start_send(packet);
+        }
+        if let Err((reason, packet)) = this.sink_queue.push(packet) {
+            record_drop(this.drop_stats_opt, Direction::Sent, reason, &packet);
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        loop {
+            ready!(this.stream.as_mut().poll_ready(cx))?;
+            match this.sink_queue.pop(cx) {
+                Poll::Pending => {
+                    ready!(this.stream.as_mut().poll_flush(cx))?;
+                    return Poll::Pending;
+                },
+                Poll::Ready(None) => return this.stream.poll_flush(cx),
+                Poll::Ready(Some(packet)) => this.stream.as_mut().start_send(packet)?,
+            }
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        this.stream.poll_close(cx)
+    }
+}
@@ -0,0 +1,219 @@
+use crate::{
+    priv_prelude::*,
+    packet::IpProtocol,
+    adapter::{
+        Direction,
+        process::{process_adapter, PacketOutput, ProcessPackets},
+    },
+};
+
+process_adapter! {
+    /// `Sink`/`Stream` adapter which counts the packets and bytes passing through it.
+    ///
+    /// Packets are counted per direction and broken down by protocol. Adapters which drop packets,
+    /// such as [`Loss`](crate::adapter::Loss) and [`RateLimit`](crate::adapter::RateLimit), can
+    /// also record their drops against the same handle, broken down by [`DropReason`], by calling
+    /// their `count_drops` method.
+    ///
+    /// Can be created via [`SinkStreamExt::with_stats`](crate::SinkStreamExt::with_stats). To
+    /// record drops made by adapters wrapped by the `Stats`, create a [`StatsHandle`] first and
+    /// pass it to [`Stats::with_handle`](crate::adapter::Stats::with_handle).
+    pub struct Stats<S>(StatsProcessor)
+}
+
+/// A handle to a [`Stats`](crate::adapter::Stats) which can be used to read and reset its
+/// counters.
+#[derive(Clone, Default)]
+pub struct StatsHandle {
+    snapshot: Arc<Mutex<StatsSnapshot>>,
+}
+
+/// Why a packet was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// The packet was randomly dropped to simulate packet loss.
+    Loss,
+    /// The packet arrived while a queue was full.
+    QueueFull,
+    /// The packet was larger than the MTU.
+    TooBig,
+    /// The packet was dropped or rejected by a firewall.
+    Filtered,
+    /// The packet wasn't ECN-capable and was dropped by a queue signalling congestion. See
+    /// [`EcnMarking`](crate::adapter::EcnMarking).
+    Congestion,
+    /// The packet was sent or arrived while a [`Switch`](crate::adapter::Switch) had the link
+    /// down.
+    LinkDown,
+}
+
+/// A number of packets and their total size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketCount {
+    /// The number of packets.
+    pub packets: u64,
+    /// The total number of bytes in the packets, including IP headers.
+    pub bytes: u64,
+}
+
+/// The counters for packets travelling in one direction.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DirectionStats {
+    /// All packets.
+    pub total: PacketCount,
+    /// TCP packets.
+    pub tcp: PacketCount,
+    /// UDP packets.
+    pub udp: PacketCount,
+    /// ICMP and ICMPv6 packets.
+    pub icmp: PacketCount,
+    /// Packets of any other protocol.
+    pub other: PacketCount,
+    /// Packets which were dropped by adapters recording against this handle, by the reason they
+    /// were dropped. Whether dropped packets are also included in the other counters depends on
+    /// whether the dropping adapter is inside or outside the `Stats`.
+    pub dropped: HashMap<DropReason, PacketCount>,
+}
+
+/// A copy of the counters of a [`Stats`](crate::adapter::Stats) at a point in time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Packets travelling in the [`Sent`](crate::adapter::Direction::Sent) direction.
+    pub sent: DirectionStats,
+    /// Packets travelling in the [`Received`](crate::adapter::Direction::Received) direction.
+    pub received: DirectionStats,
+}
+
+struct StatsProcessor {
+    snapshot: Arc<Mutex<StatsSnapshot>>,
+}
+
+impl PacketCount {
+    fn add(&mut self, packet: &IpPacket) {
+        self.packets += 1;
+        self.bytes += packet.len() as u64;
+    }
+}
+
+impl DirectionStats {
+    /// The number of dropped packets for the given reason.
+    pub fn dropped(&self, reason: DropReason) -> PacketCount {
+        self.dropped.get(&reason).copied().unwrap_or_default()
+    }
+
+    fn protocol_mut(&mut self, protocol: IpProtocol) -> &mut PacketCount {
+        match protocol {
+            IpProtocol::Tcp => &mut self.tcp,
+            IpProtocol::Udp => &mut self.udp,
+            IpProtocol::Icmp => &mut self.icmp,
+            IpProtocol::Other(_) => &mut self.other,
+        }
+    }
+}
+
+impl StatsSnapshot {
+    /// The counters for packets travelling in the given direction.
+    pub fn direction(&self, direction: Direction) -> &DirectionStats {
+        match direction {
+            Direction::Sent => &self.sent,
+            Direction::Received => &self.received,
+        }
+    }
+
+    fn direction_mut(&mut self, direction: Direction) -> &mut DirectionStats {
+        match direction {
+            Direction::Sent => &mut self.sent,
+            Direction::Received => &mut self.received,
+        }
+    }
+}
+
+impl<S> Stats<S>
+where
+    S: IpSinkStream,
+{
+    /// Creates a new [`Stats`]. See the documentation for
+    /// [`SinkStreamExt::with_stats`](crate::SinkStreamExt::with_stats).
+    pub fn new(stream: S) -> Stats<S> {
+        Stats::with_handle(stream, &StatsHandle::new())
+    }
+
+    /// Creates a new [`Stats`] which records its counters against an existing handle.
+    pub fn with_handle(stream: S, handle: &StatsHandle) -> Stats<S> {
+        let processor = StatsProcessor {
+            snapshot: handle.snapshot.clone(),
+        };
+        Stats {
+            process: adapter::process::Process::new(stream, processor),
+        }
+    }
+
+    /// Gets a handle which can be used to read and reset the counters.
+    pub fn handle(&self) -> StatsHandle {
+        StatsHandle {
+            snapshot: self.process.processor().snapshot.clone(),
+        }
+    }
+}
+
+impl StatsHandle {
+    /// Creates a new handle with all counters set to zero. Pass it to
+    /// [`Stats::with_handle`](crate::adapter::Stats::with_handle) and to the `count_drops` method
+    /// of other adapters to collect their counters in one place.
+    pub fn new() -> StatsHandle {
+        StatsHandle::default()
+    }
+
+    /// Gets a copy of the current counters.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.snapshot.lock().unwrap().clone()
+    }
+
+    /// Resets all counters to zero.
+    pub fn reset(&self) {
+        *self.snapshot.lock().unwrap() = StatsSnapshot::default();
+    }
+
+    pub(crate) fn record_drop(&self, direction: Direction, reason: DropReason, packet: &IpPacket) {
+        let mut snapshot = self.snapshot.lock().unwrap();
+        snapshot.direction_mut(direction).dropped.entry(reason).or_default().add(packet);
+    }
+}
+
+/// Records the items dropped by an adapter which works on any item type, such as
+/// [`Loss`](crate::adapter::Loss). Implemented by `()`, which records nothing, and by
+/// [`StatsHandle`] for IP packets.
+pub trait CountDrops<T> {
+    /// Records that `item`, travelling in `direction`, was dropped.
+    fn count_drop(&self, direction: Direction, reason: DropReason, item: &T);
+}
+
+impl<T> CountDrops<T> for () {
+    fn count_drop(&self, _direction: Direction, _reason: DropReason, _item: &T) {}
+}
+
+impl CountDrops<Box<IpPacket>> for StatsHandle {
+    fn count_drop(&self, direction: Direction, reason: DropReason, packet: &Box<IpPacket>) {
+        self.record_drop(direction, reason, packet);
+    }
+}
+
+impl CountDrops<io::Result<Box<IpPacket>>> for StatsHandle {
+    fn count_drop(&self, direction: Direction, reason: DropReason, packet_res: &io::Result<Box<IpPacket>>) {
+        if let Ok(packet) = packet_res {
+            self.record_drop(direction, reason, packet);
+        }
+    }
+}
+
+impl ProcessPackets for StatsProcessor {
+    fn process(&mut self, direction: Direction, packet: Box<IpPacket>, output: &mut PacketOutput) {
+        {
+            let mut snapshot = self.snapshot.lock().unwrap();
+            let direction_stats = snapshot.direction_mut(direction);
+            direction_stats.total.add(&packet);
+            direction_stats.protocol_mut(packet.protocol()).add(&packet);
+        }
+        output.forward(packet);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{CountDrops, Direction, DropReason, StatsHandle},
+};
+
+/// `Sink`/`Stream` adapter which can be switched on and off to simulate a link going down.
+///
+/// While the link is down, items sent/received through the `Sink`/`Stream` are dropped or, if
+/// [`queue_while_down`](crate::adapter::Switch::queue_while_down) has been set, held back until
+/// the link comes back up.
+///
+/// Can be created via [`SinkStreamExt::with_switch`](crate::SinkStreamExt::with_switch). Use
+/// [`handle`](crate::adapter::Switch::handle) to switch the link.
+///
+/// `C` records dropped items. It's `()`, which records nothing, unless
+/// [`count_drops`](crate::adapter::Switch::count_drops) has been called.
+#[pin_project]
+pub struct Switch<S, T, C = ()>
+where
+    S: Stream + Sink<T>,
+{
+    state: Arc<Mutex<SwitchState>>,
+    queue_while_down: bool,
+    drop_counter: C,
+    stream_finished: bool,
+    #[pin]
+    stream: S,
+    stream_queue: VecDeque<<S as Stream>::Item>,
+    sink_queue: VecDeque<T>,
+}
+
+/// A handle to a [`Switch`](crate::adapter::Switch) which can be used to bring the link down and
+/// back up.
+#[derive(Clone)]
+pub struct SwitchHandle {
+    state: Arc<Mutex<SwitchState>>,
+}
+
+struct SwitchState {
+    up: bool,
+    flap_generation: u64,
+    stream_waker_opt: Option<task::Waker>,
+    sink_waker_opt: Option<task::Waker>,
+}
+
+impl SwitchState {
+    fn set_up(&mut self, up: bool) {
+        self.up = up;
+        if up {
+            if let Some(waker) = self.stream_waker_opt.take() {
+                waker.wake();
+            }
+            if let Some(waker) = self.sink_waker_opt.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<S, T> Switch<S, T>
+where
+    S: Stream + Sink<T>,
+{
+    /// Creates a new [`Switch`]. The link is initially up. See the documentation for
+    /// [`SinkStreamExt::with_switch`](crate::SinkStreamExt::with_switch).
+    pub fn new(stream: S) -> Switch<S, T> {
+        let state = SwitchState {
+            up: true,
+            flap_generation: 0,
+            stream_waker_opt: None,
+            sink_waker_opt: None,
+        };
+        Switch {
+            state: Arc::new(Mutex::new(state)),
+            queue_while_down: false,
+            drop_counter: (),
+            stream_finished: false,
+            stream,
+            stream_queue: VecDeque::new(),
+            sink_queue: VecDeque::new(),
+        }
+    }
+}
+
+impl<S, T, C> Switch<S, T, C>
+where
+    S: Stream + Sink<T>,
+{
+    /// Queue items while the link is down rather than dropping them. Queued items are delivered
+    /// in order once the link comes back up. Flushing or closing the `Sink` while items are queued
+    /// waits for the link to come back up and for the queued items to be sent.
+    pub fn queue_while_down(mut self) -> Self {
+        self.queue_while_down = true;
+        self
+    }
+
+    /// Gets a handle which can be used to switch this link.
+    pub fn handle(&self) -> SwitchHandle {
+        SwitchHandle {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<S> Switch<S, Box<IpPacket>>
+where
+    S: IpSinkStream,
+{
+    /// Records packets dropped while the link is down against the given
+    /// [`Stats`](crate::adapter::Stats) handle as
+    /// [`DropReason::LinkDown`](crate::adapter::DropReason::LinkDown). Packets which are queued
+    /// because [`queue_while_down`](crate::adapter::Switch::queue_while_down) is set aren't
+    /// dropped, so aren't recorded.
+    pub fn count_drops(self, stats: &StatsHandle) -> Switch<S, Box<IpPacket>, StatsHandle> {
+        let Switch {
+            state,
+            queue_while_down,
+            drop_counter: (),
+            stream_finished,
+            stream,
+            stream_queue,
+            sink_queue,
+        } = self;
+        Switch {
+            state,
+            queue_while_down,
+            drop_counter: stats.clone(),
+            stream_finished,
+            stream,
+            stream_queue,
+            sink_queue,
+        }
+    }
+}
+
+impl SwitchHandle {
+    /// Brings the link down. Cancels any schedule started with
+    /// [`flap`](crate::adapter::SwitchHandle::flap).
+    pub fn down(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.flap_generation += 1;
+        state.set_up(false);
+    }
+
+    /// Brings the link up. Cancels any schedule started with
+    /// [`flap`](crate::adapter::SwitchHandle::flap).
+    pub fn up(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.flap_generation += 1;
+        state.set_up(true);
+    }
+
+    /// Checks whether the link is currently up.
+    pub fn is_up(&self) -> bool {
+        self.state.lock().unwrap().up
+    }
+
+    /// Flaps the link according to a schedule. Each `(down_for, up_for)` pair in `schedule` takes
+    /// the link down for `down_for` then brings it back up for `up_for`. Pass an infinite iterator
+    /// (eg. `std::iter::repeat`) to flap the link indefinitely. Calling
+    /// [`down`](crate::adapter::SwitchHandle::down), [`up`](crate::adapter::SwitchHandle::up) or
+    /// `flap` again cancels the schedule.
+    ///
+    /// Must be called within a `tokio` context.
+    pub fn flap<I>(&self, schedule: I)
+    where
+        I: IntoIterator<Item = (Duration, Duration)>,
+        I::IntoIter: Send + 'static,
+    {
+        let flap_generation = {
+            let mut state = self.state.lock().unwrap();
+            state.flap_generation += 1;
+            state.flap_generation
+        };
+        let state = Arc::downgrade(&self.state);
+        let schedule = schedule.into_iter();
+        tokio::spawn(async move {
+            for (down_for, up_for) in schedule {
+                for (up, duration) in [(false, down_for), (true, up_for)] {
+                    {
+                        let Some(state) = state.upgrade() else { return };
+                        let mut state = state.lock().unwrap();
+                        if state.flap_generation != flap_generation {
+                            return;
+                        }
+                        state.set_up(up);
+                    }
+                    tokio::time::sleep(duration).await;
+                }
+            }
+        });
+    }
+}
+
+impl<S, T, C> Stream for Switch<S, T, C>
+where
+    S: Stream + Sink<T>,
+    C: CountDrops<<S as Stream>::Item>,
+{
+    type Item = <S as Stream>::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        let up = {
+            let mut state = this.state.lock().unwrap();
+            if !state.up {
+                state.stream_waker_opt = Some(cx.waker().clone());
+            }
+            state.up
+        };
+        if up {
+            if let Some(value) = this.stream_queue.pop_front() {
+                return Poll::Ready(Some(value));
+            }
+        }
+        if !*this.stream_finished {
+            loop {
+                match this.stream.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(value)) => {
+                        if up {
+                            return Poll::Ready(Some(value));
+                        }
+                        if *this.queue_while_down {
+                            this.stream_queue.push_back(value);
+                        } else {
+                            this.drop_counter.count_drop(Direction::Received, DropReason::LinkDown, &value);
+                        }
+                    },
+                    Poll::Ready(None) => {
+                        *this.stream_finished = true;
+                        break;
+                    },
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+        }
+        if this.stream_queue.is_empty() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<S, T, C> Switch<S, T, C>
+where
+    S: Stream + Sink<T>,
+{
+    fn poll_send_queued(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context,
+    ) -> Poll<Result<bool, <S as Sink<T>>::Error>> {
+        let mut this = self.project();
+        let up = {
+            let mut state = this.state.lock().unwrap();
+            if !state.up {
+                state.sink_waker_opt = Some(cx.waker().clone());
+            }
+            state.up
+        };
+        if up {
+            while !this.sink_queue.is_empty() {
+                ready!(this.stream.as_mut().poll_ready(cx))?;
+                let item = this.sink_queue.pop_front().unwrap();
+                this.stream.as_mut().start_send(item)?;
+            }
+        }
+        Poll::Ready(Ok(up))
+    }
+}
+
+impl<S, T, C> Sink<T> for Switch<S, T, C>
+where
+    S: Stream + Sink<T>,
+    C: CountDrops<T>,
+{
+    type Error = <S as Sink<T>>::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Result<(), Self::Error>> {
+        let up = ready!(self.as_mut().poll_send_queued(cx))?;
+        if up {
+            self.project().stream.poll_ready(cx)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        let this = self.project();
+        let up = this.state.lock().unwrap().up;
+        if up && this.sink_queue.is_empty() {
+            return this.stream.start_send(item);
+        }
+        if up || *this.queue_while_down {
+            this.sink_queue.push_back(item);
+        } else {
+            this.drop_counter.count_drop(Direction::Sent, DropReason::LinkDown, &item);
+        }
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let _up = ready!(self.as_mut().poll_send_queued(cx))?;
+        let this = self.project();
+        ready!(this.stream.poll_flush(cx))?;
+        if this.sink_queue.is_empty() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let _up = ready!(self.as_mut().poll_send_queued(cx))?;
+        let this = self.project();
+        if !this.sink_queue.is_empty() {
+            return Poll::Pending;
+        }
+        this.stream.poll_close(cx)
+    }
+}
@@ -0,0 +1,58 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{
+        Direction,
+        process::{process_adapter, PacketOutput, ProcessPackets},
+    },
+};
+
+process_adapter! {
+    /// `Sink`/`Stream` adapter which copies every packet passing through it onto a separate
+    /// channel, leaving the packets themselves untouched.
+    ///
+    /// Can be created via [`SinkStreamExt::tap`](crate::SinkStreamExt::tap).
+    pub struct Tap<S>(TapProcessor)
+}
+
+/// A copy of a packet which passed through a [`Tap`](crate::adapter::Tap).
+#[derive(Debug, Clone)]
+pub struct TappedPacket {
+    /// The direction the packet was travelling.
+    pub direction: Direction,
+    /// When the packet passed through the tap.
+    pub instant: Instant,
+    /// The packet.
+    pub packet: Box<IpPacket>,
+}
+
+struct TapProcessor {
+    tapped_sender: mpsc::UnboundedSender<TappedPacket>,
+}
+
+impl<S> Tap<S>
+where
+    S: IpSinkStream,
+{
+    /// Creates a new [`Tap`]. See the documentation for
+    /// [`SinkStreamExt::tap`](crate::SinkStreamExt::tap).
+    pub fn new(stream: S) -> (Tap<S>, mpsc::UnboundedReceiver<TappedPacket>) {
+        let (tapped_sender, tapped_receiver) = mpsc::unbounded();
+        let processor = TapProcessor { tapped_sender };
+        let tap = Tap {
+            process: adapter::process::Process::new(stream, processor),
+        };
+        (tap, tapped_receiver)
+    }
+}
+
+impl ProcessPackets for TapProcessor {
+    fn process(&mut self, direction: Direction, packet: Box<IpPacket>, output: &mut PacketOutput) {
+        let tapped = TappedPacket {
+            direction,
+            instant: Instant::now(),
+            packet: packet.clone(),
+        };
+        let _ = self.tapped_sender.unbounded_send(tapped);
+        output.forward(packet);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::priv_prelude::*;
+
+/// The interfaces inserted into a device, each identified by the index it was given when it was
+/// inserted. Interfaces which return an error or end are removed, and their indexes are
+/// remembered until [`take_removed`](IfaceSet::take_removed) is called so that the device can
+/// forget anything else it knows about them.
+pub(crate) struct IfaceSet {
+    ifaces: HashMap<usize, Pin<Box<dyn IpSinkStream>>>,
+    next_index: usize,
+    removed: Vec<usize>,
+}
+
+/// A device's single external interface, which is dropped if it returns an error or ends.
+pub(crate) struct ExternalIface {
+    iface_opt: Option<IpChannel>,
+}
+
+impl IfaceSet {
+    pub fn new() -> IfaceSet {
+        IfaceSet {
+            ifaces: HashMap::new(),
+            next_index: 0,
+            removed: Vec::new(),
+        }
+    }
+
+    /// Inserts an interface, returning its index.
+    pub fn insert(&mut self, iface: Pin<Box<dyn IpSinkStream>>) -> usize {
+        let index = self.next_index;
+        self.ifaces.insert(index, iface);
+        self.next_index += 1;
+        index
+    }
+
+    pub fn indexes(&self) -> Vec<usize> {
+        self.ifaces.keys().copied().collect()
+    }
+
+    fn remove(&mut self, index: usize) {
+        if self.ifaces.remove(&index).is_some() {
+            self.removed.push(index);
+        }
+    }
+
+    /// Returns the indexes of the interfaces which have been removed since this was last called.
+    pub fn take_removed(&mut self) -> Vec<usize> {
+        mem::take(&mut self.removed)
+    }
+
+    /// Sends a packet on the interface at `index`, if it's still there.
+    pub fn start_send(&mut self, index: usize, packet: Box<IpPacket>) {
+        let Some(iface) = self.ifaces.get_mut(&index) else {
+            return;
+        };
+        if iface.as_mut().start_send(packet).is_err() {
+            self.remove(index);
+        }
+    }
+
+    pub fn poll_flush(&mut self, cx: &mut task::Context) -> Poll<()> {
+        let mut defunct_indexes = Vec::new();
+        let mut any_pending = false;
+        for (index, iface) in &mut self.ifaces {
+            match iface.as_mut().poll_flush(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(_)) => {
+                    defunct_indexes.push(*index);
+                },
+                Poll::Pending => {
+                    any_pending = true;
+                },
+            }
+        }
+        for index in defunct_indexes {
+            self.remove(index);
+        }
+        if any_pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+
+    /// Returns `Ready` once every interface can be sent a packet. Interfaces which have been
+    /// fully flushed always can.
+    pub fn poll_ready(&mut self, cx: &mut task::Context) -> Poll<()> {
+        match self.poll_flush(cx) {
+            Poll::Ready(()) => return Poll::Ready(()),
+            Poll::Pending => (),
+        }
+
+        let mut defunct_indexes = Vec::new();
+        let mut any_pending = false;
+        for (index, iface) in &mut self.ifaces {
+            match iface.as_mut().poll_ready(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(_)) => {
+                    defunct_indexes.push(*index);
+                },
+                Poll::Pending => {
+                    any_pending = true;
+                },
+            }
+        }
+        for index in defunct_indexes {
+            self.remove(index);
+        }
+        if any_pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+
+    /// Returns the next packet received on any interface, along with the interface's index.
+    pub fn poll_next(&mut self, cx: &mut task::Context) -> Poll<(usize, Box<IpPacket>)> {
+        let mut defunct_indexes = Vec::new();
+        let mut index_packet_opt = None;
+        for (index, iface) in &mut self.ifaces {
+            match iface.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(packet))) => {
+                    index_packet_opt = Some((*index, packet));
+                    break;
+                },
+                Poll::Ready(Some(Err(_))) | Poll::Ready(None) => {
+                    defunct_indexes.push(*index);
+                },
+                Poll::Pending => (),
+            }
+        }
+        for index in defunct_indexes {
+            self.remove(index);
+        }
+        match index_packet_opt {
+            Some((index, packet)) => Poll::Ready((index, packet)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl ExternalIface {
+    pub fn new(iface: IpChannel) -> ExternalIface {
+        ExternalIface {
+            iface_opt: Some(iface),
+        }
+    }
+
+    pub fn start_send(&mut self, packet: Box<IpPacket>) {
+        let Some(iface) = &mut self.iface_opt else {
+            return;
+        };
+        if Pin::new(iface).start_send(packet).is_err() {
+            self.iface_opt = None;
+        }
+    }
+
+    pub fn poll_flush(&mut self, cx: &mut task::Context) -> Poll<()> {
+        let Some(iface) = &mut self.iface_opt else {
+            return Poll::Ready(());
+        };
+        match Pin::new(iface).poll_flush(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(()),
+            Poll::Ready(Err(_)) => {
+                self.iface_opt = None;
+                Poll::Ready(())
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Returns `Ready` once the interface can be sent a packet. An interface which has been fully
+    /// flushed always can.
+    pub fn poll_ready(&mut self, cx: &mut task::Context) -> Poll<()> {
+        match self.poll_flush(cx) {
+            Poll::Ready(()) => return Poll::Ready(()),
+            Poll::Pending => (),
+        }
+
+        let Some(iface) = &mut self.iface_opt else {
+            return Poll::Ready(());
+        };
+        match Pin::new(iface).poll_ready(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(()),
+            Poll::Ready(Err(_)) => {
+                self.iface_opt = None;
+                Poll::Ready(())
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    pub fn poll_next(&mut self, cx: &mut task::Context) -> Poll<Box<IpPacket>> {
+        let Some(iface) = &mut self.iface_opt else {
+            return Poll::Pending;
+        };
+        match Pin::new(iface).poll_next(cx) {
+            Poll::Ready(Some(Ok(packet))) => Poll::Ready(packet),
+            Poll::Ready(Some(Err(_))) | Poll::Ready(None) => {
+                self.iface_opt = None;
+                Poll::Pending
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
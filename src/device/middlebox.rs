@@ -0,0 +1,354 @@
+use crate::{
+    priv_prelude::*,
+    device::{
+        iface_set::{ExternalIface, IfaceSet},
+        port::{self, PortHandle},
+    },
+    packet::{EcnCodepoint, IpProtocol},
+};
+
+/// The destination ports which [`MiddleboxBuilder::block_non_standard_ports`] allows by default:
+/// DNS, HTTP and HTTPS.
+const STANDARD_PORTS: [u16; 3] = [53, 80, 443];
+
+/// The TCP option kinds which aren't stripped by
+/// [`MiddleboxBuilder::strip_unknown_tcp_options`]: maximum segment size, window scale,
+/// SACK-permitted, SACK and timestamps.
+const KNOWN_TCP_OPTION_KINDS: [u8; 5] = [2, 3, 4, 5, 8];
+
+/// The smallest number of UDP flows the middlebox tracks before it starts pruning idle ones.
+const MIN_UDP_PRUNE_THRESHOLD: usize = 64;
+
+/// A middlebox which interferes with the packets passing through it in ways that real-world
+/// middleboxes are known to.
+///
+/// Like a [`Nat`](crate::device::Nat), the middlebox has an internal side, which any number of
+/// interfaces can be inserted into, and an external side. Packets are forwarded between the two
+/// sides, but not between interfaces on the internal side. Unlike a `Nat`, addresses aren't
+/// translated. By default packets pass through unmodified. Use [`MiddleboxBuilder`] to enable
+/// behaviours.
+pub struct Middlebox {
+    iface_sender: mpsc::UnboundedSender<Pin<Box<dyn IpSinkStream>>>,
+}
+
+/// Builder for creating a [`Middlebox`](crate::device::Middlebox).
+pub struct MiddleboxBuilder {
+    strip_unknown_tcp_options: bool,
+    clear_ecn: bool,
+    ttl_opt: Option<u8>,
+    normalize_ip_ids: bool,
+    allowed_ports_opt: Option<HashSet<u16>>,
+    udp_idle_timeout_opt: Option<Duration>,
+}
+
+type Flow = ((IpAddr, u16), (IpAddr, u16));
+
+struct MiddleboxTask {
+    iface_receiver: mpsc::UnboundedReceiver<Pin<Box<dyn IpSinkStream>>>,
+    external_iface: ExternalIface,
+    internal_ifaces: IfaceSet,
+    internal_addr_indexes: HashMap<IpAddr, usize>,
+    strip_unknown_tcp_options: bool,
+    clear_ecn: bool,
+    ttl_opt: Option<u8>,
+    normalize_ip_ids: bool,
+    next_ip_id: u16,
+    allowed_ports_opt: Option<HashSet<u16>>,
+    udp_idle_timeout_opt: Option<Duration>,
+    udp_last_active: HashMap<Flow, Instant>,
+    /// How large `udp_last_active` can grow before idle flows are pruned from it.
+    udp_prune_threshold: usize,
+}
+
+impl MiddleboxBuilder {
+    /// Starts building a [`Middlebox`](crate::device::Middlebox). Use to enable behaviours then
+    /// call [`build`](crate::device::MiddleboxBuilder::build) to create the middlebox.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> MiddleboxBuilder {
+        MiddleboxBuilder {
+            strip_unknown_tcp_options: false,
+            clear_ecn: false,
+            ttl_opt: None,
+            normalize_ip_ids: false,
+            allowed_ports_opt: None,
+            udp_idle_timeout_opt: None,
+        }
+    }
+
+    /// Replaces any TCP options other than MSS, window scale, SACK and timestamps with NOPs.
+    /// Only applies to IPv4 packets. netsim doesn't parse TCP over IPv6, so IPv6 TCP packets pass
+    /// through with their options intact.
+    pub fn strip_unknown_tcp_options(mut self) -> Self {
+        self.strip_unknown_tcp_options = true;
+        self
+    }
+
+    /// Clears the ECN field of all IP packets.
+    pub fn clear_ecn(mut self) -> Self {
+        self.clear_ecn = true;
+        self
+    }
+
+    /// Rewrites the TTL of IPv4 packets and the hop limit of IPv6 packets to `ttl`.
+    pub fn rewrite_ttl(mut self, ttl: u8) -> Self {
+        self.ttl_opt = Some(ttl);
+        self
+    }
+
+    /// Rewrites the identification field of IPv4 packets with the middlebox's own sequential
+    /// counter.
+    pub fn normalize_ip_ids(mut self) -> Self {
+        self.normalize_ip_ids = true;
+        self
+    }
+
+    /// Drops TCP and UDP packets sent from the internal side to ports other than 53, 80 and 443,
+    /// along with packets sent from such ports on the external side. Use
+    /// [`allow_port`](crate::device::MiddleboxBuilder::allow_port) to allow more ports.
+    pub fn block_non_standard_ports(mut self) -> Self {
+        if self.allowed_ports_opt.is_none() {
+            self.allowed_ports_opt = Some(STANDARD_PORTS.into_iter().collect());
+        }
+        self
+    }
+
+    /// Adds a port to the set of ports allowed by
+    /// [`block_non_standard_ports`](crate::device::MiddleboxBuilder::block_non_standard_ports).
+    /// Implies `block_non_standard_ports`.
+    pub fn allow_port(mut self, port: u16) -> Self {
+        self = self.block_non_standard_ports();
+        self.allowed_ports_opt.as_mut().unwrap().insert(port);
+        self
+    }
+
+    /// Drops UDP packets arriving on the external side unless the internal side has exchanged
+    /// packets on the same flow within the last `timeout`. Packets sent from the internal side
+    /// always open or refresh the flow.
+    pub fn udp_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.udp_idle_timeout_opt = Some(timeout);
+        self
+    }
+
+    /// Build the middlebox. Must be called within a `tokio` context. The returned `IpChannel` is
+    /// the external interface of the middlebox.
+    pub fn build(self) -> (Middlebox, IpChannel) {
+        let MiddleboxBuilder {
+            strip_unknown_tcp_options,
+            clear_ecn,
+            ttl_opt,
+            normalize_ip_ids,
+            allowed_ports_opt,
+            udp_idle_timeout_opt,
+        } = self;
+        let (iface_sender, iface_receiver) = mpsc::unbounded();
+        let (channel_0, channel_1) = IpChannel::new(1);
+        let task = MiddleboxTask {
+            iface_receiver,
+            external_iface: ExternalIface::new(channel_0),
+            internal_ifaces: IfaceSet::new(),
+            internal_addr_indexes: HashMap::new(),
+            strip_unknown_tcp_options,
+            clear_ecn,
+            ttl_opt,
+            normalize_ip_ids,
+            next_ip_id: 0,
+            allowed_ports_opt,
+            udp_idle_timeout_opt,
+            udp_last_active: HashMap::new(),
+            udp_prune_threshold: MIN_UDP_PRUNE_THRESHOLD,
+        };
+        tokio::spawn(task);
+        let middlebox = Middlebox { iface_sender };
+        (middlebox, channel_1)
+    }
+}
+
+impl Middlebox {
+    /// Insert an interface into the internal side of this middlebox. Packets sent by this
+    /// interface will be sent out the middlebox's external interface, and packets arriving on the
+    /// external interface which are addressed to this interface will be forwarded to it.
+    ///
+    /// Returns a [`PortHandle`](crate::device::PortHandle) which can be used to remove the
+    /// interface again.
+    pub fn insert_iface<S>(&mut self, iface: S) -> PortHandle<S>
+    where
+        S: IpSinkStream,
+    {
+        let (port_iface, port_handle) = port::new_port(iface);
+        self.iface_sender.unbounded_send(Box::pin(port_iface)).unwrap();
+        port_handle
+    }
+}
+
+impl MiddleboxTask {
+    fn poll_ready_outgoing(&mut self, cx: &mut task::Context) -> Poll<()> {
+        let external_ready = self.external_iface.poll_ready(cx);
+        let internal_ready = self.internal_ifaces.poll_ready(cx);
+        if external_ready.is_ready() && internal_ready.is_ready() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Forgets which addresses were seen on internal ifaces which have since been removed.
+    fn forget_removed_ifaces(&mut self) {
+        let removed_indexes = self.internal_ifaces.take_removed();
+        if !removed_indexes.is_empty() {
+            self.internal_addr_indexes.retain(|_addr, index| !removed_indexes.contains(index));
+        }
+    }
+
+    /// Checks whether a packet should be let through. `outgoing` is `true` for packets travelling
+    /// from the internal side to the external side.
+    fn allowed(&mut self, outgoing: bool, packet: &IpPacket) -> bool {
+        let Some((source_port, destination_port)) = packet.ports() else {
+            return true;
+        };
+        let internal_end = (packet.source_addr(), source_port);
+        let external_end = (packet.destination_addr(), destination_port);
+        let (internal_end, external_end) = if outgoing {
+            (internal_end, external_end)
+        } else {
+            (external_end, internal_end)
+        };
+        if let Some(allowed_ports) = &self.allowed_ports_opt {
+            if !allowed_ports.contains(&external_end.1) {
+                debug!("middlebox: dropping packet to/from non-standard port {}", external_end.1);
+                return false;
+            }
+        }
+        if let Some(udp_idle_timeout) = self.udp_idle_timeout_opt {
+            if packet.protocol() == IpProtocol::Udp {
+                let now = Instant::now();
+                let flow = (internal_end, external_end);
+                if !outgoing {
+                    let active = match self.udp_last_active.get(&flow) {
+                        Some(last_active) => now.duration_since(*last_active) <= udp_idle_timeout,
+                        None => false,
+                    };
+                    if !active {
+                        debug!("middlebox: dropping incoming udp packet on idle flow {:?}", flow);
+                        self.udp_last_active.remove(&flow);
+                        return false;
+                    }
+                }
+                if self.udp_last_active.insert(flow, now).is_none() {
+                    self.prune_idle_udp_flows(now, udp_idle_timeout);
+                }
+            }
+        }
+        true
+    }
+
+    /// Removes idle flows once `udp_last_active` has grown past the prune threshold, so that flows
+    /// which are never used again don't accumulate.
+    fn prune_idle_udp_flows(&mut self, now: Instant, udp_idle_timeout: Duration) {
+        if self.udp_last_active.len() < self.udp_prune_threshold {
+            return;
+        }
+        self.udp_last_active.retain(|_, last_active| now.duration_since(*last_active) <= udp_idle_timeout);
+        self.udp_prune_threshold = cmp::max(self.udp_last_active.len() * 2, MIN_UDP_PRUNE_THRESHOLD);
+    }
+
+    fn rewrite(&mut self, packet: &mut IpPacket) {
+        match packet.version_mut() {
+            IpPacketVersion::V4(packet) => {
+                if self.clear_ecn && packet.ecn() != EcnCodepoint::NotEct {
+                    packet.set_ecn(EcnCodepoint::NotEct);
+                }
+                if let Some(ttl) = self.ttl_opt {
+                    packet.set_ttl(ttl);
+                }
+                if self.normalize_ip_ids {
+                    packet.set_identification(self.next_ip_id);
+                    self.next_ip_id = self.next_ip_id.wrapping_add(1);
+                }
+                if self.strip_unknown_tcp_options {
+                    if let Ipv4PacketProtocol::Tcp(packet) = packet.protocol_mut() {
+                        packet.retain_options(|kind| KNOWN_TCP_OPTION_KINDS.contains(&kind));
+                    }
+                }
+            },
+            IpPacketVersion::V6(packet) => {
+                if self.clear_ecn {
+                    packet.set_ecn(EcnCodepoint::NotEct);
+                }
+                if let Some(ttl) = self.ttl_opt {
+                    packet.set_hop_limit(ttl);
+                }
+            },
+        }
+    }
+
+    fn dispatch_incoming_external(&mut self, mut packet: Box<IpPacket>) {
+        if !self.allowed(false, &packet) {
+            return;
+        }
+        self.rewrite(&mut packet);
+        self.forget_removed_ifaces();
+        let indexes = match self.internal_addr_indexes.get(&packet.destination_addr()) {
+            Some(index) => vec![*index],
+            None => self.internal_ifaces.indexes(),
+        };
+        for index in indexes {
+            self.internal_ifaces.start_send(index, packet.clone());
+        }
+    }
+
+    fn dispatch_incoming_internal(&mut self, iface_index: usize, mut packet: Box<IpPacket>) {
+        self.forget_removed_ifaces();
+        self.internal_addr_indexes.insert(packet.source_addr(), iface_index);
+        if !self.allowed(true, &packet) {
+            return;
+        }
+        self.rewrite(&mut packet);
+        self.external_iface.start_send(packet);
+    }
+
+    fn poll_inner(&mut self, cx: &mut task::Context) -> Poll<()> {
+        loop {
+            match Pin::new(&mut self.iface_receiver).poll_next(cx) {
+                Poll::Ready(Some(iface)) => {
+                    self.internal_ifaces.insert(iface);
+                },
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            match self.poll_ready_outgoing(cx) {
+                Poll::Ready(()) => (),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            match self.external_iface.poll_next(cx) {
+                Poll::Ready(packet) => {
+                    self.dispatch_incoming_external(packet);
+                    continue;
+                },
+                Poll::Pending => (),
+            }
+
+            match self.internal_ifaces.poll_next(cx) {
+                Poll::Ready((index, packet)) => {
+                    self.dispatch_incoming_internal(index, packet);
+                    continue;
+                },
+                Poll::Pending => (),
+            }
+
+            break Poll::Pending;
+        }
+    }
+}
+
+impl Future for MiddleboxTask {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<()> {
+        let this = self.get_mut();
+        this.poll_inner(cx)
+    }
+}
@@ -2,10 +2,19 @@
 
 mod channel;
 mod hub;
+mod iface_set;
+mod middlebox;
 mod nat;
+mod port;
+mod router;
+mod switch;
 
 pub use self::{
     channel::{BiChannel, IpChannel},
     hub::IpHub,
-    nat::{Nat, NatBuilder},
+    middlebox::{Middlebox, MiddleboxBuilder},
+    nat::{InboundDropReason, Nat, NatBuilder, NatEvent, NatMapping, PermittedRemotes},
+    port::PortHandle,
+    router::IpRouter,
+    switch::IpSwitch,
 };
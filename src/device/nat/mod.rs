@@ -1,49 +1,237 @@
 use {
-    crate::priv_prelude::*,
+    crate::{
+        priv_prelude::*,
+        device::{
+            iface_set::{ExternalIface, IfaceSet},
+            port::{self, PortHandle},
+        },
+        packet::{Icmpv4Packet, IpProtocol, Ipv4Packet},
+    },
+    std::{collections::hash_map, ops::RangeInclusive},
     self::{
-        port_map::PortMap,
+        monitor::NatRequest,
+        pool::{AddressPool, Pooling},
+        port_map::{Mapping, PortAllocation, PortMap, PortMapEntry, PortStrategy},
         restrictions::Restrictions,
     },
 };
+mod monitor;
+mod pcp;
+mod pool;
 mod port_map;
 mod restrictions;
+mod upnp;
+
+pub use self::monitor::{InboundDropReason, NatEvent, NatMapping, PermittedRemotes};
 
 /// A simple NAT (network address translation) implementation.
 ///
 /// For testing network code across NATs.
 pub struct Nat {
     iface_sender: mpsc::UnboundedSender<Pin<Box<dyn IpSinkStream>>>,
+    request_sender: mpsc::UnboundedSender<NatRequest>,
 }
 
 /// Builder for creating a [`Nat`](crate::device::Nat).
 pub struct NatBuilder {
     external_ipv4: Ipv4Addr,
+    external_ipv4_pool: Vec<Ipv4Addr>,
+    arbitrary_pooling: bool,
     internal_ipv4_network: Ipv4Network,
+    internal_ipv4_opt: Option<Ipv4Addr>,
     hair_pinning: bool,
     address_restricted: bool,
     port_restricted: bool,
+    address_dependent_mapping: bool,
+    address_and_port_dependent_mapping: bool,
+    port_allocation: PortAllocation,
+    forwarded_ports: Vec<(IpProtocol, u16, SocketAddrV4)>,
+    dmz_host_opt: Option<Ipv4Addr>,
+    pcp_server: bool,
+    upnp_igd: bool,
     reply_with_rst_to_unexpected_tcp_packets: bool,
+    udp_mapping_timeout_opt: Option<Duration>,
+    tcp_mapping_timeouts_opt: Option<TcpMappingTimeouts>,
+    icmp_mapping_timeout: Duration,
+    tcp_time_wait_opt: Option<Duration>,
+    inbound_refreshes_mappings: bool,
+}
+
+/// How long ICMP echo mappings last without being refreshed, unless configured otherwise. This is
+/// the minimum allowed by RFC 5508 (REQ-1).
+const DEFAULT_ICMP_MAPPING_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy)]
+struct TcpMappingTimeouts {
+    established: Duration,
+    transitory: Duration,
+}
+
+/// What the NAT has seen of one TCP connection through a mapping, used to pick the mapping's idle
+/// timeout. The connection counts as established once packets have been seen in both directions,
+/// and becomes transitory again when a FIN or RST is seen or a new connection is started with the
+/// same remote address. A mapping uses the established timeout while any of its connections is
+/// established (RFC 5382 section 5).
+///
+/// With [connection tracking](crate::device::NatBuilder::tcp_connection_tracking) enabled this is
+/// also the connection's conntrack state: it decides which packets fit the connection, and how
+/// long the connection lingers in TIME_WAIT once it's closed.
+struct TcpMappingState {
+    seen_outbound: bool,
+    seen_inbound: bool,
+    seen_outbound_syn: bool,
+    seen_inbound_syn: bool,
+    seen_outbound_fin: bool,
+    seen_inbound_fin: bool,
+    closing: bool,
+    last_activity: Instant,
+    time_wait_until_opt: Option<Instant>,
+}
+
+fn is_initial_syn(flags: TcpPacketFlags) -> bool {
+    flags.syn && !flags.ack && !flags.rst
+}
+
+impl TcpMappingState {
+    fn new() -> TcpMappingState {
+        TcpMappingState {
+            seen_outbound: false,
+            seen_inbound: false,
+            seen_outbound_syn: false,
+            seen_inbound_syn: false,
+            seen_outbound_fin: false,
+            seen_inbound_fin: false,
+            closing: false,
+            last_activity: Instant::now(),
+            time_wait_until_opt: None,
+        }
+    }
+
+    /// Whether a packet with `flags` fits the connection. Until a side has sent a SYN (either the
+    /// opening SYN, a SYN-ACK or a simultaneous open) the only other thing it can validly send is
+    /// a RST.
+    fn fits(&self, outbound: bool, flags: TcpPacketFlags) -> bool {
+        let sender_seen_syn = if outbound {
+            self.seen_outbound_syn
+        } else {
+            self.seen_inbound_syn
+        };
+        flags.syn || flags.rst || sender_seen_syn
+    }
+
+    /// Records a packet. Once the connection is closed it enters TIME_WAIT for `time_wait_opt`,
+    /// if connection tracking is enabled.
+    fn update(&mut self, outbound: bool, flags: TcpPacketFlags, time_wait_opt: Option<Duration>) {
+        if is_initial_syn(flags) {
+            *self = TcpMappingState::new();
+        }
+        let now = Instant::now();
+        if outbound {
+            self.seen_outbound = true;
+            self.seen_outbound_syn |= flags.syn;
+            self.seen_outbound_fin |= flags.fin;
+        } else {
+            self.seen_inbound = true;
+            self.seen_inbound_syn |= flags.syn;
+            self.seen_inbound_fin |= flags.fin;
+        }
+        if flags.fin || flags.rst {
+            self.closing = true;
+        }
+        self.last_activity = now;
+        if let Some(time_wait) = time_wait_opt {
+            let closed = flags.rst || (self.seen_outbound_fin && self.seen_inbound_fin);
+            if closed && self.time_wait_until_opt.is_none() {
+                self.time_wait_until_opt = Some(now + time_wait);
+            }
+        }
+    }
+
+    fn is_established(&self) -> bool {
+        self.seen_outbound && self.seen_inbound && !self.closing
+    }
+
+    /// When the NAT can forget the connection: once it has finished TIME_WAIT, or once it has
+    /// been idle for as long as a mapping carrying only this connection would last.
+    fn expiry(&self, timeouts_opt: Option<TcpMappingTimeouts>) -> Option<Instant> {
+        let idle_expiry_opt = timeouts_opt.map(|timeouts| {
+            let timeout = if self.is_established() {
+                timeouts.established
+            } else {
+                timeouts.transitory
+            };
+            self.last_activity + timeout
+        });
+        [self.time_wait_until_opt, idle_expiry_opt].into_iter().flatten().min()
+    }
 }
 
 impl NatBuilder {
     /// Starts building a [`Nat`](crate::device::Nat). Use to configure the NAT then call
     /// [`build`](crate::device::NatBuilder::build) to create the NAT.
     ///
-    /// * `external_ipv4` is the IPv4 address that the NAT uses on its external side.
+    /// * `external_ipv4` is the IPv4 address that the NAT uses on its external side. More
+    ///   addresses can be added with
+    ///   [`external_ipv4_pool`](crate::device::NatBuilder::external_ipv4_pool).
     /// * `internal_ipv4_network` is the IPv4 network (eg. 192.168.0.0/16) on the internal side of
     ///   the NAT. The NAT won't forward any packets on its internal side that don't originate from
     ///   this network.
     pub fn new(external_ipv4: Ipv4Addr, internal_ipv4_network: Ipv4Network) -> NatBuilder {
         NatBuilder {
             external_ipv4,
+            external_ipv4_pool: Vec::new(),
+            arbitrary_pooling: false,
             internal_ipv4_network,
+            internal_ipv4_opt: None,
             hair_pinning: false,
             address_restricted: false,
             port_restricted: false,
+            address_dependent_mapping: false,
+            address_and_port_dependent_mapping: false,
+            port_allocation: PortAllocation::default(),
+            forwarded_ports: Vec::new(),
+            dmz_host_opt: None,
+            pcp_server: false,
+            upnp_igd: false,
             reply_with_rst_to_unexpected_tcp_packets: false,
+            udp_mapping_timeout_opt: None,
+            tcp_mapping_timeouts_opt: None,
+            icmp_mapping_timeout: DEFAULT_ICMP_MAPPING_TIMEOUT,
+            tcp_time_wait_opt: None,
+            inbound_refreshes_mappings: false,
         }
     }
 
+    /// Sets the NAT's own address on its internal network, ie. the gateway address of the hosts
+    /// behind it. Packets sent to this address are handled by the NAT itself. Defaults to the
+    /// first address in the internal network, eg. 192.168.0.1.
+    pub fn internal_ipv4(mut self, internal_ipv4: Ipv4Addr) -> Self {
+        self.internal_ipv4_opt = Some(internal_ipv4);
+        self
+    }
+
+    /// Gives the NAT more external addresses, eg. from a `Vec` of addresses or an
+    /// [`Ipv4Network`](crate::Ipv4Network), as with a carrier-grade NAT. The NAT answers on all of
+    /// them and spreads its mappings across them according to its pooling behaviour, which is
+    /// paired by default. Each address has its own [range](crate::device::NatBuilder::port_range)
+    /// of external ports. With paired pooling, forwarded ports, PCP and UPnP use the address the
+    /// host they forward to is paired with, and report that address as the NAT's external
+    /// address. With arbitrary pooling they use the address passed to
+    /// [`new`](crate::device::NatBuilder::new).
+    pub fn external_ipv4_pool(mut self, external_ipv4s: impl IntoIterator<Item = Ipv4Addr>) -> Self {
+        self.external_ipv4_pool.extend(external_ipv4s);
+        self
+    }
+
+    /// Lets each mapping use any of the NAT's
+    /// [external addresses](crate::device::NatBuilder::external_ipv4_pool), rather than pairing
+    /// each internal address with one external address (RFC 4787 REQ-2). This breaks protocols
+    /// which expect all of a host's traffic to come from the same address.
+    pub fn arbitrary_pooling(mut self) -> Self {
+        self.arbitrary_pooling = true;
+        self
+    }
+
     /// Enables [NAT hair-pinning](https://en.wikipedia.org/wiki/Network_address_translation#NAT_hairpinning).
     pub fn hair_pinning(mut self) -> Self {
         self.hair_pinning = true;
@@ -67,63 +255,300 @@ impl NatBuilder {
         self
     }
 
+    /// Makes this NAT use a different external port for each remote IP address that an internal
+    /// address sends to. By default the same external port is used for all remote addresses.
+    pub fn address_dependent_mapping(mut self) -> Self {
+        self.address_dependent_mapping = true;
+        self
+    }
+
+    /// Makes this NAT use a different external port for each remote IP address and port that an
+    /// internal address sends to, ie. makes this a [symmetric NAT](https://en.wikipedia.org/wiki/Network_address_translation#Symmetric_NAT).
+    /// Takes precedence over
+    /// [`address_dependent_mapping`](crate::device::NatBuilder::address_dependent_mapping).
+    pub fn address_and_port_dependent_mapping(mut self) -> Self {
+        self.address_and_port_dependent_mapping = true;
+        self
+    }
+
+    /// Sets the range of external ports that the NAT allocates from. Defaults to `1025..=65535`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is empty.
+    pub fn port_range(mut self, range: RangeInclusive<u16>) -> Self {
+        assert!(!range.is_empty());
+        self.port_allocation.min_port = *range.start();
+        self.port_allocation.max_port = *range.end();
+        self
+    }
+
+    /// Makes the NAT allocate external ports sequentially, moving `step` ports along the port range
+    /// for each new mapping. This is the default, with a `step` of 1.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `step` is zero.
+    pub fn sequential_port_allocation(mut self, step: u16) -> Self {
+        assert!(step > 0);
+        self.port_allocation.strategy = PortStrategy::Sequential { step };
+        self
+    }
+
+    /// Makes the NAT allocate external ports at random from the port range.
+    pub fn random_port_allocation(mut self) -> Self {
+        self.port_allocation.strategy = PortStrategy::Random;
+        self
+    }
+
+    /// Makes the NAT reuse the internal port as the external port when it's free and within the
+    /// port range, falling back to the allocation strategy otherwise.
+    pub fn port_preserving(mut self) -> Self {
+        self.port_allocation.preserve_port = true;
+        self
+    }
+
+    /// Makes the NAT only allocate external ports with the same parity (odd or even) as the
+    /// internal port, as recommended by RFC 4787 for the sake of RTP/RTCP.
+    pub fn port_parity(mut self) -> Self {
+        self.port_allocation.preserve_parity = true;
+        self
+    }
+
+    /// Makes the NAT try to map consecutive internal ports to consecutive external ports. When an
+    /// internal port is mapped and the port below it already has a mapping, the port after that
+    /// mapping's external port is used if it's free.
+    pub fn port_contiguity(mut self) -> Self {
+        self.port_allocation.contiguous = true;
+        self
+    }
+
+    /// Forwards `protocol` packets arriving at the NAT's external address on `external_port` to
+    /// `internal_addr`, as with a port forwarded on a home router. If the NAT has an
+    /// [address pool](crate::device::NatBuilder::external_ipv4_pool) then the port is forwarded
+    /// on the address that `internal_addr`'s host is paired with. Forwarded packets bypass the
+    /// NAT's address and port restrictions, and packets sent from `internal_addr` always use
+    /// `external_port`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `protocol` is not TCP or UDP.
+    pub fn forward_port(mut self, protocol: IpProtocol, external_port: u16, internal_addr: SocketAddrV4) -> Self {
+        assert!(matches!(protocol, IpProtocol::Tcp | IpProtocol::Udp));
+        self.forwarded_ports.push((protocol, external_port, internal_addr));
+        self
+    }
+
+    /// Makes `internal_ipv4` the NAT's DMZ host. Inbound TCP and UDP packets which don't match
+    /// any mapping are forwarded to the DMZ host on the same port, rather than being dropped, and
+    /// a mapping is created so that the DMZ host's replies go out through that port. Like a
+    /// forwarded port, the mapping bypasses the NAT's address and port restrictions.
+    pub fn dmz_host(mut self, internal_ipv4: Ipv4Addr) -> Self {
+        self.dmz_host_opt = Some(internal_ipv4);
+        self
+    }
+
+    /// Makes the NAT answer [NAT-PMP](https://datatracker.ietf.org/doc/html/rfc6886) and
+    /// [PCP](https://datatracker.ietf.org/doc/html/rfc6887) requests sent to its
+    /// [internal address](crate::device::NatBuilder::internal_ipv4). Hosts behind the NAT can then
+    /// query the NAT's external address and create, renew and delete port mappings. Mappings
+    /// created this way accept packets from any remote address.
+    pub fn pcp_server(mut self) -> Self {
+        self.pcp_server = true;
+        self
+    }
+
+    /// Makes the NAT act as a UPnP Internet Gateway Device on its
+    /// [internal address](crate::device::NatBuilder::internal_ipv4). The NAT answers SSDP searches
+    /// and serves a device description and a WANIPConnection control endpoint over HTTP on port
+    /// 5000. The control endpoint supports the `GetExternalIPAddress`, `AddPortMapping` and
+    /// `DeletePortMapping` actions. Mappings added this way accept packets from any remote
+    /// address, and can only be deleted by the host they forward to.
+    pub fn upnp_igd(mut self) -> Self {
+        self.upnp_igd = true;
+        self
+    }
+
+    /// Makes UDP mappings expire once they've been idle for `timeout`. By default mappings never
+    /// expire. Once a mapping has expired, the next outgoing packet from the same internal address
+    /// is given a fresh external port.
+    pub fn udp_mapping_timeout(mut self, timeout: Duration) -> Self {
+        self.udp_mapping_timeout_opt = Some(timeout);
+        self
+    }
+
+    /// Makes TCP mappings expire once they've been idle for `established` if a connection through
+    /// the mapping appears to be established, or for `transitory` if the connection is still being
+    /// set up or is closing. By default mappings never expire. RFC 5382 suggests 2 hours 4
+    /// minutes and 4 minutes respectively.
+    pub fn tcp_mapping_timeouts(mut self, established: Duration, transitory: Duration) -> Self {
+        self.tcp_mapping_timeouts_opt = Some(TcpMappingTimeouts { established, transitory });
+        self
+    }
+
+    /// Makes ICMP echo mappings, which let echo replies back in to the host which sent the echo
+    /// request, expire once they've been idle for `timeout`. Defaults to 60 seconds, the minimum
+    /// allowed by RFC 5508.
+    pub fn icmp_mapping_timeout(mut self, timeout: Duration) -> Self {
+        self.icmp_mapping_timeout = timeout;
+        self
+    }
+
+    /// Makes the NAT track the state of the TCP connections passing through it. Only outbound
+    /// SYNs create mappings, inbound packets are only forwarded if they fit the state of a
+    /// connection (or open a new one on a port that allows it), and a connection is forgotten
+    /// `time_wait` after it's closed by a RST or by FINs in both directions. A mapping is removed
+    /// once all the connections through it are gone. Linux uses a `time_wait` of 2 minutes.
+    ///
+    /// Since an inbound SYN is accepted once the NAT has seen an outbound SYN to the same remote
+    /// address, this allows TCP hole punching with simultaneous open.
+    pub fn tcp_connection_tracking(mut self, time_wait: Duration) -> Self {
+        self.tcp_time_wait_opt = Some(time_wait);
+        self
+    }
+
+    /// Makes inbound packets refresh the mapping they pass through. By default only outbound
+    /// packets keep a mapping alive.
+    pub fn inbound_refreshes_mappings(mut self) -> Self {
+        self.inbound_refreshes_mappings = true;
+        self
+    }
+
     /// Build the NAT. The returned `IpChannel` is the external interface of the NAT.
     pub fn build(self) -> (Nat, IpChannel) {
         let NatBuilder {
             external_ipv4,
+            external_ipv4_pool,
+            arbitrary_pooling,
             internal_ipv4_network,
+            internal_ipv4_opt,
             hair_pinning,
             address_restricted,
             port_restricted,
+            address_dependent_mapping,
+            address_and_port_dependent_mapping,
+            port_allocation,
+            forwarded_ports,
+            dmz_host_opt,
+            pcp_server,
+            upnp_igd,
             reply_with_rst_to_unexpected_tcp_packets,
+            udp_mapping_timeout_opt,
+            tcp_mapping_timeouts_opt,
+            icmp_mapping_timeout,
+            tcp_time_wait_opt,
+            inbound_refreshes_mappings,
         } = self;
+        let internal_ipv4 = internal_ipv4_opt.unwrap_or_else(|| {
+            Ipv4Addr::from(u32::from(internal_ipv4_network.base_addr()) + 1)
+        });
+        let mut external_ipv4s = vec![external_ipv4];
+        for pool_ipv4 in external_ipv4_pool {
+            if !external_ipv4s.contains(&pool_ipv4) {
+                external_ipv4s.push(pool_ipv4);
+            }
+        }
+        let pooling = if arbitrary_pooling { Pooling::Arbitrary } else { Pooling::Paired };
         let (iface_sender, iface_receiver) = mpsc::unbounded();
+        let (request_sender, request_receiver) = mpsc::unbounded();
         let (channel_0, channel_1) = IpChannel::new(1);
-        let tcpv4_restrictions = match (port_restricted, address_restricted) {
-            (false, false) => Restrictions::Unrestricted,
-            (false, true) => Restrictions::RestrictIpAddr { sent_to: HashMap::new() },
-            (true, _) => Restrictions::RestrictSocketAddr { sent_to: HashMap::new() },
-        };
-        let udpv4_restrictions = match (port_restricted, address_restricted) {
-            (false, false) => Restrictions::Unrestricted,
-            (false, true) => Restrictions::RestrictIpAddr { sent_to: HashMap::new() },
-            (true, _) => Restrictions::RestrictSocketAddr { sent_to: HashMap::new() },
+        let tcpv4_restrictions = Restrictions::new(port_restricted, address_restricted);
+        let udpv4_restrictions = Restrictions::new(port_restricted, address_restricted);
+        let icmpv4_restrictions = Restrictions::new(port_restricted, address_restricted);
+        let mapping = match (address_and_port_dependent_mapping, address_dependent_mapping) {
+            (false, false) => Mapping::EndpointIndependent,
+            (false, true) => Mapping::AddressDependent,
+            (true, _) => Mapping::AddressAndPortDependent,
         };
+        let mut external_ipv4_pool = AddressPool::new(external_ipv4s, pooling);
+        let mut tcpv4_port_map = PortMap::new(mapping, port_allocation.clone());
+        let mut udpv4_port_map = PortMap::new(mapping, port_allocation.clone());
+        for (protocol, external_port, internal_addr) in forwarded_ports {
+            let external_ipv4 = external_ipv4_pool.host_ipv4(*internal_addr.ip());
+            let external_addr = SocketAddrV4::new(external_ipv4, external_port);
+            match protocol {
+                IpProtocol::Tcp => tcpv4_port_map.forward(external_addr, internal_addr),
+                IpProtocol::Udp => udpv4_port_map.forward(external_addr, internal_addr),
+                IpProtocol::Icmp | IpProtocol::Other(_) => unreachable!(),
+            }
+        }
         let task = NatTask {
             iface_receiver,
-            external_iface_opt: Some(channel_0),
-            internal_ifaces: HashMap::new(),
-            next_internal_iface_index: 0,
+            request_receiver,
+            event_senders: Vec::new(),
+            external_iface: ExternalIface::new(channel_0),
+            internal_ifaces: IfaceSet::new(),
             external_ipv4,
+            external_ipv4_pool,
+            internal_ipv4,
             internal_ipv4_network,
             internal_addr_indexes: HashMap::new(),
-            tcpv4_port_map: PortMap::new(),
-            udpv4_port_map: PortMap::new(),
+            tcpv4_port_map,
+            udpv4_port_map,
+            icmpv4_port_map: PortMap::new(mapping, port_allocation.clone()),
             hair_pinning,
             tcpv4_restrictions,
             udpv4_restrictions,
+            icmpv4_restrictions,
+            dmz_host_opt,
+            pcp_server,
+            upnp_igd,
+            upnp_connections: HashMap::new(),
+            gateway_packets: VecDeque::new(),
+            start_instant: Instant::now(),
             reply_with_rst_to_unexpected_tcp_packets,
+            udp_mapping_timeout_opt,
+            tcp_mapping_timeouts_opt,
+            icmp_mapping_timeout,
+            inbound_refreshes_mappings,
+            tcpv4_mapping_states: HashMap::new(),
+            tcp_time_wait_opt,
+            expiry_sleep_opt: None,
         };
         tokio::spawn(task);
-        let nat = Nat { iface_sender };
+        let nat = Nat { iface_sender, request_sender };
         (nat, channel_1)
     }
 }
 
 struct NatTask {
     iface_receiver: mpsc::UnboundedReceiver<Pin<Box<dyn IpSinkStream>>>,
-    external_iface_opt: Option<IpChannel>,
-    internal_ifaces: HashMap<usize, Pin<Box<dyn IpSinkStream>>>,
-    next_internal_iface_index: usize,
+    request_receiver: mpsc::UnboundedReceiver<NatRequest>,
+    event_senders: Vec<mpsc::UnboundedSender<NatEvent>>,
+    external_iface: ExternalIface,
+    internal_ifaces: IfaceSet,
+    /// The NAT's primary external address, used for forwarded ports and by PCP and UPnP when
+    /// pooling is arbitrary.
     external_ipv4: Ipv4Addr,
+    external_ipv4_pool: AddressPool,
+    internal_ipv4: Ipv4Addr,
     internal_ipv4_network: Ipv4Network,
     internal_addr_indexes: HashMap<IpAddr, usize>,
     tcpv4_port_map: PortMap,
     udpv4_port_map: PortMap,
+    icmpv4_port_map: PortMap,
     hair_pinning: bool,
     tcpv4_restrictions: Restrictions,
     udpv4_restrictions: Restrictions,
+    icmpv4_restrictions: Restrictions,
+    dmz_host_opt: Option<Ipv4Addr>,
+    pcp_server: bool,
+    upnp_igd: bool,
+    upnp_connections: HashMap<SocketAddrV4, upnp::HttpConnection>,
+    gateway_packets: VecDeque<(Ipv4Addr, Box<IpPacket>)>,
+    start_instant: Instant,
     reply_with_rst_to_unexpected_tcp_packets: bool,
+    udp_mapping_timeout_opt: Option<Duration>,
+    tcp_mapping_timeouts_opt: Option<TcpMappingTimeouts>,
+    icmp_mapping_timeout: Duration,
+    inbound_refreshes_mappings: bool,
+    /// The state of each remote address's connection through each TCP mapping.
+    tcpv4_mapping_states: HashMap<SocketAddrV4, HashMap<SocketAddrV4, TcpMappingState>>,
+    /// How long closed TCP connections linger in TIME_WAIT, if connection tracking is enabled.
+    tcp_time_wait_opt: Option<Duration>,
+    /// Wakes the task when the next mapping, forwarded port or connection expires, so that expiry
+    /// doesn't have to wait for the next packet.
+    expiry_sleep_opt: Option<Pin<Box<tokio::time::Sleep>>>,
 }
 
 impl Nat {
@@ -131,150 +556,436 @@ impl Nat {
     /// addresses outside the NAT's internal network will be address translated and sent out
     /// the NAT's external interface. This creates a port-mapping which allows external hosts to
     /// send packets back through the NAT to this interface.
-    pub fn insert_iface<S>(&mut self, iface: S)
+    ///
+    /// Returns a [`PortHandle`](crate::device::PortHandle) which can be used to remove the
+    /// interface again.
+    pub fn insert_iface<S>(&mut self, iface: S) -> PortHandle<S>
     where
         S: IpSinkStream,
     {
-        let iface = Box::pin(iface);
-        self.iface_sender.unbounded_send(iface).unwrap();
+        let (port_iface, port_handle) = port::new_port(iface);
+        self.iface_sender.unbounded_send(Box::pin(port_iface)).unwrap();
+        port_handle
+    }
+
+    /// Takes a snapshot of the NAT's TCP and UDP mappings, including forwarded ports.
+    pub async fn mappings(&self) -> Vec<NatMapping> {
+        let (mappings_sender, mappings_receiver) = oneshot::channel();
+        let request = NatRequest::Mappings(mappings_sender);
+        if self.request_sender.unbounded_send(request).is_err() {
+            return Vec::new();
+        }
+        mappings_receiver.await.unwrap_or_default()
+    }
+
+    /// Subscribes to events on the NAT: mappings being created, refreshed and expiring, and
+    /// inbound packets being dropped. Only events which happen after the call are reported.
+    pub fn events(&self) -> mpsc::UnboundedReceiver<NatEvent> {
+        let (event_sender, event_receiver) = mpsc::unbounded();
+        let _ = self.request_sender.unbounded_send(NatRequest::Subscribe(event_sender));
+        event_receiver
     }
 }
 
 impl NatTask {
-    fn poll_flush_outgoing(&mut self, cx: &mut task::Context) -> Poll<()> {
-        let mut any_pending = false;
-
-        match &mut self.external_iface_opt {
-            None => (),
-            Some(external_iface) => {
-                match Pin::new(external_iface).poll_flush(cx) {
-                    Poll::Ready(Ok(())) => (),
-                    Poll::Ready(Err(_)) => {
-                        self.external_iface_opt = None;
+    fn poll_ready_outgoing(&mut self, cx: &mut task::Context) -> Poll<()> {
+        let external_ready = self.external_iface.poll_ready(cx);
+        let internal_ready = self.internal_ifaces.poll_ready(cx);
+        if external_ready.is_ready() && internal_ready.is_ready() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn send_external(&mut self, packet: Box<IpPacket>) {
+        self.external_iface.start_send(packet);
+    }
+
+    /// Forgets which internal addresses were seen on internal ifaces which have since been
+    /// removed.
+    fn forget_removed_ifaces(&mut self) {
+        let removed_indexes = self.internal_ifaces.take_removed();
+        if !removed_indexes.is_empty() {
+            self.internal_addr_indexes.retain(|_addr, iface_index| !removed_indexes.contains(iface_index));
+        }
+    }
+
+    /// Sends a packet to the internal iface which `internal_ip` was last seen on, or to all
+    /// internal ifaces if it hasn't been seen yet.
+    fn send_internal(&mut self, internal_ip: Ipv4Addr, packet: Box<IpPacket>) {
+        self.forget_removed_ifaces();
+        let iface_index = match self.internal_addr_indexes.get(&IpAddr::V4(internal_ip)) {
+            Some(iface_index) => *iface_index,
+            None => {
+                let iface_indexes = self.internal_ifaces.indexes();
+                for iface_index in iface_indexes {
+                    self.send_internal_iface(iface_index, packet.clone());
+                }
+                return;
+            },
+        };
+        self.send_internal_iface(iface_index, packet);
+    }
+
+    fn send_internal_iface(&mut self, iface_index: usize, packet: Box<IpPacket>) {
+        if log_enabled!(Level::Debug) {
+            debug!(
+                "{}: forwarding packet on internal iface #{} {:?}",
+                self.external_ipv4,
+                iface_index,
+                packet,
+            );
+        }
+        self.internal_ifaces.start_send(iface_index, packet);
+    }
+
+    /// Queues a packet sent by the NAT's own services, which may send several packets in response
+    /// to one, to be sent to `internal_ip` once the internal ifaces are ready.
+    fn send_from_gateway(&mut self, internal_ip: Ipv4Addr, packet: Box<IpPacket>) {
+        self.gateway_packets.push_back((internal_ip, packet));
+    }
+
+    /// Sends a packet which has been translated to come from the NAT's external address, looping
+    /// it back through the NAT if it's addressed to the NAT's own external address.
+    fn send_translated_outgoing(&mut self, packet: Box<Ipv4Packet>) {
+        if log_enabled!(Level::Debug) {
+            debug!(
+                "{}: translated outgoing packet {:?}",
+                self.external_ipv4,
+                packet,
+            );
+        }
+        if self.external_ipv4_pool.contains(packet.destination_addr()) {
+            if self.hair_pinning {
+                self.dispatch_incoming_external(packet.ip_packet_box());
+            } else {
+                debug!(
+                    "{}: dropped internal packet from {} addressed to own external address {} since hair-pinning is disabled",
+                    self.external_ipv4, packet.source_addr(), packet.destination_addr(),
+                );
+                match packet.protocol_ref() {
+                    Ipv4PacketProtocol::Tcp(packet) => {
+                        self.emit_hair_pinning_dropped(IpProtocol::Tcp, packet.source_addr(), packet.destination_addr());
                     },
-                    Poll::Pending => {
-                        any_pending = true;
+                    Ipv4PacketProtocol::Udp(packet) => {
+                        self.emit_hair_pinning_dropped(IpProtocol::Udp, packet.source_addr(), packet.destination_addr());
                     },
+                    Ipv4PacketProtocol::Icmp(_) | Ipv4PacketProtocol::Unknown { .. } => (),
                 }
-            },
+            }
+        } else {
+            self.send_external(packet.ip_packet_box());
         }
+    }
 
-        let mut defunct_indexes = Vec::new();
-        for (index, internal_iface) in &mut self.internal_ifaces {
-            match Pin::new(internal_iface).poll_flush(cx) {
-                Poll::Ready(Ok(())) => (),
-                Poll::Ready(Err(_)) => {
-                    defunct_indexes.push(*index);
-                },
-                Poll::Pending => {
-                    any_pending = true;
-                },
+    fn expire_mappings(&mut self) {
+        let now = Instant::now();
+        for entry in self.tcpv4_port_map.remove_expired(now) {
+            debug!("{}: tcp mapping for {} expired", self.external_ipv4, entry.external_addr);
+            self.forget_mapping(IpProtocol::Tcp, &entry);
+        }
+        for external_addr in self.remove_expired_tcp_states(now) {
+            if let Some(entry) = self.tcpv4_port_map.remove_mapping(external_addr) {
+                debug!("{}: tcp mapping for {} closed", self.external_ipv4, external_addr);
+                self.forget_mapping(IpProtocol::Tcp, &entry);
             }
         }
-        for index in defunct_indexes {
-            self.internal_ifaces.remove(&index).unwrap();
+        for entry in self.udpv4_port_map.remove_expired(now) {
+            debug!("{}: udp mapping for {} expired", self.external_ipv4, entry.external_addr);
+            self.forget_mapping(IpProtocol::Udp, &entry);
         }
-        if any_pending {
-            Poll::Pending
-        } else {
-            Poll::Ready(())
+        for entry in self.icmpv4_port_map.remove_expired(now) {
+            debug!("{}: icmp echo mapping for {} expired", self.external_ipv4, entry.external_addr);
+            self.forget_mapping(IpProtocol::Icmp, &entry);
         }
+        self.expire_upnp_connections(now);
     }
 
-    fn poll_ready_outgoing(&mut self, cx: &mut task::Context) -> Poll<()> {
-        match self.poll_flush_outgoing(cx) {
-            Poll::Ready(()) => return Poll::Ready(()),
-            Poll::Pending => (),
+    /// The earliest time at which something expired by [`expire_mappings`](Self::expire_mappings)
+    /// expires.
+    fn next_expiry(&self) -> Option<Instant> {
+        let tcp_state_expiry_opt = {
+            self.tcpv4_mapping_states
+            .values()
+            .flat_map(HashMap::values)
+            .filter_map(|state| state.expiry(self.tcp_mapping_timeouts_opt))
+            .min()
+        };
+        [
+            self.tcpv4_port_map.next_expiry(),
+            self.udpv4_port_map.next_expiry(),
+            self.icmpv4_port_map.next_expiry(),
+            tcp_state_expiry_opt,
+            self.next_upnp_connection_expiry(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Sets the expiry timer for the next expiry, returning `Ready` once it's due.
+    fn poll_expiry(&mut self, cx: &mut task::Context) -> Poll<()> {
+        let expiry = match self.next_expiry() {
+            Some(expiry) => expiry,
+            None => {
+                self.expiry_sleep_opt = None;
+                return Poll::Pending;
+            },
+        };
+        match &mut self.expiry_sleep_opt {
+            Some(sleep) => sleep.as_mut().reset(expiry.into()),
+            None => {
+                let sleep = tokio::time::sleep_until(expiry.into());
+                self.expiry_sleep_opt = Some(Box::pin(sleep));
+            },
         }
+        let sleep = self.expiry_sleep_opt.as_mut().unwrap();
+        sleep.as_mut().poll(cx)
+    }
 
-        let mut any_pending = false;
+    /// Forgets the filtering and connection state of a mapping which has expired or been evicted,
+    /// and reports that it's gone.
+    fn forget_mapping(&mut self, protocol: IpProtocol, entry: &PortMapEntry) {
+        if let Some((_port_map, restrictions)) = self.port_map_and_restrictions(protocol) {
+            restrictions.forget(entry.external_addr);
+        }
+        match protocol {
+            IpProtocol::Tcp => {
+                self.forget_tcp_state(entry.external_addr);
+                self.emit_mapping_expired(protocol, entry);
+            },
+            IpProtocol::Udp => self.emit_mapping_expired(protocol, entry),
+            IpProtocol::Icmp | IpProtocol::Other(_) => (),
+        }
+    }
 
-        match &mut self.external_iface_opt {
-            None => (),
-            Some(external_iface) => {
-                match Pin::new(external_iface).poll_ready(cx) {
-                    Poll::Ready(Ok(())) => (),
-                    Poll::Ready(Err(_)) => {
-                        self.external_iface_opt = None;
-                    },
-                    Poll::Pending => {
-                        any_pending = true;
-                    },
+    /// Forgets mappings which were evicted to make room for a new mapping or forwarded port.
+    fn forget_evicted_mappings(&mut self, protocol: IpProtocol, evicted: impl IntoIterator<Item = PortMapEntry>) {
+        for entry in evicted {
+            debug!(
+                "{}: evicted mapping for {} from {} to make room for a new mapping",
+                self.external_ipv4, entry.external_addr, entry.internal_addr,
+            );
+            self.forget_mapping(protocol, &entry);
+        }
+    }
+
+    /// Forgets what the NAT has seen of the TCP traffic through `external_addr`, once its mapping
+    /// is gone.
+    fn forget_tcp_state(&mut self, external_addr: SocketAddrV4) {
+        self.tcpv4_mapping_states.remove(&external_addr);
+    }
+
+    /// Records a TCP packet between `external_addr` and `remote_addr` in the state of its
+    /// connection, returning whether the packet should be forwarded. Without connection tracking
+    /// every packet is forwarded. With it, a connection can only be opened with a SYN, and only if
+    /// `may_open` (whether the NAT's filtering lets `remote_addr` reach `external_addr`), and
+    /// packets which don't fit the connection's state are rejected.
+    fn track_tcp(
+        &mut self,
+        external_addr: SocketAddrV4,
+        remote_addr: SocketAddrV4,
+        outbound: bool,
+        flags: TcpPacketFlags,
+        may_open: bool,
+    ) -> bool {
+        let states = self.tcpv4_mapping_states.entry(external_addr).or_default();
+        if self.tcp_time_wait_opt.is_none() {
+            states.entry(remote_addr).or_insert_with(TcpMappingState::new).update(outbound, flags, None);
+            return true;
+        }
+        let state = match states.entry(remote_addr) {
+            hash_map::Entry::Occupied(entry) => {
+                let state = entry.into_mut();
+                if state.time_wait_until_opt.is_some() && is_initial_syn(flags) && may_open {
+                    *state = TcpMappingState::new();
+                }
+                state
+            },
+            hash_map::Entry::Vacant(entry) => {
+                if !is_initial_syn(flags) || !may_open {
+                    return false;
                 }
+                entry.insert(TcpMappingState::new())
             },
+        };
+        if !state.fits(outbound, flags) {
+            return false;
         }
+        state.update(outbound, flags, self.tcp_time_wait_opt);
+        true
+    }
 
-        let mut defunct_indexes = Vec::new();
-        for (index, internal_iface) in &mut self.internal_ifaces {
-            match Pin::new(internal_iface).poll_ready(cx) {
-                Poll::Ready(Ok(())) => (),
-                Poll::Ready(Err(_)) => {
-                    defunct_indexes.push(*index);
-                },
-                Poll::Pending => {
-                    any_pending = true;
-                },
+    /// Forgets TCP connections which have finished their TIME_WAIT or been idle too long by
+    /// `now`, returning the external addresses whose last connection has just finished its
+    /// TIME_WAIT.
+    fn remove_expired_tcp_states(&mut self, now: Instant) -> Vec<SocketAddrV4> {
+        let timeouts_opt = self.tcp_mapping_timeouts_opt;
+        let mut closed_addrs = Vec::new();
+        self.tcpv4_mapping_states.retain(|external_addr, states| {
+            let mut finished_time_wait = false;
+            states.retain(|_remote_addr, state| {
+                if state.expiry(timeouts_opt).is_none_or(|expiry| expiry > now) {
+                    return true;
+                }
+                finished_time_wait |= state.time_wait_until_opt.is_some_and(|time_wait_until| time_wait_until <= now);
+                false
+            });
+            if finished_time_wait && states.is_empty() {
+                closed_addrs.push(*external_addr);
             }
+            !states.is_empty()
+        });
+        closed_addrs
+    }
+
+    fn refresh_tcp_mapping(&mut self, external_addr: SocketAddrV4, outbound: bool) {
+        self.tcpv4_port_map.touch(external_addr);
+        if !outbound && !self.inbound_refreshes_mappings {
+            return;
         }
-        for index in defunct_indexes {
-            self.internal_ifaces.remove(&index).unwrap();
+        if let Some(timeouts) = self.tcp_mapping_timeouts_opt {
+            let is_established = {
+                self.tcpv4_mapping_states
+                .get(&external_addr)
+                .is_some_and(|states| states.values().any(TcpMappingState::is_established))
+            };
+            let timeout = if is_established {
+                timeouts.established
+            } else {
+                timeouts.transitory
+            };
+            self.tcpv4_port_map.refresh(external_addr, Instant::now() + timeout);
         }
-        if any_pending {
-            Poll::Pending
-        } else {
-            Poll::Ready(())
+        self.emit_mapping_refreshed(IpProtocol::Tcp, external_addr);
+    }
+
+    fn refresh_udp_mapping(&mut self, external_addr: SocketAddrV4, outbound: bool) {
+        self.udpv4_port_map.touch(external_addr);
+        if !outbound && !self.inbound_refreshes_mappings {
+            return;
         }
+        if let Some(timeout) = self.udp_mapping_timeout_opt {
+            self.udpv4_port_map.refresh(external_addr, Instant::now() + timeout);
+        }
+        self.emit_mapping_refreshed(IpProtocol::Udp, external_addr);
     }
 
-    fn poll_next_incoming_external(&mut self, cx: &mut task::Context) -> Poll<Box<IpPacket>> {
-        match &mut self.external_iface_opt {
-            None => Poll::Pending,
-            Some(external_iface) => {
-                match Pin::new(external_iface).poll_next(cx) {
-                    Poll::Ready(Some(Ok(packet))) => Poll::Ready(packet),
-                    Poll::Ready(Some(Err(_))) | Poll::Ready(None) => {
-                        self.external_iface_opt = None;
-                        Poll::Pending
-                    },
-                    Poll::Pending => Poll::Pending,
-                }
+    /// How long a new mapping for `protocol` lasts without being refreshed, if mappings for
+    /// `protocol` expire.
+    fn idle_timeout(&self, protocol: IpProtocol) -> Option<Duration> {
+        match protocol {
+            IpProtocol::Tcp => self.tcp_mapping_timeouts_opt.map(|timeouts| timeouts.transitory),
+            IpProtocol::Udp => self.udp_mapping_timeout_opt,
+            IpProtocol::Icmp => Some(self.icmp_mapping_timeout),
+            IpProtocol::Other(_) => None,
+        }
+    }
+
+    fn refresh_icmp_mapping(&mut self, external_addr: SocketAddrV4, outbound: bool) {
+        self.icmpv4_port_map.touch(external_addr);
+        if !outbound && !self.inbound_refreshes_mappings {
+            return;
+        }
+        self.icmpv4_port_map.refresh(external_addr, Instant::now() + self.icmp_mapping_timeout);
+    }
+
+    fn port_map_and_restrictions(&mut self, protocol: IpProtocol) -> Option<(&mut PortMap, &mut Restrictions)> {
+        match protocol {
+            IpProtocol::Tcp => Some((&mut self.tcpv4_port_map, &mut self.tcpv4_restrictions)),
+            IpProtocol::Udp => Some((&mut self.udpv4_port_map, &mut self.udpv4_restrictions)),
+            IpProtocol::Icmp => Some((&mut self.icmpv4_port_map, &mut self.icmpv4_restrictions)),
+            IpProtocol::Other(_) => None,
+        }
+    }
+
+    /// The external address and port to use for packets sent from `internal_addr` to
+    /// `remote_addr`, creating a mapping on one of the NAT's external addresses if there isn't
+    /// one. Returns `None` if there's no mapping and every port is forwarded.
+    fn outgoing_port(
+        &mut self,
+        protocol: IpProtocol,
+        internal_addr: SocketAddrV4,
+        remote_addr: SocketAddrV4,
+    ) -> Option<SocketAddrV4> {
+        let (port_map, _restrictions) = self.port_map_and_restrictions(protocol).unwrap();
+        if let Some(external_addr) = port_map.mapped_port(internal_addr, remote_addr) {
+            return Some(external_addr);
+        }
+        let external_ipv4 = self.external_ipv4_pool.select(*internal_addr.ip());
+        let (port_map, _restrictions) = self.port_map_and_restrictions(protocol).unwrap();
+        let (external_addr, evicted_opt) = match port_map.outgoing_port(internal_addr, remote_addr, external_ipv4) {
+            Some(port_and_evicted) => port_and_evicted,
+            None => {
+                debug!(
+                    "{}: no free port on {} for a mapping from {} since all ports are forwarded",
+                    self.external_ipv4, external_ipv4, internal_addr,
+                );
+                return None;
             },
+        };
+        self.forget_evicted_mappings(protocol, evicted_opt);
+        if matches!(protocol, IpProtocol::Tcp | IpProtocol::Udp) {
+            self.emit_mapping_created(protocol, internal_addr, external_addr);
         }
+        Some(external_addr)
     }
 
-    fn poll_next_incoming_internal(&mut self, cx: &mut task::Context) -> Poll<(usize, Box<IpPacket>)> {
-        let mut defunct_indexes = Vec::new();
-        let mut index_packet_opt = None;
-        for (index, internal_iface) in &mut self.internal_ifaces {
-            match Pin::new(internal_iface).poll_next(cx) {
-                Poll::Ready(Some(Ok(packet))) => {
-                    index_packet_opt = Some((*index, packet));
-                    break;
-                },
-                Poll::Ready(Some(Err(_))) | Poll::Ready(None) => {
-                    defunct_indexes.push(*index);
-                },
-                Poll::Pending => (),
-            }
+    /// The internal address that packets from `remote_addr` to `external_addr` should be sent
+    /// to, either through a forwarded port or through a mapping which allows them.
+    fn mapped_inbound_addr(
+        &mut self,
+        protocol: IpProtocol,
+        external_addr: SocketAddrV4,
+        remote_addr: SocketAddrV4,
+    ) -> Option<SocketAddrV4> {
+        if !self.inbound_allowed(protocol, external_addr, remote_addr) {
+            return None;
         }
-        for index in defunct_indexes {
-            self.internal_ifaces.remove(&index).unwrap();
+        let (port_map, _restrictions) = self.port_map_and_restrictions(protocol)?;
+        port_map.internal_addr(external_addr)
+    }
+
+    /// Whether the NAT's filtering lets `remote_addr` send packets to `external_addr`.
+    fn inbound_allowed(&mut self, protocol: IpProtocol, external_addr: SocketAddrV4, remote_addr: SocketAddrV4) -> bool {
+        let Some((port_map, restrictions)) = self.port_map_and_restrictions(protocol) else {
+            return false;
+        };
+        port_map.accepts_any_remote(external_addr) || restrictions.incoming_allowed(external_addr, remote_addr)
+    }
+
+    /// Maps `external_addr` to the DMZ host, if there is one and the port isn't already in use,
+    /// and returns the DMZ host's address.
+    fn dmz_inbound_addr(
+        &mut self,
+        protocol: IpProtocol,
+        external_addr: SocketAddrV4,
+        remote_addr: SocketAddrV4,
+    ) -> Option<SocketAddrV4> {
+        let dmz_addr = SocketAddrV4::new(self.dmz_host_opt?, external_addr.port());
+        let timeout_opt = self.idle_timeout(protocol);
+        let (port_map, _restrictions) = self.port_map_and_restrictions(protocol)?;
+        if !port_map.insert_dmz(external_addr, dmz_addr, remote_addr) {
+            return None;
         }
-        match index_packet_opt {
-            Some((index, packet)) => Poll::Ready((index, packet)),
-            None => Poll::Pending,
+        if let Some(timeout) = timeout_opt {
+            port_map.refresh(external_addr, Instant::now() + timeout);
         }
+        self.emit_mapping_created(protocol, dmz_addr, external_addr);
+        Some(dmz_addr)
     }
 
     fn dispatch_incoming_external(&mut self, packet: Box<IpPacket>) {
         if log_enabled!(Level::Debug) {
             debug!("{}: received from external iface: {:?}", self.external_ipv4, packet);
         }
+        self.expire_mappings();
 
         match packet.version_box() {
             IpPacketVersion::V6(_) => (),
             IpPacketVersion::V4(packet) => {
-                if packet.destination_addr() != self.external_ipv4 {
+                if !self.external_ipv4_pool.contains(packet.destination_addr()) {
                     debug!(
                         "{}: dropping external packet addressed to different ip {}",
                         self.external_ipv4, packet.destination_addr(),
@@ -283,12 +994,21 @@ impl NatTask {
                 }
                 match packet.protocol_box() {
                     Ipv4PacketProtocol::Tcp(mut packet) => {
-                        let port = packet.destination_port();
-                        let mapped_addr_opt = if self.tcpv4_restrictions.incoming_allowed(port, packet.source_addr()) {
-                            self.tcpv4_port_map.incoming_addr(port)
-                        } else {
-                            None
+                        let protocol = IpProtocol::Tcp;
+                        let flags = packet.flags();
+                        let external_addr = packet.destination_addr();
+                        let remote_addr = packet.source_addr();
+                        let mapped_addr_opt = match self.mapped_inbound_addr(protocol, external_addr, remote_addr) {
+                            Some(mapped_addr) => Some(mapped_addr),
+                            None if self.tcp_time_wait_opt.is_none() || (flags.syn && !flags.ack) => {
+                                self.dmz_inbound_addr(protocol, external_addr, remote_addr)
+                            },
+                            None => None,
                         };
+                        let mapped_addr_opt = mapped_addr_opt.filter(|_mapped_addr| {
+                            let may_open = self.inbound_allowed(protocol, external_addr, remote_addr);
+                            self.track_tcp(external_addr, remote_addr, false, flags, may_open)
+                        });
                         let mapped_addr = match mapped_addr_opt {
                             Some(mapped_addr) => mapped_addr,
                             None => {
@@ -302,55 +1022,26 @@ impl NatTask {
                                     rst_packet.set_source_addr(packet.destination_addr());
                                     rst_packet.set_destination_addr(packet.source_addr());
                                     rst_packet.set_ack_number(packet.seq_number().wrapping_add(1));
-                                    match &mut self.external_iface_opt {
-                                        None => (),
-                                        Some(external_iface) => {
-                                            match Pin::new(external_iface).start_send(rst_packet.ip_packet_box()) {
-                                                Ok(()) => (),
-                                                Err(_) => {
-                                                    self.external_iface_opt = None;
-                                                },
-                                            }
-                                        },
-                                    }
+                                    self.send_external(rst_packet.ip_packet_box());
                                 }
                                 debug!(
                                     "{}: dropping external packet addressed to unmapped or disallowed port {}",
                                     self.external_ipv4, packet.destination_addr(),
                                 );
+                                self.emit_inbound_dropped(protocol, packet.source_addr(), packet.destination_addr());
                                 return;
                             },
                         };
-                        let iface_index = match self.internal_addr_indexes.get(&IpAddr::V4(*mapped_addr.ip())) {
-                            Some(iface_index) => iface_index,
-                            None => return,
-                        };
-                        let internal_iface = match self.internal_ifaces.get_mut(iface_index) {
-                            Some(internal_iface) => internal_iface,
-                            None => return,
-                        };
+                        self.refresh_tcp_mapping(external_addr, false);
                         packet.set_destination_addr(mapped_addr);
-                        if log_enabled!(Level::Debug) {
-                            debug!(
-                                "{}: forwarding translated packet on internal iface #{} {:?}",
-                                self.external_ipv4,
-                                iface_index,
-                                packet,
-                            );
-                        }
-                        match Pin::new(internal_iface).start_send(packet.ip_packet_box()) {
-                            Ok(()) => (),
-                            Err(_) => {
-                                self.internal_ifaces.remove(iface_index);
-                            },
-                        }
+                        self.send_internal(*mapped_addr.ip(), packet.ip_packet_box());
                     },
                     Ipv4PacketProtocol::Udp(mut packet) => {
-                        let port = packet.destination_port();
-                        let mapped_addr_opt = if self.udpv4_restrictions.incoming_allowed(port, packet.source_addr()) {
-                            self.udpv4_port_map.incoming_addr(port)
-                        } else {
-                            None
+                        let protocol = IpProtocol::Udp;
+                        let external_addr = packet.destination_addr();
+                        let mapped_addr_opt = match self.mapped_inbound_addr(protocol, external_addr, packet.source_addr()) {
+                            Some(mapped_addr) => Some(mapped_addr),
+                            None => self.dmz_inbound_addr(protocol, external_addr, packet.source_addr()),
                         };
                         let mapped_addr = match mapped_addr_opt {
                             Some(mapped_addr) => mapped_addr,
@@ -359,40 +1050,69 @@ impl NatTask {
                                     "{}: dropping external packet addressed to unmapped or disallowed port {}",
                                     self.external_ipv4, packet.destination_addr(),
                                 );
+                                self.emit_inbound_dropped(protocol, packet.source_addr(), packet.destination_addr());
                                 return;
                             },
                         };
-                        let iface_index = match self.internal_addr_indexes.get(&IpAddr::V4(*mapped_addr.ip())) {
-                            Some(iface_index) => iface_index,
-                            None => return,
-                        };
-                        let internal_iface = match self.internal_ifaces.get_mut(iface_index) {
-                            Some(internal_iface) => internal_iface,
-                            None => return,
-                        };
+                        self.refresh_udp_mapping(external_addr, false);
                         packet.set_destination_addr(mapped_addr);
-                        if log_enabled!(Level::Debug) {
-                            debug!(
-                                "{}: forwarding translated packet on internal iface #{} {:?}",
-                                self.external_ipv4,
-                                iface_index,
-                                packet,
-                            );
-                        }
-                        match Pin::new(internal_iface).start_send(packet.ip_packet_box()) {
-                            Ok(()) => (),
-                            Err(_) => {
-                                self.internal_ifaces.remove(iface_index);
-                            },
-                        }
+                        self.send_internal(*mapped_addr.ip(), packet.ip_packet_box());
+                    },
+                    Ipv4PacketProtocol::Icmp(packet) => {
+                        self.dispatch_incoming_external_icmp(packet);
                     },
-                    Ipv4PacketProtocol::Icmp(_) => (),
                     Ipv4PacketProtocol::Unknown { .. } => (),
                 }
             },
         }
     }
 
+    /// Translates echo replies using the echo identifier as a port, and translates errors by
+    /// rewriting the packet quoted inside them back to the internal address (RFC 5508).
+    fn dispatch_incoming_external_icmp(&mut self, mut packet: Box<Icmpv4Packet>) {
+        if let Some(identifier) = packet.echo_identifier() {
+            if !packet.is_echo_reply() {
+                return;
+            }
+            let external_addr = SocketAddrV4::new(packet.destination_addr(), identifier);
+            let remote_addr = SocketAddrV4::new(packet.source_addr(), 0);
+            let mapped_addr = match self.mapped_inbound_addr(IpProtocol::Icmp, external_addr, remote_addr) {
+                Some(mapped_addr) => mapped_addr,
+                None => {
+                    debug!(
+                        "{}: dropping external echo reply with unmapped or disallowed identifier {}",
+                        self.external_ipv4, identifier,
+                    );
+                    return;
+                },
+            };
+            self.refresh_icmp_mapping(external_addr, false);
+            packet.set_destination_addr(*mapped_addr.ip());
+            packet.set_echo_identifier(mapped_addr.port());
+            self.send_internal(*mapped_addr.ip(), packet.ip_packet_box());
+            return;
+        }
+
+        let (protocol, quoted_source_addr, quoted_destination_addr) = match packet.quoted_addrs() {
+            Some(quoted_addrs) => quoted_addrs,
+            None => return,
+        };
+        let external_port = quoted_source_addr.port();
+        let mapped_addr = match self.mapped_inbound_addr(protocol, quoted_source_addr, quoted_destination_addr) {
+            Some(mapped_addr) => mapped_addr,
+            None => {
+                debug!(
+                    "{}: dropping external icmp error about unmapped or disallowed port {}",
+                    self.external_ipv4, external_port,
+                );
+                return;
+            },
+        };
+        packet.set_quoted_source_addr(mapped_addr);
+        packet.set_destination_addr(*mapped_addr.ip());
+        self.send_internal(*mapped_addr.ip(), packet.ip_packet_box());
+    }
+
     fn dispatch_incoming_internal(&mut self, iface_index: usize, packet: Box<IpPacket>) {
         if log_enabled!(Level::Debug) {
             debug!(
@@ -402,6 +1122,7 @@ impl NatTask {
                 packet,
             );
         }
+        self.expire_mappings();
 
         match packet.version_box() {
             IpPacketVersion::V6(packet) => {
@@ -417,113 +1138,137 @@ impl NatTask {
                 }
                 self.internal_addr_indexes.insert(IpAddr::V4(packet.source_addr()), iface_index);
                 let destination_ip = packet.destination_addr();
+                if destination_ip == self.internal_ipv4 || (self.upnp_igd && destination_ip == upnp::SSDP_MULTICAST_IPV4) {
+                    self.dispatch_incoming_gateway(packet);
+                    return;
+                }
                 if self.internal_ipv4_network.contains(destination_ip) {
-                    let iface_index = match self.internal_addr_indexes.get(&IpAddr::V4(destination_ip)) {
-                        Some(iface_index) => iface_index,
-                        None => {
-                            debug!(
-                                "{}: dropping internal packet addressed to unknown internal device {}",
-                                self.external_ipv4, packet.destination_addr(),
-                            );
-                            return;
-                        },
-                    };
-                    let internal_iface = match self.internal_ifaces.get_mut(iface_index) {
-                        Some(internal_iface) => internal_iface,
-                        None => return,
-                    };
-                    match Pin::new(internal_iface).start_send(packet.ip_packet_box()) {
-                        Ok(()) => (),
-                        Err(_) => {
-                            self.internal_ifaces.remove(iface_index);
-                        },
+                    if !self.internal_addr_indexes.contains_key(&IpAddr::V4(destination_ip)) {
+                        debug!(
+                            "{}: dropping internal packet addressed to unknown internal device {}",
+                            self.external_ipv4, packet.destination_addr(),
+                        );
+                        return;
                     }
-                } else {
-                    match packet.protocol_box() {
-                        Ipv4PacketProtocol::Tcp(mut packet) => {
-                            let internal_addr = packet.source_addr();
-                            let port = self.tcpv4_port_map.outgoing_port(internal_addr);
-                            self.tcpv4_restrictions.sending(port, packet.destination_addr());
-                            packet.set_source_addr(SocketAddrV4::new(self.external_ipv4, port));
-                            if log_enabled!(Level::Debug) {
-                                debug!(
-                                    "{}: translated outgoing packet {:?}",
-                                    self.external_ipv4,
-                                    packet,
-                                );
-                            }
-                            if *packet.destination_addr().ip() == self.external_ipv4 {
-                                if self.hair_pinning {
-                                    self.dispatch_incoming_external(packet.ip_packet_box());
-                                } else {
-                                    debug!(
-                                        "{}: dropped internal packet from {} addressed to own external address {} since hair-pinning is disabled",
-                                        self.external_ipv4, packet.source_addr(), packet.destination_addr(),
-                                    );
-                                }
-                            } else {
-                                match &mut self.external_iface_opt {
-                                    None => (),
-                                    Some(external_iface) => {
-                                        match Pin::new(external_iface).start_send(packet.ip_packet_box()) {
-                                            Ok(()) => (),
-                                            Err(_) => {
-                                                self.external_iface_opt = None;
-                                            },
-                                        }
-                                    },
-                                }
-                            }
-                        },
-                        Ipv4PacketProtocol::Udp(mut packet) => {
-                            let internal_addr = packet.source_addr();
-                            let port = self.udpv4_port_map.outgoing_port(internal_addr);
-                            self.udpv4_restrictions.sending(port, packet.destination_addr());
-                            packet.set_source_addr(SocketAddrV4::new(self.external_ipv4, port));
-                            if log_enabled!(Level::Debug) {
+                    self.send_internal(destination_ip, packet.ip_packet_box());
+                    return;
+                }
+                match packet.protocol_box() {
+                    Ipv4PacketProtocol::Tcp(mut packet) => {
+                        let internal_addr = packet.source_addr();
+                        let remote_addr = packet.destination_addr();
+                        let flags = packet.flags();
+                        let external_addr_opt = match self.tcpv4_port_map.mapped_port(internal_addr, remote_addr) {
+                            Some(external_addr) => Some(external_addr),
+                            None if self.tcp_time_wait_opt.is_none() || (flags.syn && !flags.ack) => {
+                                self.outgoing_port(IpProtocol::Tcp, internal_addr, remote_addr)
+                            },
+                            None => {
                                 debug!(
-                                    "{}: translated outgoing packet {:?}",
-                                    self.external_ipv4,
-                                    packet,
+                                    "{}: dropping internal tcp packet from {} which doesn't start a connection",
+                                    self.external_ipv4, internal_addr,
                                 );
-                            }
-                            if *packet.destination_addr().ip() == self.external_ipv4 {
-                                if self.hair_pinning {
-                                    self.dispatch_incoming_external(packet.ip_packet_box());
-                                } else {
-                                    debug!(
-                                        "{}: dropped internal packet from {} addressed to own external address {} since hair-pinning is disabled",
-                                        self.external_ipv4, packet.source_addr(), packet.destination_addr(),
-                                    );
-                                }
-                            } else {
-                                match &mut self.external_iface_opt {
-                                    None => (),
-                                    Some(external_iface) => {
-                                        match Pin::new(external_iface).start_send(packet.ip_packet_box()) {
-                                            Ok(()) => (),
-                                            Err(_) => {
-                                                self.external_iface_opt = None;
-                                            },
-                                        }
-                                    },
-                                }
-                            }
-                        },
-                        Ipv4PacketProtocol::Icmp(_) => (),
-                        Ipv4PacketProtocol::Unknown { .. } => (),
-                    }
+                                return;
+                            },
+                        };
+                        let Some(external_addr) = external_addr_opt else { return };
+                        if !self.track_tcp(external_addr, remote_addr, true, flags, true) {
+                            debug!(
+                                "{}: dropping internal tcp packet from {} which doesn't fit connection state",
+                                self.external_ipv4, internal_addr,
+                            );
+                            return;
+                        }
+                        self.tcpv4_restrictions.sending(external_addr, remote_addr);
+                        self.refresh_tcp_mapping(external_addr, true);
+                        packet.set_source_addr(external_addr);
+                        self.send_translated_outgoing(packet.ipv4_packet_box());
+                    },
+                    Ipv4PacketProtocol::Udp(mut packet) => {
+                        let internal_addr = packet.source_addr();
+                        let Some(external_addr) = self.outgoing_port(IpProtocol::Udp, internal_addr, packet.destination_addr()) else {
+                            return;
+                        };
+                        self.udpv4_restrictions.sending(external_addr, packet.destination_addr());
+                        self.refresh_udp_mapping(external_addr, true);
+                        packet.set_source_addr(external_addr);
+                        self.send_translated_outgoing(packet.ipv4_packet_box());
+                    },
+                    Ipv4PacketProtocol::Icmp(packet) => {
+                        self.dispatch_incoming_internal_icmp(packet);
+                    },
+                    Ipv4PacketProtocol::Unknown { .. } => (),
                 }
             },
         }
     }
 
+    /// Handles packets addressed to the NAT's own internal address, or to the SSDP multicast
+    /// address if the NAT is a UPnP device.
+    fn dispatch_incoming_gateway(&mut self, packet: Box<Ipv4Packet>) {
+        match packet.protocol_box() {
+            Ipv4PacketProtocol::Udp(packet) if self.pcp_server && packet.destination_port() == pcp::SERVER_PORT => {
+                self.dispatch_pcp_request(&packet);
+            },
+            Ipv4PacketProtocol::Udp(packet) if self.upnp_igd && packet.destination_port() == upnp::SSDP_PORT => {
+                self.dispatch_ssdp_request(&packet);
+            },
+            Ipv4PacketProtocol::Tcp(packet) if self.upnp_igd && packet.destination_port() == upnp::HTTP_PORT => {
+                self.dispatch_http_segment(&packet);
+            },
+            _ => {
+                debug!(
+                    "{}: dropping internal packet addressed to the nat's internal address {}",
+                    self.external_ipv4, self.internal_ipv4,
+                );
+            },
+        }
+    }
+
+    /// Translates echo requests using the echo identifier as a port, and translates errors about
+    /// packets which came in through the NAT by rewriting the quoted packet to use the NAT's
+    /// external address.
+    fn dispatch_incoming_internal_icmp(&mut self, mut packet: Box<Icmpv4Packet>) {
+        if let Some(identifier) = packet.echo_identifier() {
+            if !packet.is_echo_request() {
+                return;
+            }
+            let internal_addr = SocketAddrV4::new(packet.source_addr(), identifier);
+            let remote_addr = SocketAddrV4::new(packet.destination_addr(), 0);
+            let Some(external_addr) = self.outgoing_port(IpProtocol::Icmp, internal_addr, remote_addr) else {
+                return;
+            };
+            self.icmpv4_restrictions.sending(external_addr, remote_addr);
+            self.refresh_icmp_mapping(external_addr, true);
+            packet.set_source_addr(*external_addr.ip());
+            packet.set_echo_identifier(external_addr.port());
+            self.send_translated_outgoing(packet.ipv4_packet_box());
+            return;
+        }
+
+        let (protocol, quoted_source_addr, quoted_destination_addr) = match packet.quoted_addrs() {
+            Some(quoted_addrs) => quoted_addrs,
+            None => return,
+        };
+        let port_map = match protocol {
+            IpProtocol::Tcp => &self.tcpv4_port_map,
+            IpProtocol::Udp => &self.udpv4_port_map,
+            IpProtocol::Icmp | IpProtocol::Other(_) => return,
+        };
+        let external_addr = match port_map.mapped_port(quoted_destination_addr, quoted_source_addr) {
+            Some(external_addr) => external_addr,
+            None => return,
+        };
+        packet.set_quoted_destination_addr(external_addr);
+        packet.set_source_addr(*external_addr.ip());
+        self.send_translated_outgoing(packet.ipv4_packet_box());
+    }
+
     fn poll_inner(&mut self, cx: &mut task::Context) -> Poll<()> {
         loop {
             match Pin::new(&mut self.iface_receiver).poll_next(cx) {
                 Poll::Ready(Some(iface)) => {
-                    self.internal_ifaces.insert(self.next_internal_iface_index, iface);
-                    self.next_internal_iface_index += 1;
+                    self.internal_ifaces.insert(iface);
                 },
                 Poll::Ready(None) => return Poll::Ready(()),
                 Poll::Pending => break,
@@ -531,12 +1276,21 @@ impl NatTask {
         }
 
         loop {
+            while let Poll::Ready(Some(request)) = Pin::new(&mut self.request_receiver).poll_next(cx) {
+                self.handle_request(request);
+            }
+
             match self.poll_ready_outgoing(cx) {
                 Poll::Ready(()) => (),
                 Poll::Pending => return Poll::Pending,
             }
 
-            match self.poll_next_incoming_external(cx) {
+            if let Some((internal_ip, packet)) = self.gateway_packets.pop_front() {
+                self.send_internal(internal_ip, packet);
+                continue;
+            }
+
+            match self.external_iface.poll_next(cx) {
                 Poll::Ready(packet) => {
                     self.dispatch_incoming_external(packet);
                     continue;
@@ -544,7 +1298,7 @@ impl NatTask {
                 Poll::Pending => (),
             }
 
-            match self.poll_next_incoming_internal(cx) {
+            match self.internal_ifaces.poll_next(cx) {
                 Poll::Ready((index, packet)) => {
                     self.dispatch_incoming_internal(index, packet);
                     continue;
@@ -552,6 +1306,14 @@ impl NatTask {
                 Poll::Pending => (),
             }
 
+            match self.poll_expiry(cx) {
+                Poll::Ready(()) => {
+                    self.expire_mappings();
+                    continue;
+                },
+                Poll::Pending => (),
+            }
+
             break Poll::Pending;
         }
     }
@@ -0,0 +1,215 @@
+//! Lets users look inside a running NAT, to help with debugging code which has to traverse it.
+
+use {
+    super::{NatTask, port_map::PortMapEntry},
+    crate::{
+        priv_prelude::*,
+        packet::IpProtocol,
+    },
+};
+
+/// A TCP or UDP mapping on a [`Nat`](crate::device::Nat), as returned by
+/// [`Nat::mappings`](crate::device::Nat::mappings).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NatMapping {
+    /// The protocol of the mapping, either TCP or UDP.
+    pub protocol: IpProtocol,
+    /// The internal address which packets arriving on the mapping are forwarded to.
+    pub internal_addr: SocketAddrV4,
+    /// The NAT's external address and port for the mapping.
+    pub external_addr: SocketAddrV4,
+    /// Whether this is a forwarded port, either configured on the NAT or requested by a host
+    /// through PCP or UPnP, rather than a mapping created by outbound traffic.
+    pub forwarded: bool,
+    /// The remote addresses which are allowed to send packets through the mapping.
+    pub permitted_remotes: PermittedRemotes,
+    /// When the mapping was created or last had a packet pass through it.
+    pub last_activity: Instant,
+}
+
+/// The remote addresses which are allowed to send packets through a
+/// [`NatMapping`](crate::device::NatMapping).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PermittedRemotes {
+    /// Any remote address.
+    Any,
+    /// Any port on these IP addresses, which the mapping has sent packets to.
+    Ips(Vec<Ipv4Addr>),
+    /// These socket addresses, which the mapping has sent packets to.
+    Addrs(Vec<SocketAddrV4>),
+}
+
+/// Something which happened on a [`Nat`](crate::device::Nat), as reported by
+/// [`Nat::events`](crate::device::Nat::events).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NatEvent {
+    /// A TCP or UDP mapping was created, either by an outbound packet, for the DMZ host or on
+    /// request through PCP or UPnP.
+    MappingCreated {
+        protocol: IpProtocol,
+        internal_addr: SocketAddrV4,
+        external_addr: SocketAddrV4,
+    },
+    /// A packet passed through a mapping and reset its idle timeout, or a host renewed a mapping
+    /// it requested.
+    MappingRefreshed {
+        protocol: IpProtocol,
+        internal_addr: SocketAddrV4,
+        external_addr: SocketAddrV4,
+    },
+    /// A mapping was removed because it was idle for too long, its lifetime ran out, it was
+    /// evicted to make room for another mapping when every port was in use or, with TCP
+    /// connection tracking, all the connections through it closed.
+    MappingExpired {
+        protocol: IpProtocol,
+        internal_addr: SocketAddrV4,
+        external_addr: SocketAddrV4,
+    },
+    /// A mapping was deleted through PCP, NAT-PMP or UPnP by the host it forwards to.
+    MappingRemoved {
+        protocol: IpProtocol,
+        internal_addr: SocketAddrV4,
+        external_addr: SocketAddrV4,
+    },
+    /// An inbound TCP or UDP packet was dropped.
+    InboundDropped {
+        protocol: IpProtocol,
+        remote_addr: SocketAddrV4,
+        external_addr: SocketAddrV4,
+        reason: InboundDropReason,
+    },
+}
+
+/// Why a [`Nat`](crate::device::Nat) dropped an inbound packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InboundDropReason {
+    /// There was no mapping for the packet's destination port.
+    Unmapped,
+    /// There was a mapping but the NAT's filtering, ie. its address and port restrictions or its
+    /// TCP connection tracking, didn't allow the packet through.
+    Filtered,
+    /// The packet was sent to the NAT's external address by a host behind the NAT, and
+    /// hair-pinning is disabled.
+    HairPinningDisabled,
+}
+
+pub(super) enum NatRequest {
+    Mappings(oneshot::Sender<Vec<NatMapping>>),
+    Subscribe(mpsc::UnboundedSender<NatEvent>),
+}
+
+impl NatTask {
+    pub(super) fn handle_request(&mut self, request: NatRequest) {
+        match request {
+            NatRequest::Mappings(mappings_sender) => {
+                self.expire_mappings();
+                let _ = mappings_sender.send(self.mappings());
+            },
+            NatRequest::Subscribe(event_sender) => {
+                self.event_senders.push(event_sender);
+            },
+        }
+    }
+
+    fn mappings(&self) -> Vec<NatMapping> {
+        let tables = [
+            (IpProtocol::Tcp, &self.tcpv4_port_map, &self.tcpv4_restrictions),
+            (IpProtocol::Udp, &self.udpv4_port_map, &self.udpv4_restrictions),
+        ];
+        let mut mappings = Vec::new();
+        for (protocol, port_map, restrictions) in tables {
+            for entry in port_map.entries() {
+                let permitted_remotes = if port_map.accepts_any_remote(entry.external_addr) {
+                    PermittedRemotes::Any
+                } else {
+                    restrictions.permitted_remotes(entry.external_addr)
+                };
+                mappings.push(NatMapping {
+                    protocol,
+                    internal_addr: entry.internal_addr,
+                    external_addr: entry.external_addr,
+                    forwarded: entry.forwarded,
+                    permitted_remotes,
+                    last_activity: entry.last_activity,
+                });
+            }
+        }
+        mappings.sort_by_key(|mapping| (mapping.protocol, mapping.external_addr));
+        mappings
+    }
+
+    fn emit(&mut self, event: NatEvent) {
+        self.event_senders.retain(|event_sender| event_sender.unbounded_send(event.clone()).is_ok());
+    }
+
+    pub(super) fn emit_mapping_created(
+        &mut self,
+        protocol: IpProtocol,
+        internal_addr: SocketAddrV4,
+        external_addr: SocketAddrV4,
+    ) {
+        self.emit(NatEvent::MappingCreated { protocol, internal_addr, external_addr });
+    }
+
+    pub(super) fn emit_mapping_refreshed(&mut self, protocol: IpProtocol, external_addr: SocketAddrV4) {
+        if self.event_senders.is_empty() {
+            return;
+        }
+        let internal_addr = match self.port_map_and_restrictions(protocol) {
+            Some((port_map, _restrictions)) => match port_map.internal_addr(external_addr) {
+                Some(internal_addr) => internal_addr,
+                None => return,
+            },
+            None => return,
+        };
+        self.emit(NatEvent::MappingRefreshed { protocol, internal_addr, external_addr });
+    }
+
+    pub(super) fn emit_mapping_expired(&mut self, protocol: IpProtocol, entry: &PortMapEntry) {
+        let internal_addr = entry.internal_addr;
+        let external_addr = entry.external_addr;
+        self.emit(NatEvent::MappingExpired { protocol, internal_addr, external_addr });
+    }
+
+    pub(super) fn emit_mapping_removed(
+        &mut self,
+        protocol: IpProtocol,
+        internal_addr: SocketAddrV4,
+        external_addr: SocketAddrV4,
+    ) {
+        self.emit(NatEvent::MappingRemoved { protocol, internal_addr, external_addr });
+    }
+
+    /// Reports that an inbound packet from `remote_addr` to `external_addr` was dropped, working
+    /// out whether it was dropped for lack of a mapping or by the NAT's filtering.
+    pub(super) fn emit_inbound_dropped(
+        &mut self,
+        protocol: IpProtocol,
+        remote_addr: SocketAddrV4,
+        external_addr: SocketAddrV4,
+    ) {
+        if self.event_senders.is_empty() {
+            return;
+        }
+        let mapped = match self.port_map_and_restrictions(protocol) {
+            Some((port_map, _restrictions)) => port_map.internal_addr(external_addr).is_some(),
+            None => false,
+        };
+        let reason = if mapped {
+            InboundDropReason::Filtered
+        } else {
+            InboundDropReason::Unmapped
+        };
+        self.emit(NatEvent::InboundDropped { protocol, remote_addr, external_addr, reason });
+    }
+
+    pub(super) fn emit_hair_pinning_dropped(
+        &mut self,
+        protocol: IpProtocol,
+        remote_addr: SocketAddrV4,
+        external_addr: SocketAddrV4,
+    ) {
+        let reason = InboundDropReason::HairPinningDisabled;
+        self.emit(NatEvent::InboundDropped { protocol, remote_addr, external_addr, reason });
+    }
+}
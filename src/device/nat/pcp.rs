@@ -0,0 +1,285 @@
+//! NAT-PMP (RFC 6886) and PCP (RFC 6887) server. Both protocols share a port, and are told apart
+//! by the version number at the start of each request.
+
+use {
+    super::NatTask,
+    crate::{
+        priv_prelude::*,
+        packet::{IpProtocol, Udpv4Packet},
+    },
+};
+
+pub const SERVER_PORT: u16 = 5351;
+
+/// The longest lifetime, in seconds, that the server will give a mapping.
+const MAX_MAPPING_LIFETIME: u32 = 24 * 60 * 60;
+
+const NAT_PMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+
+/// Set on the opcode of NAT-PMP and PCP responses.
+const RESPONSE_BIT: u8 = 0x80;
+
+mod nat_pmp_opcodes {
+    pub const EXTERNAL_ADDRESS: u8 = 0;
+    pub const MAP_UDP: u8 = 1;
+    pub const MAP_TCP: u8 = 2;
+}
+
+mod nat_pmp_result_codes {
+    pub const SUCCESS: u16 = 0;
+    pub const UNSUPPORTED_VERSION: u16 = 1;
+    pub const NOT_AUTHORIZED: u16 = 2;
+    pub const OUT_OF_RESOURCES: u16 = 4;
+    pub const UNSUPPORTED_OPCODE: u16 = 5;
+}
+
+mod pcp_opcodes {
+    pub const ANNOUNCE: u8 = 0;
+    pub const MAP: u8 = 1;
+}
+
+mod pcp_result_codes {
+    pub const SUCCESS: u8 = 0;
+    pub const UNSUPP_VERSION: u8 = 1;
+    pub const MALFORMED_REQUEST: u8 = 3;
+    pub const UNSUPP_OPCODE: u8 = 4;
+    pub const UNSUPP_OPTION: u8 = 5;
+    pub const MALFORMED_OPTION: u8 = 6;
+    pub const NO_RESOURCES: u8 = 8;
+    pub const UNSUPP_PROTOCOL: u8 = 9;
+    pub const ADDRESS_MISMATCH: u8 = 12;
+}
+
+const PCP_HEADER_LEN: usize = 24;
+const PCP_MAP_LEN: usize = 36;
+const PCP_MAX_LEN: usize = 1100;
+
+/// Options with codes below this must be understood by the server.
+const PCP_OPTIONAL_OPTION_CODES: u8 = 128;
+
+fn ipv4_mapped(addr: Ipv4Addr) -> [u8; 16] {
+    addr.to_ipv6_mapped().octets()
+}
+
+impl NatTask {
+    pub(super) fn dispatch_pcp_request(&mut self, packet: &Udpv4Packet) {
+        let client_addr = packet.source_addr();
+        let request = packet.data();
+        if request.len() < 2 || request[1] & RESPONSE_BIT != 0 {
+            return;
+        }
+        let response = match request[0] {
+            NAT_PMP_VERSION => self.nat_pmp_response(*client_addr.ip(), request),
+            _ => self.pcp_response(*client_addr.ip(), request),
+        };
+        let response = match response {
+            Some(response) => response,
+            None => return,
+        };
+        let server_addr = SocketAddrV4::new(self.internal_ipv4, SERVER_PORT);
+        let response_packet = Udpv4Packet::new(server_addr, client_addr, &response);
+        self.send_from_gateway(*client_addr.ip(), response_packet.ip_packet_box());
+    }
+
+    /// Seconds since the NAT started, which lets clients notice if the NAT has lost its mappings.
+    fn epoch(&self) -> u32 {
+        self.start_instant.elapsed().as_secs() as u32
+    }
+
+    /// Creates or renews a mapping to `internal_addr` for `lifetime` seconds, or deletes it if
+    /// `lifetime` is zero. Returns the external address and port, which are unspecified when
+    /// deleting, and the assigned lifetime, or `None` if there's no port left to map.
+    fn request_mapping(
+        &mut self,
+        protocol: IpProtocol,
+        internal_addr: SocketAddrV4,
+        suggested_port: u16,
+        lifetime: u32,
+    ) -> Option<(SocketAddrV4, u32)> {
+        if lifetime == 0 {
+            self.remove_requested_mapping(protocol, internal_addr);
+            return Some((SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0), 0));
+        }
+        let lifetime = lifetime.min(MAX_MAPPING_LIFETIME);
+        let expiry = Instant::now() + Duration::from_secs(u64::from(lifetime));
+        let external_ipv4 = self.external_ipv4_pool.host_ipv4(*internal_addr.ip());
+        let (port_map, restrictions) = self.port_map_and_restrictions(protocol)?;
+        let renewed = {
+            port_map.forwarded_port(internal_addr).is_some()
+            || !port_map.mapped_addrs(internal_addr).is_empty()
+        };
+        let (external_addr, removed) = port_map.forward_until(internal_addr, external_ipv4, suggested_port, expiry)?;
+        restrictions.forget(external_addr);
+        self.forget_evicted_mappings(protocol, removed);
+        debug!(
+            "{}: mapped {} to {} for {} seconds on request",
+            self.external_ipv4, external_addr, internal_addr, lifetime,
+        );
+        if renewed {
+            self.emit_mapping_refreshed(protocol, external_addr);
+        } else {
+            self.emit_mapping_created(protocol, internal_addr, external_addr);
+        }
+        Some((external_addr, lifetime))
+    }
+
+    fn remove_requested_mapping(&mut self, protocol: IpProtocol, internal_addr: SocketAddrV4) {
+        let (port_map, restrictions) = match self.port_map_and_restrictions(protocol) {
+            Some(port_map_and_restrictions) => port_map_and_restrictions,
+            None => return,
+        };
+        if let Some(external_addr) = port_map.unforward(internal_addr) {
+            restrictions.forget(external_addr);
+            if protocol == IpProtocol::Tcp {
+                self.forget_tcp_state(external_addr);
+            }
+            debug!("{}: unmapped {} from {} on request", self.external_ipv4, external_addr, internal_addr);
+            self.emit_mapping_removed(protocol, internal_addr, external_addr);
+        }
+    }
+
+    fn nat_pmp_response(&mut self, client_ip: Ipv4Addr, request: &[u8]) -> Option<Vec<u8>> {
+        let opcode = request[1];
+        let mut response = vec![NAT_PMP_VERSION, RESPONSE_BIT | opcode];
+        match opcode {
+            nat_pmp_opcodes::EXTERNAL_ADDRESS => {
+                response.extend(nat_pmp_result_codes::SUCCESS.to_be_bytes());
+                response.extend(self.epoch().to_be_bytes());
+                response.extend(self.external_ipv4_pool.host_ipv4(client_ip).octets());
+            },
+            nat_pmp_opcodes::MAP_UDP | nat_pmp_opcodes::MAP_TCP => {
+                if request.len() < 12 {
+                    return None;
+                }
+                let protocol = if opcode == nat_pmp_opcodes::MAP_UDP { IpProtocol::Udp } else { IpProtocol::Tcp };
+                let internal_port = u16::from_be_bytes([request[4], request[5]]);
+                let suggested_port = u16::from_be_bytes([request[6], request[7]]);
+                let lifetime = u32::from_be_bytes([request[8], request[9], request[10], request[11]]);
+                let (result_code, external_port, lifetime) = match (internal_port, lifetime) {
+                    (0, 0) => {
+                        for internal_addr in self.requested_mapping_addrs(protocol, client_ip) {
+                            self.remove_requested_mapping(protocol, internal_addr);
+                        }
+                        (nat_pmp_result_codes::SUCCESS, 0, 0)
+                    },
+                    (0, _) => (nat_pmp_result_codes::NOT_AUTHORIZED, 0, 0),
+                    (_, _) => {
+                        let internal_addr = SocketAddrV4::new(client_ip, internal_port);
+                        match self.request_mapping(protocol, internal_addr, suggested_port, lifetime) {
+                            Some((external_addr, lifetime)) => (nat_pmp_result_codes::SUCCESS, external_addr.port(), lifetime),
+                            None => (nat_pmp_result_codes::OUT_OF_RESOURCES, 0, 0),
+                        }
+                    },
+                };
+                response.extend(result_code.to_be_bytes());
+                response.extend(self.epoch().to_be_bytes());
+                response.extend(internal_port.to_be_bytes());
+                response.extend(external_port.to_be_bytes());
+                response.extend(lifetime.to_be_bytes());
+            },
+            _ => {
+                response.extend(nat_pmp_result_codes::UNSUPPORTED_OPCODE.to_be_bytes());
+                response.extend(self.epoch().to_be_bytes());
+            },
+        }
+        Some(response)
+    }
+
+    fn requested_mapping_addrs(&mut self, protocol: IpProtocol, client_ip: Ipv4Addr) -> Vec<SocketAddrV4> {
+        match self.port_map_and_restrictions(protocol) {
+            Some((port_map, _restrictions)) => port_map.forwarded_addrs(client_ip),
+            None => Vec::new(),
+        }
+    }
+
+    fn pcp_response(&mut self, client_ip: Ipv4Addr, request: &[u8]) -> Option<Vec<u8>> {
+        let opcode = request[1];
+        if request[0] == 1 {
+            // Version 1 was never used by PCP. Clients which speak it expect NAT-PMP style errors.
+            let mut response = vec![NAT_PMP_VERSION, RESPONSE_BIT | opcode];
+            response.extend(nat_pmp_result_codes::UNSUPPORTED_VERSION.to_be_bytes());
+            response.extend(self.epoch().to_be_bytes());
+            return Some(response);
+        }
+
+        let (result_code, lifetime, map_response_opt) = if request[0] != PCP_VERSION {
+            (pcp_result_codes::UNSUPP_VERSION, 0, None)
+        } else if request.len() < PCP_HEADER_LEN || request.len() > PCP_MAX_LEN || !request.len().is_multiple_of(4) {
+            (pcp_result_codes::MALFORMED_REQUEST, 0, None)
+        } else if request[8..24] != ipv4_mapped(client_ip) {
+            (pcp_result_codes::ADDRESS_MISMATCH, 0, None)
+        } else {
+            let lifetime = u32::from_be_bytes([request[4], request[5], request[6], request[7]]);
+            match opcode {
+                pcp_opcodes::ANNOUNCE => (pcp_result_codes::SUCCESS, 0, None),
+                pcp_opcodes::MAP => self.pcp_map_response(client_ip, lifetime, &request[PCP_HEADER_LEN..]),
+                _ => (pcp_result_codes::UNSUPP_OPCODE, 0, None),
+            }
+        };
+
+        let mut response = vec![PCP_VERSION, RESPONSE_BIT | opcode, 0, result_code];
+        response.extend(lifetime.to_be_bytes());
+        response.extend(self.epoch().to_be_bytes());
+        response.extend([0; 12]);
+        if let Some(map_response) = map_response_opt {
+            response.extend(map_response);
+        }
+        Some(response)
+    }
+
+    /// Handles the body of a PCP MAP request. Returns the result code, the assigned lifetime and
+    /// the body of the response.
+    fn pcp_map_response(&mut self, client_ip: Ipv4Addr, lifetime: u32, body: &[u8]) -> (u8, u32, Option<Vec<u8>>) {
+        if body.len() < PCP_MAP_LEN {
+            return (pcp_result_codes::MALFORMED_REQUEST, 0, None);
+        }
+        let (map_request, options) = body.split_at(PCP_MAP_LEN);
+        let mut map_response = map_request.to_vec();
+        if let Err(result_code) = check_pcp_options(options) {
+            return (result_code, 0, Some(map_response));
+        }
+
+        let protocol = match map_request[12] {
+            6 => IpProtocol::Tcp,
+            17 => IpProtocol::Udp,
+            _ => return (pcp_result_codes::UNSUPP_PROTOCOL, 0, Some(map_response)),
+        };
+        let internal_port = u16::from_be_bytes([map_request[16], map_request[17]]);
+        if internal_port == 0 {
+            return (pcp_result_codes::MALFORMED_REQUEST, 0, Some(map_response));
+        }
+        let suggested_port = u16::from_be_bytes([map_request[18], map_request[19]]);
+        let internal_addr = SocketAddrV4::new(client_ip, internal_port);
+        let (external_addr, lifetime) = match self.request_mapping(protocol, internal_addr, suggested_port, lifetime) {
+            Some(addr_and_lifetime) => addr_and_lifetime,
+            None => return (pcp_result_codes::NO_RESOURCES, 0, Some(map_response)),
+        };
+        if lifetime != 0 {
+            map_response[18..20].copy_from_slice(&external_addr.port().to_be_bytes());
+            map_response[20..36].copy_from_slice(&ipv4_mapped(*external_addr.ip()));
+        }
+        (pcp_result_codes::SUCCESS, lifetime, Some(map_response))
+    }
+}
+
+/// Checks that a request doesn't contain any options that we'd need to understand. We don't
+/// support any options, but options which are optional can be ignored.
+fn check_pcp_options(mut options: &[u8]) -> Result<(), u8> {
+    while !options.is_empty() {
+        if options.len() < 4 {
+            return Err(pcp_result_codes::MALFORMED_OPTION);
+        }
+        let code = options[0];
+        let len = usize::from(u16::from_be_bytes([options[2], options[3]]));
+        let padded_len = 4 + len.div_ceil(4) * 4;
+        if options.len() < padded_len {
+            return Err(pcp_result_codes::MALFORMED_OPTION);
+        }
+        if code < PCP_OPTIONAL_OPTION_CODES {
+            return Err(pcp_result_codes::UNSUPP_OPTION);
+        }
+        options = &options[padded_len..];
+    }
+    Ok(())
+}
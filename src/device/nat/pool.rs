@@ -0,0 +1,62 @@
+use crate::priv_prelude::*;
+
+/// How the NAT picks which of its external addresses a new mapping uses (RFC 4787 section 4.1).
+#[derive(Clone, Copy)]
+pub enum Pooling {
+    /// All the mappings of an internal IP address use the same external address.
+    Paired,
+    /// Each mapping may use any external address.
+    Arbitrary,
+}
+
+/// The NAT's external addresses.
+pub struct AddressPool {
+    external_ipv4s: Vec<Ipv4Addr>,
+    pooling: Pooling,
+    /// The external address each internal address is paired with, when pooling is paired.
+    pairings: HashMap<Ipv4Addr, Ipv4Addr>,
+    next_index: usize,
+}
+
+impl AddressPool {
+    /// Creates a pool of `external_ipv4s`, which must not be empty.
+    pub fn new(external_ipv4s: Vec<Ipv4Addr>, pooling: Pooling) -> AddressPool {
+        assert!(!external_ipv4s.is_empty());
+        AddressPool {
+            external_ipv4s,
+            pooling,
+            pairings: HashMap::new(),
+            next_index: 0,
+        }
+    }
+
+    pub fn contains(&self, ipv4_addr: Ipv4Addr) -> bool {
+        self.external_ipv4s.contains(&ipv4_addr)
+    }
+
+    /// Picks the external address for a new mapping from `internal_ip`. Addresses are handed out
+    /// in turn, either to each new internal address or to each new mapping.
+    pub fn select(&mut self, internal_ip: Ipv4Addr) -> Ipv4Addr {
+        if let Pooling::Paired = self.pooling {
+            if let Some(external_ipv4) = self.pairings.get(&internal_ip) {
+                return *external_ipv4;
+            }
+        }
+        let external_ipv4 = self.external_ipv4s[self.next_index];
+        self.next_index = (self.next_index + 1) % self.external_ipv4s.len();
+        if let Pooling::Paired = self.pooling {
+            self.pairings.insert(internal_ip, external_ipv4);
+        }
+        external_ipv4
+    }
+
+    /// The external address for ports forwarded to `internal_ip`, whether configured on the NAT
+    /// or requested through PCP or UPnP. This is the address `internal_ip` is paired with, or the
+    /// primary address if pooling is arbitrary.
+    pub fn host_ipv4(&mut self, internal_ip: Ipv4Addr) -> Ipv4Addr {
+        match self.pooling {
+            Pooling::Paired => self.select(internal_ip),
+            Pooling::Arbitrary => self.external_ipv4s[0],
+        }
+    }
+}
@@ -1,55 +1,497 @@
 use crate::priv_prelude::*;
 
+/// Which external endpoints share a mapping (RFC 4787 section 4.1).
+#[derive(Clone, Copy)]
+pub enum Mapping {
+    /// Packets from an internal address always use the same external port.
+    EndpointIndependent,
+    /// Packets from an internal address use a different external port for each remote IP address.
+    AddressDependent,
+    /// Packets from an internal address use a different external port for each remote socket
+    /// address.
+    AddressAndPortDependent,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct MappingKey {
+    internal_addr: SocketAddrV4,
+    /// The parts of the remote address which the mapping depends on, with the rest zeroed.
+    remote_addr: SocketAddrV4,
+}
+
+/// A mapping created by outgoing traffic, as seen from the external side.
+#[derive(Clone, Copy)]
+struct IncomingMapping {
+    key: MappingKey,
+    /// Whether the mapping was created for the DMZ host, in which case it accepts packets from
+    /// any remote address.
+    dmz: bool,
+}
+
+/// How the NAT picks external ports for new mappings.
+#[derive(Clone)]
+pub struct PortAllocation {
+    pub min_port: u16,
+    pub max_port: u16,
+    pub strategy: PortStrategy,
+    /// Use the internal port as the external port if it's free.
+    pub preserve_port: bool,
+    /// Only use external ports with the same parity as the internal port.
+    pub preserve_parity: bool,
+    /// Map consecutive internal ports to consecutive external ports where possible.
+    pub contiguous: bool,
+}
+
+#[derive(Clone, Copy)]
+pub enum PortStrategy {
+    Sequential { step: u16 },
+    Random,
+}
+
+impl Default for PortAllocation {
+    fn default() -> PortAllocation {
+        PortAllocation {
+            min_port: 1025,
+            max_port: u16::MAX,
+            strategy: PortStrategy::Sequential { step: 1 },
+            preserve_port: false,
+            preserve_parity: false,
+            contiguous: false,
+        }
+    }
+}
+
+/// A mapping or forwarded port in a [`PortMap`].
+pub struct PortMapEntry {
+    pub external_addr: SocketAddrV4,
+    pub internal_addr: SocketAddrV4,
+    pub forwarded: bool,
+    pub last_activity: Instant,
+}
+
+/// The mappings and forwarded ports for one protocol. Mappings and forwarded ports are keyed by
+/// their external address and port, so each of the NAT's external addresses has its own range of
+/// ports.
 pub struct PortMap {
-    outgoing_map: HashMap<SocketAddrV4, u16>,
-    incoming_map: HashMap<u16, SocketAddrV4>,
-    next_port: u16,
+    mapping: Mapping,
+    allocation: PortAllocation,
+    outgoing_map: HashMap<MappingKey, SocketAddrV4>,
+    incoming_map: HashMap<SocketAddrV4, IncomingMapping>,
+    expiries: HashMap<SocketAddrV4, Instant>,
+    forwarded_incoming_map: HashMap<SocketAddrV4, SocketAddrV4>,
+    forwarded_outgoing_map: HashMap<SocketAddrV4, SocketAddrV4>,
+    /// Expiry times of forwarded ports which were requested by hosts, eg. through PCP, rather
+    /// than configured on the NAT. `None` if the port was requested without a lifetime.
+    forwarded_expiries: HashMap<SocketAddrV4, Option<Instant>>,
+    /// When each port was created or last had a packet pass through it.
+    last_activity: HashMap<SocketAddrV4, Instant>,
+    /// The next port for the sequential strategy to try on each external address.
+    next_ports: HashMap<Ipv4Addr, u16>,
 }
 
 impl PortMap {
-    const INITIAL_PORT: u16 = 1025;
-
-    pub fn new() -> PortMap {
+    pub fn new(mapping: Mapping, allocation: PortAllocation) -> PortMap {
         PortMap {
+            mapping,
+            allocation,
             outgoing_map: HashMap::new(),
             incoming_map: HashMap::new(),
-            next_port: PortMap::INITIAL_PORT,
-        }
-    }
-
-    pub fn outgoing_port(&mut self, internal_addr: SocketAddrV4) -> u16 {
-        match self.outgoing_map.entry(internal_addr) {
-            hash_map::Entry::Occupied(entry) => *entry.get(),
-            hash_map::Entry::Vacant(entry) => {
-                let mut attempts = PortMap::INITIAL_PORT;
-                let port = loop {
-                    let port = self.next_port;
-                    self.next_port = {
-                        self.next_port.checked_add(1).unwrap_or(PortMap::INITIAL_PORT)
-                    };
-                    match self.incoming_map.entry(port) {
-                        hash_map::Entry::Occupied(mut entry) => {
-                            attempts = match attempts.checked_add(1) {
-                                Some(attempts) => attempts,
-                                None => {
-                                    entry.insert(internal_addr);
-                                    break port;
-                                },
-                            };
-                        },
-                        hash_map::Entry::Vacant(entry) => {
-                            entry.insert(internal_addr);
-                            break port;
-                        },
-                    }
+            expiries: HashMap::new(),
+            forwarded_incoming_map: HashMap::new(),
+            forwarded_outgoing_map: HashMap::new(),
+            forwarded_expiries: HashMap::new(),
+            last_activity: HashMap::new(),
+            next_ports: HashMap::new(),
+        }
+    }
+
+    /// Permanently maps `external_addr` to `internal_addr`. Packets from `internal_addr` always
+    /// use `external_addr` and `external_addr` is never used for other mappings.
+    pub fn forward(&mut self, external_addr: SocketAddrV4, internal_addr: SocketAddrV4) {
+        if let Some(old_internal_addr) = self.forwarded_incoming_map.insert(external_addr, internal_addr) {
+            self.forwarded_outgoing_map.remove(&old_internal_addr);
+        }
+        self.forwarded_outgoing_map.insert(internal_addr, external_addr);
+        self.last_activity.insert(external_addr, Instant::now());
+    }
+
+    /// Forwards a port on `external_ipv4` to `internal_addr` until `expiry`. If `internal_addr`
+    /// already has a forwarded port then that port is kept, and its expiry is updated unless it's
+    /// permanent. If it has a mapping then the mapping's port is forwarded instead (RFC 6887
+    /// section 11.3), and any other mappings from `internal_addr` are removed since they'd no
+    /// longer be used. Otherwise `suggested_port` is used if it's free. Returns the forwarded
+    /// address and port along with the mappings which were removed or evicted to make room for
+    /// it, or `None` if every port is forwarded.
+    pub fn forward_until(
+        &mut self,
+        internal_addr: SocketAddrV4,
+        external_ipv4: Ipv4Addr,
+        suggested_port: u16,
+        expiry: Instant,
+    ) -> Option<(SocketAddrV4, Vec<PortMapEntry>)> {
+        if let Some(external_addr) = self.forwarded_outgoing_map.get(&internal_addr) {
+            let external_addr = *external_addr;
+            if let Some(expiry_opt) = self.forwarded_expiries.get_mut(&external_addr) {
+                *expiry_opt = Some(expiry);
+            }
+            return Some((external_addr, Vec::new()));
+        }
+        let mut mapped_addrs = self.mapped_addrs(internal_addr);
+        mapped_addrs.sort_unstable();
+        let external_addr = match mapped_addrs.first() {
+            Some(external_addr) => *external_addr,
+            None => {
+                let key = MappingKey {
+                    internal_addr,
+                    remote_addr: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
                 };
-                *entry.insert(port)
+                let suggested_addr = SocketAddrV4::new(external_ipv4, suggested_port);
+                if suggested_port != 0 && self.port_usable(key, suggested_addr) {
+                    suggested_addr
+                } else {
+                    SocketAddrV4::new(external_ipv4, self.allocate_port(key, external_ipv4)?)
+                }
             },
+        };
+        let mut removed = Vec::new();
+        for mapped_addr in mapped_addrs.into_iter().skip(1) {
+            removed.extend(self.remove_mapping(mapped_addr));
+        }
+        match self.remove_mapping(external_addr) {
+            Some(entry) if entry.internal_addr != internal_addr => removed.push(entry),
+            Some(_) | None => (),
         }
+        self.forward(external_addr, internal_addr);
+        self.forwarded_expiries.insert(external_addr, Some(expiry));
+        Some((external_addr, removed))
     }
 
-    pub fn incoming_addr(&self, port: u16) -> Option<SocketAddrV4> {
-        self.incoming_map.get(&port).copied()
+    /// The external addresses of the mappings from `internal_addr`.
+    pub fn mapped_addrs(&self, internal_addr: SocketAddrV4) -> Vec<SocketAddrV4> {
+        self.outgoing_map
+        .iter()
+        .filter(|(key, _external_addr)| key.internal_addr == internal_addr)
+        .map(|(_key, external_addr)| *external_addr)
+        .collect()
+    }
+
+    /// Forwards exactly `external_addr` to `internal_addr` until `expiry_opt`, or until it's
+    /// removed if `expiry_opt` is `None`. Fails, returning `None`, if `external_addr` is already
+    /// forwarded elsewhere or `internal_addr` already has a different port forwarded to it. Renews
+    /// the forward if it already exists. On success, returns the mapping which was evicted from
+    /// `external_addr`, if any.
+    pub fn forward_exactly(
+        &mut self,
+        external_addr: SocketAddrV4,
+        internal_addr: SocketAddrV4,
+        expiry_opt: Option<Instant>,
+    ) -> Option<Option<PortMapEntry>> {
+        match self.forwarded_incoming_map.get(&external_addr) {
+            Some(forwarded_addr) if *forwarded_addr == internal_addr => {
+                if let Some(forwarded_expiry_opt) = self.forwarded_expiries.get_mut(&external_addr) {
+                    *forwarded_expiry_opt = expiry_opt;
+                }
+                return Some(None);
+            },
+            Some(_) => return None,
+            None => (),
+        }
+        if self.forwarded_outgoing_map.contains_key(&internal_addr) {
+            return None;
+        }
+        let evicted_opt = self.remove_mapping(external_addr);
+        self.forward(external_addr, internal_addr);
+        self.forwarded_expiries.insert(external_addr, expiry_opt);
+        Some(evicted_opt)
+    }
+
+    /// Removes `external_addr` if it was forwarded by
+    /// [`forward_until`](PortMap::forward_until) or [`forward_exactly`](PortMap::forward_exactly),
+    /// returning the address it was forwarded to.
+    pub fn unforward_port(&mut self, external_addr: SocketAddrV4) -> Option<SocketAddrV4> {
+        self.forwarded_expiries.remove(&external_addr)?;
+        let internal_addr = self.forwarded_incoming_map.remove(&external_addr)?;
+        self.forwarded_outgoing_map.remove(&internal_addr);
+        self.last_activity.remove(&external_addr);
+        Some(internal_addr)
     }
-}
 
+    /// Removes the port forwarded to `internal_addr` by
+    /// [`forward_until`](PortMap::forward_until), returning its external address. Permanently
+    /// forwarded ports aren't removed.
+    pub fn unforward(&mut self, internal_addr: SocketAddrV4) -> Option<SocketAddrV4> {
+        let external_addr = *self.forwarded_outgoing_map.get(&internal_addr)?;
+        self.unforward_port(external_addr)?;
+        Some(external_addr)
+    }
+
+    /// The internal addresses at `internal_ip` which have ports forwarded to them by
+    /// [`forward_until`](PortMap::forward_until).
+    pub fn forwarded_addrs(&self, internal_ip: Ipv4Addr) -> Vec<SocketAddrV4> {
+        self.forwarded_outgoing_map
+        .iter()
+        .filter(|(internal_addr, external_addr)| {
+            *internal_addr.ip() == internal_ip && self.forwarded_expiries.contains_key(external_addr)
+        })
+        .map(|(internal_addr, _external_addr)| *internal_addr)
+        .collect()
+    }
+
+    /// The internal address that `external_addr` is forwarded to, if any.
+    pub fn forwarded_addr(&self, external_addr: SocketAddrV4) -> Option<SocketAddrV4> {
+        self.forwarded_incoming_map.get(&external_addr).copied()
+    }
+
+    fn key(&self, internal_addr: SocketAddrV4, remote_addr: SocketAddrV4) -> MappingKey {
+        let remote_addr = match self.mapping {
+            Mapping::EndpointIndependent => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+            Mapping::AddressDependent => SocketAddrV4::new(*remote_addr.ip(), 0),
+            Mapping::AddressAndPortDependent => remote_addr,
+        };
+        MappingKey { internal_addr, remote_addr }
+    }
+
+    /// The external address and port to use for packets sent from `internal_addr` to
+    /// `remote_addr`, creating a mapping on `external_ipv4` if there isn't one. Returns the
+    /// external address along with any mapping which was evicted to make room for it, or `None` if
+    /// every port on `external_ipv4` is forwarded.
+    pub fn outgoing_port(
+        &mut self,
+        internal_addr: SocketAddrV4,
+        remote_addr: SocketAddrV4,
+        external_ipv4: Ipv4Addr,
+    ) -> Option<(SocketAddrV4, Option<PortMapEntry>)> {
+        if let Some(external_addr) = self.forwarded_outgoing_map.get(&internal_addr) {
+            return Some((*external_addr, None));
+        }
+        let key = self.key(internal_addr, remote_addr);
+        if let Some(external_addr) = self.outgoing_map.get(&key) {
+            return Some((*external_addr, None));
+        }
+        let external_addr = SocketAddrV4::new(external_ipv4, self.allocate_port(key, external_ipv4)?);
+        let evicted_opt = self.remove_mapping(external_addr);
+        self.incoming_map.insert(external_addr, IncomingMapping { key, dmz: false });
+        self.outgoing_map.insert(key, external_addr);
+        self.last_activity.insert(external_addr, Instant::now());
+        Some((external_addr, evicted_opt))
+    }
+
+    /// Creates a mapping for the DMZ host from `internal_addr` to `remote_addr` which uses
+    /// `external_addr`, if `external_addr` is free and there isn't already a mapping. Like a
+    /// forwarded port, the mapping accepts packets from any remote address. Returns whether the
+    /// mapping was created.
+    pub fn insert_dmz(
+        &mut self,
+        external_addr: SocketAddrV4,
+        internal_addr: SocketAddrV4,
+        remote_addr: SocketAddrV4,
+    ) -> bool {
+        let key = self.key(internal_addr, remote_addr);
+        let in_use = {
+            self.incoming_map.contains_key(&external_addr)
+            || self.forwarded_incoming_map.contains_key(&external_addr)
+            || self.forwarded_outgoing_map.contains_key(&internal_addr)
+            || self.outgoing_map.contains_key(&key)
+        };
+        if in_use {
+            return false;
+        }
+        self.incoming_map.insert(external_addr, IncomingMapping { key, dmz: true });
+        self.outgoing_map.insert(key, external_addr);
+        self.last_activity.insert(external_addr, Instant::now());
+        true
+    }
+
+    fn port_usable(&self, key: MappingKey, external_addr: SocketAddrV4) -> bool {
+        let allocation = &self.allocation;
+        let port = external_addr.port();
+        (allocation.min_port..=allocation.max_port).contains(&port)
+            && (!allocation.preserve_parity || port % 2 == key.internal_addr.port() % 2)
+            && !self.incoming_map.contains_key(&external_addr)
+            && !self.forwarded_incoming_map.contains_key(&external_addr)
+    }
+
+    /// Picks a free port on `external_ipv4` for a new mapping. If every usable port is taken then
+    /// a port used by another mapping is picked, and the caller has to evict that mapping.
+    /// Forwarded ports are never picked, so this returns `None` if every port is forwarded.
+    fn allocate_port(&mut self, key: MappingKey, external_ipv4: Ipv4Addr) -> Option<u16> {
+        let internal_port = key.internal_addr.port();
+        let usable = |port_map: &PortMap, port: u16| {
+            port_map.port_usable(key, SocketAddrV4::new(external_ipv4, port))
+        };
+        if self.allocation.contiguous {
+            let previous_port_opt = {
+                internal_port
+                .checked_sub(1)
+                .map(|port| MappingKey { internal_addr: SocketAddrV4::new(*key.internal_addr.ip(), port), .. key })
+                .and_then(|previous_key| self.outgoing_map.get(&previous_key))
+                .filter(|previous_addr| *previous_addr.ip() == external_ipv4)
+                .and_then(|previous_addr| previous_addr.port().checked_add(1))
+            };
+            if let Some(port) = previous_port_opt {
+                if usable(self, port) {
+                    return Some(port);
+                }
+            }
+        }
+        if self.allocation.preserve_port && usable(self, internal_port) {
+            return Some(internal_port);
+        }
+
+        let PortAllocation { min_port, max_port, strategy, .. } = self.allocation;
+        let num_ports = u32::from(max_port - min_port) + 1;
+        for _ in 0..num_ports {
+            let port = match strategy {
+                PortStrategy::Sequential { step } => {
+                    let next_port = self.next_ports.entry(external_ipv4).or_insert(min_port);
+                    let port = *next_port;
+                    let offset = (u32::from(port - min_port) + u32::from(step)) % num_ports;
+                    *next_port = min_port + offset as u16;
+                    port
+                },
+                PortStrategy::Random => rand::thread_rng().gen_range(min_port..=max_port),
+            };
+            if usable(self, port) {
+                return Some(port);
+            }
+        }
+        // The strategy couldn't find a free port, eg. because the step skips over all the free
+        // ones, so fall back to searching the whole range.
+        if let Some(port) = (min_port..=max_port).find(|port| usable(self, *port)) {
+            return Some(port);
+        }
+        // Every port is taken, so take over a mapped port in turn.
+        for _ in 0..num_ports {
+            let next_port = self.next_ports.entry(external_ipv4).or_insert(min_port);
+            let port = *next_port;
+            *next_port = if port == max_port { min_port } else { port + 1 };
+            if !self.forwarded_incoming_map.contains_key(&SocketAddrV4::new(external_ipv4, port)) {
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    /// The external address and port used for packets sent from `internal_addr` to
+    /// `remote_addr`, without creating a mapping if there isn't one.
+    pub fn mapped_port(&self, internal_addr: SocketAddrV4, remote_addr: SocketAddrV4) -> Option<SocketAddrV4> {
+        if let Some(external_addr) = self.forwarded_outgoing_map.get(&internal_addr) {
+            return Some(*external_addr);
+        }
+        self.outgoing_map.get(&self.key(internal_addr, remote_addr)).copied()
+    }
+
+    pub fn incoming_addr(&self, external_addr: SocketAddrV4) -> Option<SocketAddrV4> {
+        self.incoming_map.get(&external_addr).map(|mapping| mapping.key.internal_addr)
+    }
+
+    /// Whether `external_addr` accepts packets from any remote address, regardless of the NAT's
+    /// restrictions, because it's forwarded or was mapped for the DMZ host.
+    pub fn accepts_any_remote(&self, external_addr: SocketAddrV4) -> bool {
+        self.forwarded_incoming_map.contains_key(&external_addr)
+        || self.incoming_map.get(&external_addr).is_some_and(|mapping| mapping.dmz)
+    }
+
+    /// The internal address that `external_addr` maps or is forwarded to.
+    pub fn internal_addr(&self, external_addr: SocketAddrV4) -> Option<SocketAddrV4> {
+        self.forwarded_addr(external_addr).or_else(|| self.incoming_addr(external_addr))
+    }
+
+    /// The external address and port forwarded to `internal_addr`, if there is one.
+    pub fn forwarded_port(&self, internal_addr: SocketAddrV4) -> Option<SocketAddrV4> {
+        self.forwarded_outgoing_map.get(&internal_addr).copied()
+    }
+
+    /// Records that a packet has passed through `external_addr`.
+    pub fn touch(&mut self, external_addr: SocketAddrV4) {
+        if let Some(last_activity) = self.last_activity.get_mut(&external_addr) {
+            *last_activity = Instant::now();
+        }
+    }
+
+    /// Sets the mapping for `external_addr` to expire at `expiry`. Mappings which are never
+    /// refreshed never expire.
+    pub fn refresh(&mut self, external_addr: SocketAddrV4, expiry: Instant) {
+        if self.incoming_map.contains_key(&external_addr) {
+            self.expiries.insert(external_addr, expiry);
+        }
+    }
+
+    /// Removes the dynamic mapping for `external_addr`, returning it. Forwarded ports are left
+    /// alone.
+    pub fn remove_mapping(&mut self, external_addr: SocketAddrV4) -> Option<PortMapEntry> {
+        let mapping = self.incoming_map.remove(&external_addr)?;
+        self.outgoing_map.remove(&mapping.key);
+        self.expiries.remove(&external_addr);
+        Some(PortMapEntry {
+            external_addr,
+            internal_addr: mapping.key.internal_addr,
+            forwarded: false,
+            last_activity: self.last_activity.remove(&external_addr).unwrap(),
+        })
+    }
+
+    /// The earliest time at which a mapping or requested forwarded port expires.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        let forwarded_expiries = self.forwarded_expiries.values().filter_map(|expiry_opt| *expiry_opt);
+        self.expiries.values().copied().chain(forwarded_expiries).min()
+    }
+
+    /// Removes all mappings and requested forwarded ports which have expired by `now`, returning
+    /// them.
+    pub fn remove_expired(&mut self, now: Instant) -> Vec<PortMapEntry> {
+        let expired_addrs: Vec<SocketAddrV4> = {
+            self.expiries
+            .iter()
+            .filter(|(_external_addr, expiry)| **expiry <= now)
+            .map(|(external_addr, _expiry)| *external_addr)
+            .collect()
+        };
+        let mut expired = Vec::new();
+        for external_addr in expired_addrs {
+            expired.extend(self.remove_mapping(external_addr));
+        }
+        let expired_forwarded_addrs: Vec<SocketAddrV4> = {
+            self.forwarded_expiries
+            .iter()
+            .filter(|(_external_addr, expiry_opt)| matches!(expiry_opt, Some(expiry) if *expiry <= now))
+            .map(|(external_addr, _expiry_opt)| *external_addr)
+            .collect()
+        };
+        for external_addr in expired_forwarded_addrs {
+            let last_activity = self.last_activity[&external_addr];
+            if let Some(internal_addr) = self.unforward_port(external_addr) {
+                expired.push(PortMapEntry {
+                    external_addr,
+                    internal_addr,
+                    forwarded: true,
+                    last_activity,
+                });
+            }
+        }
+        expired
+    }
+
+    /// Lists all the mappings and forwarded ports.
+    pub fn entries(&self) -> Vec<PortMapEntry> {
+        let mappings = {
+            self.incoming_map
+            .iter()
+            .map(|(external_addr, mapping)| (*external_addr, mapping.key.internal_addr, false))
+        };
+        let forwarded_ports = {
+            self.forwarded_incoming_map
+            .iter()
+            .map(|(external_addr, internal_addr)| (*external_addr, *internal_addr, true))
+        };
+        mappings
+        .chain(forwarded_ports)
+        .map(|(external_addr, internal_addr, forwarded)| PortMapEntry {
+            external_addr,
+            internal_addr,
+            forwarded,
+            last_activity: self.last_activity[&external_addr],
+        })
+        .collect()
+    }
+}
@@ -1,44 +1,90 @@
-use crate::priv_prelude::*;
+use {
+    super::PermittedRemotes,
+    crate::priv_prelude::*,
+};
 
 pub enum Restrictions {
     Unrestricted,
     RestrictIpAddr {
-        sent_to: HashMap<u16, HashSet<Ipv4Addr>>,
+        sent_to: HashMap<SocketAddrV4, HashSet<Ipv4Addr>>,
     },
     RestrictSocketAddr {
-        sent_to: HashMap<u16, HashSet<SocketAddrV4>>,
+        sent_to: HashMap<SocketAddrV4, HashSet<SocketAddrV4>>,
     },
 }
 
 impl Restrictions {
-    pub fn sending(&mut self, external_port: u16, destination_addr: SocketAddrV4) {
+    /// Creates the restrictions for a NAT which is port restricted, address restricted or neither.
+    /// Port restriction implies address restriction.
+    pub fn new(port_restricted: bool, address_restricted: bool) -> Restrictions {
+        match (port_restricted, address_restricted) {
+            (false, false) => Restrictions::Unrestricted,
+            (false, true) => Restrictions::RestrictIpAddr { sent_to: HashMap::new() },
+            (true, _) => Restrictions::RestrictSocketAddr { sent_to: HashMap::new() },
+        }
+    }
+
+    pub fn sending(&mut self, external_addr: SocketAddrV4, destination_addr: SocketAddrV4) {
         match self {
             Restrictions::Unrestricted => (),
             Restrictions::RestrictIpAddr { sent_to } => {
-                sent_to.entry(external_port).or_default().insert(*destination_addr.ip());
+                sent_to.entry(external_addr).or_default().insert(*destination_addr.ip());
             },
             Restrictions::RestrictSocketAddr { sent_to } => {
-                sent_to.entry(external_port).or_default().insert(destination_addr);
+                sent_to.entry(external_addr).or_default().insert(destination_addr);
             },
         }
     }
 
-    pub fn incoming_allowed(&self, external_port: u16, source_addr: SocketAddrV4) -> bool {
+    /// Forgets which addresses have been sent to from `external_addr`.
+    pub fn forget(&mut self, external_addr: SocketAddrV4) {
+        match self {
+            Restrictions::Unrestricted => (),
+            Restrictions::RestrictIpAddr { sent_to } => {
+                sent_to.remove(&external_addr);
+            },
+            Restrictions::RestrictSocketAddr { sent_to } => {
+                sent_to.remove(&external_addr);
+            },
+        }
+    }
+
+    pub fn incoming_allowed(&self, external_addr: SocketAddrV4, source_addr: SocketAddrV4) -> bool {
         match self {
             Restrictions::Unrestricted => true,
             Restrictions::RestrictIpAddr { sent_to } => {
-                match sent_to.get(&external_port) {
+                match sent_to.get(&external_addr) {
                     None => false,
                     Some(ipv4_addrs) => ipv4_addrs.contains(source_addr.ip()),
                 }
             },
             Restrictions::RestrictSocketAddr { sent_to } => {
-                match sent_to.get(&external_port) {
+                match sent_to.get(&external_addr) {
                     None => false,
                     Some(socket_addrs) => socket_addrs.contains(&source_addr),
                 }
             },
         }
     }
-}
 
+    /// The remote addresses which are allowed to send to `external_addr`.
+    pub fn permitted_remotes(&self, external_addr: SocketAddrV4) -> PermittedRemotes {
+        match self {
+            Restrictions::Unrestricted => PermittedRemotes::Any,
+            Restrictions::RestrictIpAddr { sent_to } => {
+                let mut ipv4_addrs: Vec<Ipv4Addr> = {
+                    sent_to.get(&external_addr).into_iter().flatten().copied().collect()
+                };
+                ipv4_addrs.sort_unstable();
+                PermittedRemotes::Ips(ipv4_addrs)
+            },
+            Restrictions::RestrictSocketAddr { sent_to } => {
+                let mut socket_addrs: Vec<SocketAddrV4> = {
+                    sent_to.get(&external_addr).into_iter().flatten().copied().collect()
+                };
+                socket_addrs.sort_unstable();
+                PermittedRemotes::Addrs(socket_addrs)
+            },
+        }
+    }
+}
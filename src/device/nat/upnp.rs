@@ -0,0 +1,564 @@
+//! Just enough of a UPnP Internet Gateway Device for clients to find the NAT over SSDP and manage
+//! port mappings through its WANIPConnection service. The service is served over HTTP by a
+//! minimal TCP implementation which assumes a lossless, in-order network.
+
+use {
+    super::NatTask,
+    crate::{
+        priv_prelude::*,
+        packet::{IpProtocol, Tcpv4Packet, Udpv4Packet},
+    },
+};
+
+pub const SSDP_MULTICAST_IPV4: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+pub const SSDP_PORT: u16 = 1900;
+pub const HTTP_PORT: u16 = 5000;
+
+const DESCRIPTION_PATH: &str = "/rootDesc.xml";
+const CONTROL_PATH: &str = "/ctl/IPConn";
+const SERVER: &str = "netsim UPnP/1.0 netsim/0.3";
+
+const ROOT_DEVICE_TYPE: &str = "upnp:rootdevice";
+const GATEWAY_DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const WAN_DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:WANDevice:1";
+const WAN_CONNECTION_DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:WANConnectionDevice:1";
+const WAN_IP_CONNECTION_TYPE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+
+/// The most data we'll put in a single TCP segment.
+const MAX_SEGMENT_LEN: usize = 1200;
+
+/// A request larger than this is answered with an error rather than buffered any further.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+/// A connection which sees no segments for this long is forgotten, so that clients which go away
+/// without closing their connections don't leave state behind forever.
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The server side of a TCP connection to the HTTP endpoint.
+pub struct HttpConnection {
+    initial_seq_number: u32,
+    /// The sequence number of the next byte we'll send.
+    next_seq_number: u32,
+    /// The sequence number of the next byte we expect from the client.
+    next_ack_number: u32,
+    request: Vec<u8>,
+    responded: bool,
+    fin_received: bool,
+    last_active: Instant,
+}
+
+/// A UPnP error, as returned in a SOAP fault.
+struct UpnpError {
+    code: u16,
+    description: &'static str,
+}
+
+impl UpnpError {
+    const INVALID_ACTION: UpnpError = UpnpError { code: 401, description: "Invalid Action" };
+    const INVALID_ARGS: UpnpError = UpnpError { code: 402, description: "Invalid Args" };
+    const ACTION_NOT_AUTHORIZED: UpnpError = UpnpError { code: 606, description: "Action not authorized" };
+    const NO_SUCH_ENTRY_IN_ARRAY: UpnpError = UpnpError { code: 714, description: "NoSuchEntryInArray" };
+    const WILD_CARD_NOT_PERMITTED_IN_EXT_PORT: UpnpError = UpnpError {
+        code: 716,
+        description: "WildCardNotPermittedInExtPort",
+    };
+    const CONFLICT_IN_MAPPING_ENTRY: UpnpError = UpnpError { code: 718, description: "ConflictInMappingEntry" };
+    const REMOTE_HOST_ONLY_SUPPORTS_WILDCARD: UpnpError = UpnpError {
+        code: 726,
+        description: "RemoteHostOnlySupportsWildcard",
+    };
+}
+
+/// A parsed HTTP request.
+struct HttpRequest<'r> {
+    method: &'r str,
+    path: &'r str,
+    headers: Vec<(&'r str, &'r str)>,
+    body: &'r str,
+}
+
+impl<'r> HttpRequest<'r> {
+    /// Parses a request, returning `None` if it hasn't been fully received yet.
+    fn parse(request: &'r [u8]) -> Option<Result<HttpRequest<'r>, ()>> {
+        let header_end = request.windows(4).position(|window| window == b"\r\n\r\n")?;
+        let head = match str::from_utf8(&request[..header_end]) {
+            Ok(head) => head,
+            Err(_) => return Some(Err(())),
+        };
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or("").split(' ');
+        let (method, path) = match (request_line.next(), request_line.next()) {
+            (Some(method), Some(path)) => (method, path),
+            _ => return Some(Err(())),
+        };
+        let headers: Vec<(&str, &str)> = {
+            lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .collect()
+        };
+        let content_len = match find_header(&headers, "content-length") {
+            None => 0,
+            Some(content_len) => match content_len.parse::<usize>() {
+                Ok(content_len) => content_len,
+                Err(_) => return Some(Err(())),
+            },
+        };
+        let body = &request[header_end + 4..];
+        if body.len() < content_len {
+            return None;
+        }
+        let body = match str::from_utf8(&body[..content_len]) {
+            Ok(body) => body,
+            Err(_) => return Some(Err(())),
+        };
+        Some(Ok(HttpRequest { method, path, headers, body }))
+    }
+
+    fn header(&self, name: &str) -> Option<&'r str> {
+        find_header(&self.headers, name)
+    }
+}
+
+fn find_header<'r>(headers: &[(&'r str, &'r str)], name: &str) -> Option<&'r str> {
+    headers
+    .iter()
+    .find(|(header_name, _value)| header_name.eq_ignore_ascii_case(name))
+    .map(|(_header_name, value)| *value)
+}
+
+/// Finds the value of the argument `name` in a SOAP request body.
+fn soap_arg<'b>(body: &'b str, name: &str) -> Option<&'b str> {
+    if body.contains(&format!("<{name}/>")) || body.contains(&format!("<{name} />")) {
+        return Some("");
+    }
+    let start = body.find(&format!("<{name}>"))? + name.len() + 2;
+    let len = body[start..].find(&format!("</{name}>"))?;
+    Some(body[start..start + len].trim())
+}
+
+fn http_response(status: &str, content_type: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {status}\r\n\
+        Content-Type: {content_type}\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        Server: {SERVER}\r\n\
+        EXT:\r\n\
+        \r\n\
+        {body}",
+        body.len(),
+    ).into_bytes()
+}
+
+fn soap_envelope(body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\"?>\r\n\
+        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+        s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+        <s:Body>{body}</s:Body>\
+        </s:Envelope>\r\n"
+    )
+}
+
+fn soap_response(action: &str, args: &[(&str, String)]) -> Vec<u8> {
+    let args: String = {
+        args
+        .iter()
+        .map(|(name, value)| format!("<{name}>{value}</{name}>"))
+        .collect()
+    };
+    let body = soap_envelope(&format!(
+        "<u:{action}Response xmlns:u=\"{WAN_IP_CONNECTION_TYPE}\">{args}</u:{action}Response>"
+    ));
+    http_response("200 OK", "text/xml; charset=\"utf-8\"", &body)
+}
+
+fn soap_error(error: UpnpError) -> Vec<u8> {
+    let body = soap_envelope(&format!(
+        "<s:Fault>\
+        <faultcode>s:Client</faultcode>\
+        <faultstring>UPnPError</faultstring>\
+        <detail>\
+        <UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+        <errorCode>{}</errorCode>\
+        <errorDescription>{}</errorDescription>\
+        </UPnPError>\
+        </detail>\
+        </s:Fault>",
+        error.code, error.description,
+    ));
+    http_response("500 Internal Server Error", "text/xml; charset=\"utf-8\"", &body)
+}
+
+fn parse_protocol(protocol: &str) -> Option<IpProtocol> {
+    match protocol {
+        "TCP" => Some(IpProtocol::Tcp),
+        "UDP" => Some(IpProtocol::Udp),
+        _ => None,
+    }
+}
+
+impl NatTask {
+    /// A UUID for one of the NAT's UPnP devices, made unique by the NAT's external address.
+    fn upnp_uuid(&self, device_index: u16) -> String {
+        format!("uuid:6e657473-696d-4e41-5400-{:08x}{:04x}", u32::from(self.external_ipv4), device_index)
+    }
+
+    fn upnp_location(&self) -> String {
+        format!("http://{}:{}{}", self.internal_ipv4, HTTP_PORT, DESCRIPTION_PATH)
+    }
+
+    /// Answers an SSDP M-SEARCH request for any of the device and service types that we have.
+    pub(super) fn dispatch_ssdp_request(&mut self, packet: &Udpv4Packet) {
+        let request = match HttpRequest::parse(packet.data()) {
+            Some(Ok(request)) => request,
+            Some(Err(())) | None => return,
+        };
+        if request.method != "M-SEARCH" || request.header("man") != Some("\"ssdp:discover\"") {
+            return;
+        }
+        let search_target = request.header("st").unwrap_or("");
+        let uuids_and_types = [
+            (self.upnp_uuid(0), ROOT_DEVICE_TYPE),
+            (self.upnp_uuid(0), GATEWAY_DEVICE_TYPE),
+            (self.upnp_uuid(1), WAN_DEVICE_TYPE),
+            (self.upnp_uuid(2), WAN_CONNECTION_DEVICE_TYPE),
+            (self.upnp_uuid(2), WAN_IP_CONNECTION_TYPE),
+        ];
+        let server_addr = SocketAddrV4::new(self.internal_ipv4, SSDP_PORT);
+        let client_addr = packet.source_addr();
+        for (uuid, notification_type) in uuids_and_types {
+            if search_target != "ssdp:all" && search_target != notification_type {
+                continue;
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                CACHE-CONTROL: max-age=120\r\n\
+                ST: {notification_type}\r\n\
+                USN: {uuid}::{notification_type}\r\n\
+                EXT:\r\n\
+                SERVER: {SERVER}\r\n\
+                LOCATION: {}\r\n\
+                \r\n",
+                self.upnp_location(),
+            );
+            let response_packet = Udpv4Packet::new(server_addr, client_addr, response.as_bytes());
+            self.send_from_gateway(*client_addr.ip(), response_packet.ip_packet_box());
+        }
+    }
+
+    fn send_http_segment(
+        &mut self,
+        client_addr: SocketAddrV4,
+        seq_number: u32,
+        ack_number: u32,
+        flags: TcpPacketFlags,
+        data: &[u8],
+    ) {
+        let mut packet = Tcpv4Packet::new_with_data(data);
+        packet.set_source_addr(SocketAddrV4::new(self.internal_ipv4, HTTP_PORT));
+        packet.set_destination_addr(client_addr);
+        packet.set_seq_number(seq_number);
+        packet.set_ack_number(ack_number);
+        packet.set_window_size(u16::MAX);
+        packet.set_flags(flags);
+        self.send_from_gateway(*client_addr.ip(), packet.ip_packet_box());
+    }
+
+    /// Handles a TCP segment sent to the HTTP endpoint. Each connection carries a single request,
+    /// after which the response is sent and the connection is closed.
+    pub(super) fn dispatch_http_segment(&mut self, packet: &Tcpv4Packet) {
+        let client_addr = packet.source_addr();
+        let flags = packet.flags();
+        let seq_number = packet.seq_number();
+        if flags.rst {
+            self.upnp_connections.remove(&client_addr);
+            return;
+        }
+        if flags.syn && !flags.ack {
+            let next_ack_number = seq_number.wrapping_add(1);
+            let connection = match self.upnp_connections.get(&client_addr) {
+                // A retransmitted SYN, so we need to send the same SYN-ACK again.
+                Some(connection) if connection.next_ack_number == next_ack_number => connection,
+                _ => {
+                    let initial_seq_number = rand::thread_rng().gen();
+                    let connection = HttpConnection {
+                        initial_seq_number,
+                        next_seq_number: initial_seq_number.wrapping_add(1),
+                        next_ack_number,
+                        request: Vec::new(),
+                        responded: false,
+                        fin_received: false,
+                        last_active: Instant::now(),
+                    };
+                    self.upnp_connections.insert(client_addr, connection);
+                    &self.upnp_connections[&client_addr]
+                },
+            };
+            let initial_seq_number = connection.initial_seq_number;
+            let syn_ack = TcpPacketFlags { syn: true, ack: true, .. TcpPacketFlags::default() };
+            self.send_http_segment(client_addr, initial_seq_number, next_ack_number, syn_ack, &[]);
+            return;
+        }
+
+        let connection = match self.upnp_connections.get_mut(&client_addr) {
+            Some(connection) => connection,
+            None => {
+                if flags.ack {
+                    let rst = TcpPacketFlags { rst: true, .. TcpPacketFlags::default() };
+                    self.send_http_segment(client_addr, packet.ack_number(), 0, rst, &[]);
+                }
+                return;
+            },
+        };
+        connection.last_active = Instant::now();
+        let data = packet.data();
+        if !data.is_empty() && seq_number == connection.next_ack_number {
+            if !connection.responded {
+                connection.request.extend(data);
+            }
+            connection.next_ack_number = connection.next_ack_number.wrapping_add(data.len() as u32);
+        }
+        let fin_in_sequence = seq_number.wrapping_add(data.len() as u32) == connection.next_ack_number;
+        if flags.fin && fin_in_sequence && !connection.fin_received {
+            connection.fin_received = true;
+            connection.next_ack_number = connection.next_ack_number.wrapping_add(1);
+        }
+        if connection.fin_received && connection.responded && flags.ack && packet.ack_number() == connection.next_seq_number {
+            self.upnp_connections.remove(&client_addr);
+            return;
+        }
+
+        let response_opt = if connection.responded {
+            None
+        } else if connection.request.len() > MAX_REQUEST_LEN {
+            Some(http_response("413 Payload Too Large", "text/plain", ""))
+        } else {
+            match HttpRequest::parse(&connection.request).map(|request_res| request_res.is_ok()) {
+                None => None,
+                Some(false) => Some(http_response("400 Bad Request", "text/plain", "")),
+                Some(true) => {
+                    let request = mem::take(&mut connection.request);
+                    Some(self.http_response(*client_addr.ip(), &request))
+                },
+            }
+        };
+        let connection = self.upnp_connections.get_mut(&client_addr).unwrap();
+        let ack_number = connection.next_ack_number;
+        let ack = TcpPacketFlags { ack: true, .. TcpPacketFlags::default() };
+        match response_opt {
+            Some(response) => {
+                connection.responded = true;
+                let mut seq_number = connection.next_seq_number;
+                connection.next_seq_number = {
+                    seq_number
+                    .wrapping_add(response.len() as u32)
+                    .wrapping_add(1)
+                };
+                for chunk in response.chunks(MAX_SEGMENT_LEN) {
+                    let psh_ack = TcpPacketFlags { psh: true, .. ack };
+                    self.send_http_segment(client_addr, seq_number, ack_number, psh_ack, chunk);
+                    seq_number = seq_number.wrapping_add(chunk.len() as u32);
+                }
+                let fin_ack = TcpPacketFlags { fin: true, .. ack };
+                self.send_http_segment(client_addr, seq_number, ack_number, fin_ack, &[]);
+            },
+            None => {
+                if !data.is_empty() || flags.fin {
+                    let seq_number = connection.next_seq_number;
+                    self.send_http_segment(client_addr, seq_number, ack_number, ack, &[]);
+                }
+            },
+        }
+    }
+
+    /// The earliest time at which a connection to the HTTP endpoint will have been idle for too
+    /// long.
+    pub(super) fn next_upnp_connection_expiry(&self) -> Option<Instant> {
+        self.upnp_connections.values().map(|connection| connection.last_active + IDLE_CONNECTION_TIMEOUT).min()
+    }
+
+    /// Forgets connections to the HTTP endpoint which have been idle for too long.
+    pub(super) fn expire_upnp_connections(&mut self, now: Instant) {
+        self.upnp_connections.retain(|_client_addr, connection| {
+            now.saturating_duration_since(connection.last_active) < IDLE_CONNECTION_TIMEOUT
+        });
+    }
+
+    /// Answers a request which has been fully received.
+    fn http_response(&mut self, client_ip: Ipv4Addr, request: &[u8]) -> Vec<u8> {
+        let request = HttpRequest::parse(request).unwrap().unwrap();
+        match (request.method, request.path) {
+            ("GET", DESCRIPTION_PATH) => {
+                let description = self.upnp_description();
+                http_response("200 OK", "text/xml; charset=\"utf-8\"", &description)
+            },
+            ("POST", CONTROL_PATH) => {
+                let action = {
+                    request
+                    .header("soapaction")
+                    .unwrap_or("")
+                    .trim_matches('"')
+                    .rsplit_once('#')
+                    .map(|(_service_type, action)| action)
+                    .unwrap_or("")
+                };
+                match self.soap_action(client_ip, action, request.body) {
+                    Ok(args) => soap_response(action, &args),
+                    Err(error) => soap_error(error),
+                }
+            },
+            _ => http_response("404 Not Found", "text/plain", ""),
+        }
+    }
+
+    fn upnp_description(&self) -> String {
+        format!(
+            "<?xml version=\"1.0\"?>\r\n\
+            <root xmlns=\"urn:schemas-upnp-org:device-1-0\">\
+            <specVersion><major>1</major><minor>0</minor></specVersion>\
+            <device>\
+            <deviceType>{GATEWAY_DEVICE_TYPE}</deviceType>\
+            <friendlyName>netsim NAT</friendlyName>\
+            <manufacturer>netsim</manufacturer>\
+            <modelName>netsim NAT</modelName>\
+            <UDN>{}</UDN>\
+            <deviceList><device>\
+            <deviceType>{WAN_DEVICE_TYPE}</deviceType>\
+            <friendlyName>WAN Device</friendlyName>\
+            <manufacturer>netsim</manufacturer>\
+            <modelName>netsim NAT</modelName>\
+            <UDN>{}</UDN>\
+            <deviceList><device>\
+            <deviceType>{WAN_CONNECTION_DEVICE_TYPE}</deviceType>\
+            <friendlyName>WAN Connection Device</friendlyName>\
+            <manufacturer>netsim</manufacturer>\
+            <modelName>netsim NAT</modelName>\
+            <UDN>{}</UDN>\
+            <serviceList><service>\
+            <serviceType>{WAN_IP_CONNECTION_TYPE}</serviceType>\
+            <serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>\
+            <SCPDURL>/WANIPCn.xml</SCPDURL>\
+            <controlURL>{CONTROL_PATH}</controlURL>\
+            <eventSubURL>/evt/IPConn</eventSubURL>\
+            </service></serviceList>\
+            </device></deviceList>\
+            </device></deviceList>\
+            <presentationURL>http://{}/</presentationURL>\
+            </device>\
+            </root>\r\n",
+            self.upnp_uuid(0), self.upnp_uuid(1), self.upnp_uuid(2), self.internal_ipv4,
+        )
+    }
+
+    fn soap_action(
+        &mut self,
+        client_ip: Ipv4Addr,
+        action: &str,
+        body: &str,
+    ) -> Result<Vec<(&'static str, String)>, UpnpError> {
+        match action {
+            "GetExternalIPAddress" => {
+                let external_ipv4 = self.external_ipv4_pool.host_ipv4(client_ip);
+                Ok(vec![("NewExternalIPAddress", external_ipv4.to_string())])
+            },
+            "AddPortMapping" => {
+                let remote_host = soap_arg(body, "NewRemoteHost").unwrap_or("");
+                if !remote_host.is_empty() {
+                    return Err(UpnpError::REMOTE_HOST_ONLY_SUPPORTS_WILDCARD);
+                }
+                let external_port = {
+                    soap_arg(body, "NewExternalPort")
+                    .and_then(|port| port.parse::<u16>().ok())
+                    .ok_or(UpnpError::INVALID_ARGS)?
+                };
+                if external_port == 0 {
+                    return Err(UpnpError::WILD_CARD_NOT_PERMITTED_IN_EXT_PORT);
+                }
+                let protocol = {
+                    soap_arg(body, "NewProtocol")
+                    .and_then(parse_protocol)
+                    .ok_or(UpnpError::INVALID_ARGS)?
+                };
+                let internal_port = {
+                    soap_arg(body, "NewInternalPort")
+                    .and_then(|port| port.parse::<u16>().ok())
+                    .filter(|port| *port != 0)
+                    .ok_or(UpnpError::INVALID_ARGS)?
+                };
+                let internal_ip = match soap_arg(body, "NewInternalClient") {
+                    None | Some("") => client_ip,
+                    Some(internal_ip) => internal_ip.parse::<Ipv4Addr>().map_err(|_| UpnpError::INVALID_ARGS)?,
+                };
+                if !self.internal_ipv4_network.contains(internal_ip) {
+                    return Err(UpnpError::INVALID_ARGS);
+                }
+                let lease_duration = match soap_arg(body, "NewLeaseDuration") {
+                    None | Some("") => 0,
+                    Some(lease_duration) => lease_duration.parse::<u32>().map_err(|_| UpnpError::INVALID_ARGS)?,
+                };
+                let expiry_opt = match lease_duration {
+                    0 => None,
+                    lease_duration => Some(Instant::now() + Duration::from_secs(u64::from(lease_duration))),
+                };
+                let internal_addr = SocketAddrV4::new(internal_ip, internal_port);
+                let external_addr = SocketAddrV4::new(self.external_ipv4_pool.host_ipv4(internal_ip), external_port);
+                let (port_map, _restrictions) = self.port_map_and_restrictions(protocol).unwrap();
+                let renewed = port_map.forwarded_addr(external_addr) == Some(internal_addr);
+                let evicted_opt = {
+                    port_map
+                    .forward_exactly(external_addr, internal_addr, expiry_opt)
+                    .ok_or(UpnpError::CONFLICT_IN_MAPPING_ENTRY)?
+                };
+                self.forget_evicted_mappings(protocol, evicted_opt);
+                debug!(
+                    "{}: mapped {} to {} through upnp",
+                    self.external_ipv4, external_addr, internal_addr,
+                );
+                if renewed {
+                    self.emit_mapping_refreshed(protocol, external_addr);
+                } else {
+                    self.emit_mapping_created(protocol, internal_addr, external_addr);
+                }
+                Ok(Vec::new())
+            },
+            "DeletePortMapping" => {
+                let external_port = {
+                    soap_arg(body, "NewExternalPort")
+                    .and_then(|port| port.parse::<u16>().ok())
+                    .ok_or(UpnpError::INVALID_ARGS)?
+                };
+                let protocol = {
+                    soap_arg(body, "NewProtocol")
+                    .and_then(parse_protocol)
+                    .ok_or(UpnpError::INVALID_ARGS)?
+                };
+                // Mappings are only ever deleted by the host they forward to, so the mapping is on
+                // the client's own external address.
+                let external_addr = SocketAddrV4::new(self.external_ipv4_pool.host_ipv4(client_ip), external_port);
+                let (port_map, restrictions) = self.port_map_and_restrictions(protocol).unwrap();
+                let internal_addr = {
+                    port_map
+                    .forwarded_addr(external_addr)
+                    .ok_or(UpnpError::NO_SUCH_ENTRY_IN_ARRAY)?
+                };
+                // Only the host a mapping forwards to may delete it.
+                if *internal_addr.ip() != client_ip {
+                    return Err(UpnpError::ACTION_NOT_AUTHORIZED);
+                }
+                port_map.unforward_port(external_addr).ok_or(UpnpError::NO_SUCH_ENTRY_IN_ARRAY)?;
+                restrictions.forget(external_addr);
+                if protocol == IpProtocol::Tcp {
+                    self.forget_tcp_state(external_addr);
+                }
+                debug!(
+                    "{}: unmapped {} from {} through upnp",
+                    self.external_ipv4, external_addr, internal_addr,
+                );
+                self.emit_mapping_removed(protocol, internal_addr, external_addr);
+                Ok(Vec::new())
+            },
+            _ => Err(UpnpError::INVALID_ACTION),
+        }
+    }
+}
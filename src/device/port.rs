@@ -0,0 +1,138 @@
+use crate::priv_prelude::*;
+
+/// A handle to an interface which has been inserted into a device, such as an
+/// [`IpHub`](crate::device::IpHub) or [`Nat`](crate::device::Nat).
+///
+/// Use [`remove`](crate::device::PortHandle::remove) to detach the interface from the device so
+/// that it can be inserted somewhere else, eg. to simulate a machine roaming between networks.
+/// Dropping the handle leaves the interface in place.
+pub struct PortHandle<S> {
+    shared: Arc<Mutex<PortShared<S>>>,
+}
+
+/// The interface as seen by the device. Once the interface has been removed through its
+/// [`PortHandle`] this acts like a closed `Sink`/`Stream` so that the device drops it.
+pub(crate) struct PortIface<S> {
+    shared: Arc<Mutex<PortShared<S>>>,
+}
+
+struct PortShared<S> {
+    iface_opt: Option<Pin<Box<S>>>,
+    attached: bool,
+    waker_opt: Option<task::Waker>,
+}
+
+/// Wraps `iface` so that it can be handed to a device, returning the wrapped interface along with
+/// a handle to it.
+pub(crate) fn new_port<S>(iface: S) -> (PortIface<S>, PortHandle<S>)
+where
+    S: IpSinkStream,
+{
+    let shared = Arc::new(Mutex::new(PortShared {
+        iface_opt: Some(Box::pin(iface)),
+        attached: true,
+        waker_opt: None,
+    }));
+    let port_iface = PortIface { shared: shared.clone() };
+    let port_handle = PortHandle { shared };
+    (port_iface, port_handle)
+}
+
+impl<S> PortHandle<S> {
+    /// Whether the interface is still attached to the device. This becomes `false` once the
+    /// interface has been removed, or if the device has stopped using it because the interface
+    /// closed or errored or the device itself was dropped.
+    pub fn is_live(&self) -> bool {
+        self.shared.lock().unwrap().attached
+    }
+
+    /// Detaches the interface from the device and gives it back. The returned interface can be
+    /// inserted into another device. Any packets which the device had queued for the interface
+    /// are dropped.
+    pub fn remove(self) -> Pin<Box<S>> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.attached = false;
+        if let Some(waker) = shared.waker_opt.take() {
+            waker.wake();
+        }
+        shared.iface_opt.take().unwrap()
+    }
+}
+
+impl<S> PortShared<S> {
+    /// Gets the interface, if it hasn't been removed, registering the device's task to be woken
+    /// if it is.
+    fn iface_mut(&mut self, cx: &mut task::Context) -> Option<Pin<&mut S>> {
+        let iface = self.iface_opt.as_mut()?;
+        match &self.waker_opt {
+            Some(waker) if waker.will_wake(cx.waker()) => (),
+            _ => self.waker_opt = Some(cx.waker().clone()),
+        }
+        Some(iface.as_mut())
+    }
+}
+
+fn removed_error() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "interface has been removed from the device")
+}
+
+impl<S> Drop for PortIface<S> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.attached = false;
+    }
+}
+
+impl<S> Stream for PortIface<S>
+where
+    S: IpSinkStream,
+{
+    type Item = io::Result<Box<IpPacket>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Option<io::Result<Box<IpPacket>>>> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.iface_mut(cx) {
+            Some(iface) => iface.poll_next(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl<S> Sink<Box<IpPacket>> for PortIface<S>
+where
+    S: IpSinkStream,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.iface_mut(cx) {
+            Some(iface) => iface.poll_ready(cx),
+            None => Poll::Ready(Err(removed_error())),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, packet: Box<IpPacket>) -> io::Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.iface_opt.as_mut() {
+            Some(iface) => iface.as_mut().start_send(packet),
+            None => Err(removed_error()),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.iface_mut(cx) {
+            Some(iface) => iface.poll_flush(cx),
+            None => Poll::Ready(Err(removed_error())),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.iface_mut(cx) {
+            Some(iface) => iface.poll_close(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use crate::{
+    priv_prelude::*,
+    device::{
+        iface_set::IfaceSet,
+        port::{self, PortHandle},
+    },
+    packet::{Icmpv4Packet, Icmpv6Packet},
+};
+
+/// A simple IP router.
+///
+/// Each interface inserted into the router is given a list of routes, ie. the address ranges
+/// which are reachable through it. Unlike an [`IpHub`](crate::device::IpHub), packets are only
+/// forwarded to the interface with the longest route matching their destination address, and have
+/// their TTL (IPv4) or hop limit (IPv6) decremented along the way.
+///
+/// Packets which have run out of hops are dropped and answered with an ICMP "time exceeded"
+/// error. Packets with no matching route are dropped and answered with an ICMP "net unreachable"
+/// error. Errors are sent from the router's own address and are routed back towards the packet's
+/// sender in the same way as any other packet. Packets addressed to the router itself are dropped.
+pub struct IpRouter {
+    iface_sender: mpsc::UnboundedSender<RouterPort>,
+}
+
+struct RouterPort {
+    iface: Pin<Box<dyn IpSinkStream>>,
+    routes: Vec<IpNetwork>,
+}
+
+struct IpRouterTask {
+    ipv4_addr: Ipv4Addr,
+    ipv6_addr: Ipv6Addr,
+    iface_receiver: mpsc::UnboundedReceiver<RouterPort>,
+    ifaces: IfaceSet,
+    /// The routes of each interface in `ifaces`.
+    routes: HashMap<usize, Vec<IpNetwork>>,
+}
+
+#[derive(Clone, Copy)]
+enum RouterError {
+    NetUnreachable,
+    TimeExceeded,
+}
+
+impl IpRouter {
+    /// Create a new `IpRouter`. Must be called within a `tokio` context.
+    ///
+    /// `ipv4_addr` and `ipv6_addr` are the router's own addresses. They're used as the source
+    /// address of any ICMP errors that the router sends.
+    pub fn new(ipv4_addr: Ipv4Addr, ipv6_addr: Ipv6Addr) -> IpRouter {
+        let (iface_sender, iface_receiver) = mpsc::unbounded();
+        let task = IpRouterTask {
+            ipv4_addr,
+            ipv6_addr,
+            iface_receiver,
+            ifaces: IfaceSet::new(),
+            routes: HashMap::new(),
+        };
+        tokio::spawn(task);
+        IpRouter { iface_sender }
+    }
+
+    /// Insert a `Sink`/`Stream` of IP packets into the router. Packets destined for any of the
+    /// address ranges in `routes` will be sent to this `Sink`, unless another interface has a
+    /// longer matching route.
+    ///
+    /// Use [`Ipv4Network::GLOBAL`](crate::Ipv4Network::GLOBAL) or
+    /// [`Ipv6Network::GLOBAL`](crate::Ipv6Network::GLOBAL) as a route to make this the router's
+    /// default route.
+    ///
+    /// Returns a [`PortHandle`](crate::device::PortHandle) which can be used to remove the
+    /// interface again.
+    pub fn insert_iface<S, R>(&mut self, iface: S, routes: R) -> PortHandle<S>
+    where
+        S: IpSinkStream,
+        R: IntoIterator,
+        R::Item: Into<IpNetwork>,
+    {
+        let (port_iface, port_handle) = port::new_port(iface);
+        let port = RouterPort {
+            iface: Box::pin(port_iface),
+            routes: routes.into_iter().map(Into::into).collect(),
+        };
+        self.iface_sender.unbounded_send(port).unwrap();
+        port_handle
+    }
+}
+
+impl IpRouterTask {
+    /// Forgets the routes of interfaces which have been removed.
+    fn forget_removed_ifaces(&mut self) {
+        for index in self.ifaces.take_removed() {
+            self.routes.remove(&index);
+        }
+    }
+
+    /// Finds the interface with the longest route matching `addr`. If several interfaces have
+    /// equally long routes, the one inserted last wins.
+    fn route(&self, addr: IpAddr) -> Option<usize> {
+        self.routes
+        .iter()
+        .flat_map(|(index, routes)| {
+            routes
+            .iter()
+            .filter(move |route| route.contains(addr))
+            .map(move |route| (route.subnet_mask_bits(), *index))
+        })
+        .max()
+        .map(|(_subnet_mask_bits, index)| index)
+    }
+
+    fn dispatch(&mut self, mut packet: Box<IpPacket>) {
+        self.forget_removed_ifaces();
+        let destination_addr = packet.destination_addr();
+        if destination_addr == IpAddr::V4(self.ipv4_addr) || destination_addr == IpAddr::V6(self.ipv6_addr) {
+            return;
+        }
+        let send_index = match self.route(destination_addr) {
+            Some(send_index) => send_index,
+            None => {
+                self.send_error(&packet, RouterError::NetUnreachable);
+                return;
+            },
+        };
+        let hops_remaining = match packet.version_mut() {
+            IpPacketVersion::V4(packet) => {
+                let ttl = packet.ttl().saturating_sub(1);
+                packet.set_ttl(ttl);
+                ttl
+            },
+            IpPacketVersion::V6(packet) => {
+                let hop_limit = packet.hop_limit().saturating_sub(1);
+                packet.set_hop_limit(hop_limit);
+                hop_limit
+            },
+        };
+        if hops_remaining == 0 {
+            self.send_error(&packet, RouterError::TimeExceeded);
+            return;
+        }
+        self.ifaces.start_send(send_index, packet);
+    }
+
+    fn send_error(&mut self, invoking_packet: &IpPacket, error: RouterError) {
+        let reply = match invoking_packet.version_ref() {
+            IpPacketVersion::V4(packet) => {
+                if let Ipv4PacketProtocol::Icmp(icmp_packet) = packet.protocol_ref() {
+                    if icmp_packet.is_error() {
+                        return;
+                    }
+                }
+                let reply = match error {
+                    RouterError::NetUnreachable => {
+                        Icmpv4Packet::new_destination_unreachable(self.ipv4_addr, 0, packet)
+                    },
+                    RouterError::TimeExceeded => Icmpv4Packet::new_time_exceeded(self.ipv4_addr, packet),
+                };
+                reply.ip_packet_box()
+            },
+            IpPacketVersion::V6(packet) => {
+                if let Ipv6PacketProtocol::Icmp(icmp_packet) = packet.protocol_ref() {
+                    if icmp_packet.is_error() {
+                        return;
+                    }
+                }
+                let reply = match error {
+                    RouterError::NetUnreachable => {
+                        Icmpv6Packet::new_destination_unreachable(self.ipv6_addr, 0, packet)
+                    },
+                    RouterError::TimeExceeded => Icmpv6Packet::new_time_exceeded(self.ipv6_addr, packet),
+                };
+                reply.ip_packet_box()
+            },
+        };
+        if let Some(send_index) = self.route(reply.destination_addr()) {
+            self.ifaces.start_send(send_index, reply);
+        }
+    }
+
+    fn poll_inner(&mut self, cx: &mut task::Context) -> Poll<()> {
+        loop {
+            match Pin::new(&mut self.iface_receiver).poll_next(cx) {
+                Poll::Ready(Some(port)) => {
+                    let index = self.ifaces.insert(port.iface);
+                    self.routes.insert(index, port.routes);
+                },
+                Poll::Ready(None) => {
+                    return Poll::Ready(());
+                },
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            match self.ifaces.poll_ready(cx) {
+                Poll::Ready(()) => (),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            let (recv_index, packet) = match self.ifaces.poll_next(cx) {
+                Poll::Ready((index, packet)) => (index, packet),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            if log_enabled!(Level::Debug) {
+                debug!("recieved on iface #{} {:?}", recv_index, packet);
+            }
+
+            self.dispatch(packet);
+        }
+    }
+}
+
+impl Future for IpRouterTask {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<()> {
+        let this = self.get_mut();
+        this.poll_inner(cx)
+    }
+}
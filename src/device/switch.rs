@@ -0,0 +1,181 @@
+use crate::{
+    priv_prelude::*,
+    device::{
+        iface_set::IfaceSet,
+        port::{self, PortHandle},
+    },
+};
+
+/// The default time after which an `IpSwitch` forgets which port an address lives on.
+const DEFAULT_ENTRY_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The smallest number of addresses an `IpSwitch` remembers before it starts pruning stale ones.
+const MIN_PRUNE_THRESHOLD: usize = 64;
+
+/// A learning IP network switch.
+///
+/// Like an [`IpHub`](crate::device::IpHub), any number of interfaces can be inserted into the
+/// switch. The switch remembers which interface each source address was last seen on, and packets
+/// destined for a known address are only forwarded to that interface. Packets destined for an
+/// unknown, broadcast or multicast address are forwarded to all interfaces other than the one
+/// they arrived on. Addresses which haven't been seen for a while are forgotten.
+pub struct IpSwitch {
+    iface_sender: mpsc::UnboundedSender<Pin<Box<dyn IpSinkStream>>>,
+}
+
+struct IpSwitchTask {
+    entry_timeout: Duration,
+    iface_receiver: mpsc::UnboundedReceiver<Pin<Box<dyn IpSinkStream>>>,
+    ifaces: IfaceSet,
+    addr_indexes: HashMap<IpAddr, (usize, Instant)>,
+    /// How large `addr_indexes` can grow before stale entries are pruned from it.
+    prune_threshold: usize,
+}
+
+impl IpSwitch {
+    /// Create a new `IpSwitch` which forgets addresses after five minutes of inactivity. Must be
+    /// called within a `tokio` context.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> IpSwitch {
+        IpSwitch::with_entry_timeout(DEFAULT_ENTRY_TIMEOUT)
+    }
+
+    /// Create a new `IpSwitch` which forgets which interface an address lives on once no packets
+    /// have been seen from that address for `entry_timeout`. Must be called within a `tokio`
+    /// context.
+    pub fn with_entry_timeout(entry_timeout: Duration) -> IpSwitch {
+        let (iface_sender, iface_receiver) = mpsc::unbounded();
+        let task = IpSwitchTask {
+            entry_timeout,
+            iface_receiver,
+            ifaces: IfaceSet::new(),
+            addr_indexes: HashMap::new(),
+            prune_threshold: MIN_PRUNE_THRESHOLD,
+        };
+        tokio::spawn(task);
+        IpSwitch { iface_sender }
+    }
+
+    /// Insert a `Sink`/`Stream` of IP packets into the switch.
+    ///
+    /// Returns a [`PortHandle`](crate::device::PortHandle) which can be used to remove the
+    /// interface again.
+    pub fn insert_iface<S>(&mut self, iface: S) -> PortHandle<S>
+    where
+        S: IpSinkStream,
+    {
+        let (port_iface, port_handle) = port::new_port(iface);
+        self.iface_sender.unbounded_send(Box::pin(port_iface)).unwrap();
+        port_handle
+    }
+}
+
+fn is_group_addr(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_multicast() || addr.is_broadcast(),
+        IpAddr::V6(addr) => addr.is_multicast(),
+    }
+}
+
+impl IpSwitchTask {
+    /// Forgets which addresses were seen on interfaces which have since been removed.
+    fn forget_removed_ifaces(&mut self) {
+        let removed_indexes = self.ifaces.take_removed();
+        if !removed_indexes.is_empty() {
+            self.addr_indexes.retain(|_addr, (index, _last_seen)| !removed_indexes.contains(index));
+        }
+    }
+
+    /// Forgets stale addresses once `addr_indexes` has grown past the prune threshold, so that
+    /// addresses which are only ever seen as sources don't accumulate.
+    fn prune_addr_indexes(&mut self, now: Instant) {
+        if self.addr_indexes.len() < self.prune_threshold {
+            return;
+        }
+        let entry_timeout = self.entry_timeout;
+        self.addr_indexes.retain(|_, (_index, last_seen)| now.saturating_duration_since(*last_seen) < entry_timeout);
+        self.prune_threshold = cmp::max(self.addr_indexes.len() * 2, MIN_PRUNE_THRESHOLD);
+    }
+
+    /// Looks up which interface `addr` was last seen on, forgetting it if it's gone stale.
+    fn learned_index(&mut self, addr: IpAddr, now: Instant) -> Option<usize> {
+        let (index, last_seen) = *self.addr_indexes.get(&addr)?;
+        if now.saturating_duration_since(last_seen) >= self.entry_timeout {
+            self.addr_indexes.remove(&addr);
+            return None;
+        }
+        Some(index)
+    }
+
+    fn dispatch(&mut self, recv_index: usize, packet: Box<IpPacket>) {
+        self.forget_removed_ifaces();
+        let now = Instant::now();
+        let source_addr = packet.source_addr();
+        if !source_addr.is_unspecified() && !is_group_addr(source_addr) {
+            let previous_opt = self.addr_indexes.insert(source_addr, (recv_index, now));
+            if previous_opt.is_none() {
+                self.prune_addr_indexes(now);
+            }
+        }
+
+        let destination_addr = packet.destination_addr();
+        let learned_index_opt = if is_group_addr(destination_addr) {
+            None
+        } else {
+            self.learned_index(destination_addr, now)
+        };
+        match learned_index_opt {
+            Some(send_index) => {
+                if send_index != recv_index {
+                    self.ifaces.start_send(send_index, packet);
+                }
+            },
+            None => {
+                for send_index in self.ifaces.indexes() {
+                    if send_index != recv_index {
+                        self.ifaces.start_send(send_index, packet.clone());
+                    }
+                }
+            },
+        }
+    }
+
+    fn poll_inner(&mut self, cx: &mut task::Context) -> Poll<()> {
+        loop {
+            match Pin::new(&mut self.iface_receiver).poll_next(cx) {
+                Poll::Ready(Some(iface)) => {
+                    self.ifaces.insert(iface);
+                },
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            match self.ifaces.poll_ready(cx) {
+                Poll::Ready(()) => (),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            let (recv_index, packet) = match self.ifaces.poll_next(cx) {
+                Poll::Ready((index, packet)) => (index, packet),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            if log_enabled!(Level::Debug) {
+                debug!("recieved on iface #{} {:?}", recv_index, packet);
+            }
+
+            self.dispatch(recv_index, packet);
+        }
+    }
+}
+
+impl Future for IpSwitchTask {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<()> {
+        let this = self.get_mut();
+        this.poll_inner(cx)
+    }
+}
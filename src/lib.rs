@@ -103,7 +103,7 @@ pub use {
         stream::{IpIface, IpSinkStream},
     },
     connect::connect,
-    network::{Ipv4Network, Ipv6Network, NetworkParseError, Ipv4NetworkIter, Ipv6NetworkIter},
+    network::{IpNetwork, Ipv4Network, Ipv6Network, NetworkParseError, Ipv4NetworkIter, Ipv6NetworkIter},
     netsim_macros::{ipv4_network, ipv6_network, isolate},
     stream_ext::SinkStreamExt,
     tokio,
@@ -64,6 +64,13 @@ pub struct Ipv6Network {
     subnet_mask_bits: u8,
 }
 
+/// An IPv4 or IPv6 address range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpNetwork {
+    V4(Ipv4Network),
+    V6(Ipv6Network),
+}
+
 impl Ipv4Network {
     /// The global network containing the entire IPv4 address range.
     pub const GLOBAL: Ipv4Network = ipv4_network!("0.0.0.0/0");
@@ -248,6 +255,47 @@ impl Ipv6Network {
     }
 }
 
+impl IpNetwork {
+    /// Checks whether this range contains the given IP address. An IPv4 range never contains an
+    /// IPv6 address, and vice versa.
+    pub fn contains(self, addr: IpAddr) -> bool {
+        match (self, addr) {
+            (IpNetwork::V4(network), IpAddr::V4(addr)) => network.contains(addr),
+            (IpNetwork::V6(network), IpAddr::V6(addr)) => network.contains(addr),
+            _ => false,
+        }
+    }
+
+    /// The length of the subnet mask.
+    pub fn subnet_mask_bits(self) -> u8 {
+        match self {
+            IpNetwork::V4(network) => network.subnet_mask_bits(),
+            IpNetwork::V6(network) => network.subnet_mask_bits(),
+        }
+    }
+}
+
+impl From<Ipv4Network> for IpNetwork {
+    fn from(network: Ipv4Network) -> IpNetwork {
+        IpNetwork::V4(network)
+    }
+}
+
+impl From<Ipv6Network> for IpNetwork {
+    fn from(network: Ipv6Network) -> IpNetwork {
+        IpNetwork::V6(network)
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpNetwork::V4(network) => fmt::Display::fmt(network, f),
+            IpNetwork::V6(network) => fmt::Display::fmt(network, f),
+        }
+    }
+}
+
 impl fmt::Debug for Ipv4Network {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "ipv4_network!({:?})", self.to_string())
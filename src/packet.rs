@@ -151,6 +151,114 @@ mod protocol_numbers {
     pub const ICMP_V6: u8 = 58;
 }
 
+mod icmpv4_types {
+    pub const ECHO_REPLY: u8 = 0;
+    pub const DESTINATION_UNREACHABLE: u8 = 3;
+    pub const SOURCE_QUENCH: u8 = 4;
+    pub const REDIRECT: u8 = 5;
+    pub const ECHO_REQUEST: u8 = 8;
+    pub const TIME_EXCEEDED: u8 = 11;
+    pub const PARAMETER_PROBLEM: u8 = 12;
+}
+
+mod tcp_option_kinds {
+    pub const END_OF_OPTIONS: u8 = 0;
+    pub const NOP: u8 = 1;
+    pub const MAXIMUM_SEGMENT_SIZE: u8 = 2;
+}
+
+mod icmpv6_types {
+    pub const DESTINATION_UNREACHABLE: u8 = 1;
+    pub const PACKET_TOO_BIG: u8 = 2;
+    pub const TIME_EXCEEDED: u8 = 3;
+}
+
+fn new_ipv4_data(
+    protocol_number: u8,
+    source_addr: Ipv4Addr,
+    destination_addr: Ipv4Addr,
+    payload_len: usize,
+) -> Vec<u8> {
+    let mut data = Vec::with_capacity(20 + payload_len);
+    data.push((4u8 << 4) | 5u8);
+    data.push(0);
+    data.extend(((20 + payload_len) as u16).to_be_bytes());
+
+    data.extend(0u16.to_be_bytes());
+    data.push(0x40);
+    data.push(0);
+
+    data.push(64);
+    data.push(protocol_number);
+    data.extend([0, 0]);
+
+    data.extend(source_addr.octets());
+    data.extend(destination_addr.octets());
+    data
+}
+
+fn new_ipv6_data(
+    protocol_number: u8,
+    source_addr: Ipv6Addr,
+    destination_addr: Ipv6Addr,
+    payload_len: usize,
+) -> Vec<u8> {
+    let mut data = Vec::with_capacity(40 + payload_len);
+    data.push(6u8 << 4);
+    data.extend([0; 3]);
+
+    data.extend((payload_len as u16).to_be_bytes());
+    data.push(protocol_number);
+    data.push(64);
+
+    data.extend(source_addr.octets());
+    data.extend(destination_addr.octets());
+    data
+}
+
+/// The ECN (explicit congestion notification) codepoint of an IP packet, as defined by RFC 3168.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum EcnCodepoint {
+    /// Not ECN-capable transport.
+    NotEct,
+    /// ECN-capable transport, codepoint 1. Used by L4S.
+    Ect1,
+    /// ECN-capable transport, codepoint 0.
+    Ect0,
+    /// Congestion experienced.
+    Ce,
+}
+
+impl EcnCodepoint {
+    fn from_bits(bits: u8) -> EcnCodepoint {
+        match bits & 0b11 {
+            0b00 => EcnCodepoint::NotEct,
+            0b01 => EcnCodepoint::Ect1,
+            0b10 => EcnCodepoint::Ect0,
+            _ => EcnCodepoint::Ce,
+        }
+    }
+
+    fn to_bits(self) -> u8 {
+        match self {
+            EcnCodepoint::NotEct => 0b00,
+            EcnCodepoint::Ect1 => 0b01,
+            EcnCodepoint::Ect0 => 0b10,
+            EcnCodepoint::Ce => 0b11,
+        }
+    }
+}
+
+/// A transport-layer protocol carried by an IP packet.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IpProtocol {
+    Tcp,
+    Udp,
+    /// ICMP for IPv4 packets, ICMPv6 for IPv6 packets.
+    Icmp,
+    Other(u8),
+}
+
 #[derive(Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Default)]
 pub struct TcpPacketFlags {
     pub cwr: bool,
@@ -163,6 +271,40 @@ pub struct TcpPacketFlags {
     pub fin: bool,
 }
 
+impl TcpPacketFlags {
+    fn from_byte(flags: u8) -> TcpPacketFlags {
+        TcpPacketFlags {
+            cwr: bit!(flags, 7),
+            ece: bit!(flags, 6),
+            urg: bit!(flags, 5),
+            ack: bit!(flags, 4),
+            psh: bit!(flags, 3),
+            rst: bit!(flags, 2),
+            syn: bit!(flags, 1),
+            fin: bit!(flags, 0),
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        let TcpPacketFlags { cwr, ece, urg, ack, psh, rst, syn, fin } = self;
+        let mut byte = 0;
+        set_bit!(&mut byte, cwr, 7);
+        set_bit!(&mut byte, ece, 6);
+        set_bit!(&mut byte, urg, 5);
+        set_bit!(&mut byte, ack, 4);
+        set_bit!(&mut byte, psh, 3);
+        set_bit!(&mut byte, rst, 2);
+        set_bit!(&mut byte, syn, 1);
+        set_bit!(&mut byte, fin, 0);
+        byte
+    }
+
+    /// Checks whether these flags, masked with `mask`, equal `flags`.
+    pub fn masked_eq(self, mask: TcpPacketFlags, flags: TcpPacketFlags) -> bool {
+        self.to_byte() & mask.to_byte() == flags.to_byte()
+    }
+}
+
 impl fmt::Debug for TcpPacketFlags {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         let mut written = false;
@@ -296,6 +438,69 @@ impl IpPacket {
             IpPacketVersion::V6(packet) => IpAddr::V6(packet.destination_addr()),
         }
     }
+
+    pub fn protocol(&self) -> IpProtocol {
+        let protocol_number = match self.version_ref() {
+            IpPacketVersion::V4(packet) => packet.data[9],
+            IpPacketVersion::V6(packet) => packet.protocol_number(),
+        };
+        match (self.version_ref(), protocol_number) {
+            (_, protocol_numbers::TCP) => IpProtocol::Tcp,
+            (_, protocol_numbers::UDP) => IpProtocol::Udp,
+            (IpPacketVersion::V4(_), protocol_numbers::ICMP_V4) => IpProtocol::Icmp,
+            (IpPacketVersion::V6(_), protocol_numbers::ICMP_V6) => IpProtocol::Icmp,
+            (_, protocol_number) => IpProtocol::Other(protocol_number),
+        }
+    }
+
+    /// The source and destination ports of a TCP or UDP packet.
+    pub fn ports(&self) -> Option<(u16, u16)> {
+        match self.protocol() {
+            IpProtocol::Tcp | IpProtocol::Udp => (),
+            _ => return None,
+        }
+        let header_len = self.transport_header_offset();
+        let ports = self.data.get(header_len..(header_len + 4))?;
+        let source_port = u16::from_be_bytes([ports[0], ports[1]]);
+        let destination_port = u16::from_be_bytes([ports[2], ports[3]]);
+        Some((source_port, destination_port))
+    }
+
+    /// The flags of a TCP packet.
+    pub fn tcp_flags(&self) -> Option<TcpPacketFlags> {
+        if self.protocol() != IpProtocol::Tcp {
+            return None;
+        }
+        let flags = *self.data.get(self.transport_header_offset() + 13)?;
+        Some(TcpPacketFlags::from_byte(flags))
+    }
+
+    pub fn ecn(&self) -> EcnCodepoint {
+        match self.version_ref() {
+            IpPacketVersion::V4(packet) => packet.ecn(),
+            IpPacketVersion::V6(packet) => packet.ecn(),
+        }
+    }
+
+    /// Sets the ECN codepoint. For IPv4 packets the header checksum is updated.
+    pub fn set_ecn(&mut self, ecn: EcnCodepoint) {
+        match self.version_mut() {
+            IpPacketVersion::V4(packet) => packet.set_ecn(ecn),
+            IpPacketVersion::V6(packet) => packet.set_ecn(ecn),
+        }
+    }
+
+    pub(crate) fn as_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.data[..]
+    }
+
+    /// The offset of the transport-layer header, after all IP headers.
+    pub(crate) fn transport_header_offset(&self) -> usize {
+        match self.version_ref() {
+            IpPacketVersion::V4(packet) => packet.ipv4_header_len(),
+            IpPacketVersion::V6(packet) => packet.upper_layer_header_len(),
+        }
+    }
 }
 
 pub enum Ipv4PacketProtocol<P>
@@ -375,6 +580,38 @@ impl Ipv4Packet {
         (self.data[0] & 0x0f) as usize * 4
     }
 
+    /// Whether the "don't fragment" flag is set.
+    pub fn dont_fragment(&self) -> bool {
+        bit!(self.data[6], 6)
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.data[8]
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.data[8] = ttl;
+        self.fix_checksum();
+    }
+
+    pub fn identification(&self) -> u16 {
+        u16::from_be_bytes(slice!(&self.data, 4..6))
+    }
+
+    pub fn set_identification(&mut self, identification: u16) {
+        *slice_mut!(self.data, 4..6) = identification.to_be_bytes();
+        self.fix_checksum();
+    }
+
+    pub fn ecn(&self) -> EcnCodepoint {
+        EcnCodepoint::from_bits(self.data[1])
+    }
+
+    pub fn set_ecn(&mut self, ecn: EcnCodepoint) {
+        self.data[1] = (self.data[1] & !0b11) | ecn.to_bits();
+        self.fix_checksum();
+    }
+
     fn fix_checksum(&mut self) {
         let mut hasher = Ipv4Hasher::new();
         let header_len = self.ipv4_header_len();
@@ -433,6 +670,14 @@ impl Ipv6Packet {
     }
 
     fn protocol_number(&self) -> u8 {
+        self.upper_layer_header().0
+    }
+
+    fn upper_layer_header_len(&self) -> usize {
+        self.upper_layer_header().1
+    }
+
+    fn upper_layer_header(&self) -> (u8, usize) {
         let mut header_position = 0;
         let mut next_header_position = 40;
         let mut offset = 6;
@@ -456,7 +701,7 @@ impl Ipv6Packet {
                     next_header_position += 2 + 4 * self.data[header_position + 1] as usize;
                     offset = 0;
                 },
-                protocol_number => break protocol_number,
+                protocol_number => break (protocol_number, header_position),
             }
         }
     }
@@ -470,6 +715,22 @@ impl Ipv6Packet {
         let addr = slice!(&self.data, 24..40);
         Ipv6Addr::from(addr)
     }
+
+    pub fn hop_limit(&self) -> u8 {
+        self.data[7]
+    }
+
+    pub fn set_hop_limit(&mut self, hop_limit: u8) {
+        self.data[7] = hop_limit;
+    }
+
+    pub fn ecn(&self) -> EcnCodepoint {
+        EcnCodepoint::from_bits(self.data[1] >> 4)
+    }
+
+    pub fn set_ecn(&mut self, ecn: EcnCodepoint) {
+        self.data[1] = (self.data[1] & !0b0011_0000) | (ecn.to_bits() << 4);
+    }
 }
 
 impl Tcpv4Packet {
@@ -517,33 +778,108 @@ impl Tcpv4Packet {
         u32::from_be_bytes(ack_bytes)
     }
 
+    pub fn tcp_header_len(&self) -> usize {
+        let header_len = self.ipv4_packet_ref().ipv4_header_len();
+        (self.data[header_len + 12] >> 4) as usize * 4
+    }
+
+    pub fn data(&self) -> &[u8] {
+        let header_len = self.ipv4_packet_ref().ipv4_header_len();
+        let full_header_len = header_len + self.tcp_header_len();
+        &self.data[full_header_len..]
+    }
+
     pub fn flags(&self) -> TcpPacketFlags {
         let header_len = self.ipv4_packet_ref().ipv4_header_len();
-        let flags = self.data[header_len + 13];
-        TcpPacketFlags {
-            cwr: bit!(flags, 7),
-            ece: bit!(flags, 6),
-            urg: bit!(flags, 5),
-            ack: bit!(flags, 4),
-            psh: bit!(flags, 3),
-            rst: bit!(flags, 2),
-            syn: bit!(flags, 1),
-            fin: bit!(flags, 0),
+        TcpPacketFlags::from_byte(self.data[header_len + 13])
+    }
+
+    pub fn window_size(&self) -> u16 {
+        let header_len = self.ipv4_packet_ref().ipv4_header_len();
+        u16::from_be_bytes(slice!(&self.data[header_len..], 14..16))
+    }
+
+    pub fn set_window_size(&mut self, window_size: u16) {
+        let header_len = self.ipv4_packet_ref().ipv4_header_len();
+        *slice_mut!(&mut self.data[header_len..], 14..16) = window_size.to_be_bytes();
+        self.fix_checksum();
+    }
+
+    /// The kind, offset and length of each option in the TCP header. Parsing stops at the end of
+    /// options list option or at the first malformed option.
+    fn option_spans(&self) -> Vec<(u8, usize, usize)> {
+        let header_len = self.ipv4_packet_ref().ipv4_header_len();
+        let options_end = cmp::min(header_len + self.tcp_header_len(), self.data.len());
+        let mut spans = Vec::new();
+        let mut offset = header_len + 20;
+        while offset < options_end {
+            let kind = self.data[offset];
+            let len = match kind {
+                tcp_option_kinds::END_OF_OPTIONS => break,
+                tcp_option_kinds::NOP => 1,
+                _ => {
+                    let Some(&len) = self.data.get(offset + 1) else { break };
+                    let len = len as usize;
+                    if len < 2 || offset + len > options_end {
+                        break;
+                    }
+                    len
+                },
+            };
+            spans.push((kind, offset, len));
+            offset += len;
+        }
+        spans
+    }
+
+    /// The kinds of the options in the TCP header, in order.
+    pub fn option_kinds(&self) -> Vec<u8> {
+        self.option_spans().into_iter().map(|(kind, _offset, _len)| kind).collect()
+    }
+
+    /// Replaces every TCP option whose kind doesn't satisfy `retain` with NOP options. The length
+    /// of the TCP header is left unchanged.
+    pub fn retain_options<F>(&mut self, mut retain: F)
+    where
+        F: FnMut(u8) -> bool,
+    {
+        let mut changed = false;
+        for (kind, offset, len) in self.option_spans() {
+            if kind != tcp_option_kinds::NOP && !retain(kind) {
+                self.data[offset..(offset + len)].fill(tcp_option_kinds::NOP);
+                changed = true;
+            }
+        }
+        if changed {
+            self.fix_checksum();
+        }
+    }
+
+    /// The value of the maximum segment size option, if present.
+    pub fn mss(&self) -> Option<u16> {
+        self.option_spans().into_iter().find_map(|(kind, offset, len)| {
+            if kind != tcp_option_kinds::MAXIMUM_SEGMENT_SIZE || len != 4 {
+                return None;
+            }
+            Some(u16::from_be_bytes(slice!(&self.data[offset..], 2..4)))
+        })
+    }
+
+    /// Sets the value of the maximum segment size option. Does nothing if the packet doesn't
+    /// have the option.
+    pub fn set_mss(&mut self, mss: u16) {
+        let spans = self.option_spans();
+        for (kind, offset, len) in spans {
+            if kind == tcp_option_kinds::MAXIMUM_SEGMENT_SIZE && len == 4 {
+                *slice_mut!(&mut self.data[offset..], 2..4) = mss.to_be_bytes();
+                self.fix_checksum();
+            }
         }
     }
 
     pub fn set_flags(&mut self, flags: TcpPacketFlags) {
         let header_len = self.ipv4_packet_ref().ipv4_header_len();
-        let TcpPacketFlags { cwr, ece, urg, ack, psh, rst, syn, fin } = flags;
-        let byte = &mut self.data[header_len + 13];
-        set_bit!(byte, cwr, 7);
-        set_bit!(byte, ece, 6);
-        set_bit!(byte, urg, 5);
-        set_bit!(byte, ack, 4);
-        set_bit!(byte, psh, 3);
-        set_bit!(byte, rst, 2);
-        set_bit!(byte, syn, 1);
-        set_bit!(byte, fin, 0);
+        self.data[header_len + 13] = flags.to_byte();
         self.fix_checksum();
     }
 
@@ -605,10 +941,15 @@ impl Tcpv4Packet {
     }
 
     pub fn new() -> Box<Tcpv4Packet> {
-        let mut data = Vec::with_capacity(40);
+        Tcpv4Packet::new_with_data(&[])
+    }
+
+    /// Creates a TCP packet carrying `payload`, with all the header fields zeroed.
+    pub fn new_with_data(payload: &[u8]) -> Box<Tcpv4Packet> {
+        let mut data = Vec::with_capacity(40 + payload.len());
         data.push((4u8 << 4) | 5u8);
         data.push(0);
-        data.extend(40u16.to_be_bytes());
+        data.extend(((40 + payload.len()) as u16).to_be_bytes());
 
         data.extend(0u16.to_be_bytes());
         data.push(0x40);
@@ -632,6 +973,7 @@ impl Tcpv4Packet {
 
         data.extend([0; 2]);
         data.extend([0; 2]);
+        data.extend(payload);
 
         let ret: Box<[u8]> = data.into();
         let mut ret: Box<Tcpv4Packet> = unsafe { mem::transmute(ret) };
@@ -641,6 +983,27 @@ impl Tcpv4Packet {
 }
 
 impl Udpv4Packet {
+    /// Creates a UDP packet carrying `data`.
+    pub fn new(source_addr: SocketAddrV4, destination_addr: SocketAddrV4, data: &[u8]) -> Box<Udpv4Packet> {
+        let mut packet_data = new_ipv4_data(
+            protocol_numbers::UDP,
+            *source_addr.ip(),
+            *destination_addr.ip(),
+            8 + data.len(),
+        );
+        packet_data.extend(source_addr.port().to_be_bytes());
+        packet_data.extend(destination_addr.port().to_be_bytes());
+        packet_data.extend(((8 + data.len()) as u16).to_be_bytes());
+        packet_data.extend([0, 0]);
+        packet_data.extend(data);
+
+        let ret: Box<[u8]> = packet_data.into();
+        let mut ret: Box<Udpv4Packet> = unsafe { mem::transmute(ret) };
+        ret.ipv4_packet_mut().fix_checksum();
+        ret.fix_checksum();
+        ret
+    }
+
     pub fn source_ip_addr(&self) -> Ipv4Addr {
         self.ipv4_packet_ref().source_addr()
     }
@@ -724,7 +1087,89 @@ impl Udpv4Packet {
     }
 }
 
+/// Applies the change from `old` to `new` to a one's complement checksum, as described in RFC
+/// 1624. `old` and `new` must be the same, even, length.
+fn update_checksum(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    debug_assert_eq!(old.len(), new.len());
+    let mut sum = u32::from(!checksum);
+    for (old_word, new_word) in old.chunks(2).zip(new.chunks(2)) {
+        sum += u32::from(!u16::from_be_bytes([old_word[0], old_word[1]]));
+        sum += u32::from(u16::from_be_bytes([new_word[0], new_word[1]]));
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
 impl Icmpv4Packet {
+    /// The maximum size of an ICMPv4 error packet, including the IPv4 header.
+    const MAX_ERROR_LEN: usize = 576;
+
+    /// Creates an echo request ("ping") packet.
+    pub fn new_echo_request(
+        source_addr: Ipv4Addr,
+        destination_addr: Ipv4Addr,
+        identifier: u16,
+        sequence_number: u16,
+        data: &[u8],
+    ) -> Box<Icmpv4Packet> {
+        Icmpv4Packet::new_echo(
+            icmpv4_types::ECHO_REQUEST,
+            source_addr,
+            destination_addr,
+            identifier,
+            sequence_number,
+            data,
+        )
+    }
+
+    /// Creates an echo reply packet.
+    pub fn new_echo_reply(
+        source_addr: Ipv4Addr,
+        destination_addr: Ipv4Addr,
+        identifier: u16,
+        sequence_number: u16,
+        data: &[u8],
+    ) -> Box<Icmpv4Packet> {
+        Icmpv4Packet::new_echo(
+            icmpv4_types::ECHO_REPLY,
+            source_addr,
+            destination_addr,
+            identifier,
+            sequence_number,
+            data,
+        )
+    }
+
+    fn new_echo(
+        icmp_type: u8,
+        source_addr: Ipv4Addr,
+        destination_addr: Ipv4Addr,
+        identifier: u16,
+        sequence_number: u16,
+        data: &[u8],
+    ) -> Box<Icmpv4Packet> {
+        let mut packet_data = new_ipv4_data(
+            protocol_numbers::ICMP_V4,
+            source_addr,
+            destination_addr,
+            8 + data.len(),
+        );
+        packet_data.push(icmp_type);
+        packet_data.push(0);
+        packet_data.extend([0, 0]);
+        packet_data.extend(identifier.to_be_bytes());
+        packet_data.extend(sequence_number.to_be_bytes());
+        packet_data.extend(data);
+
+        let ret: Box<[u8]> = packet_data.into();
+        let mut ret: Box<Icmpv4Packet> = unsafe { mem::transmute(ret) };
+        ret.ipv4_packet_mut().fix_checksum();
+        ret.fix_checksum();
+        ret
+    }
+
     pub fn source_addr(&self) -> Ipv4Addr {
         self.ipv4_packet_ref().source_addr()
     }
@@ -732,9 +1177,277 @@ impl Icmpv4Packet {
     pub fn destination_addr(&self) -> Ipv4Addr {
         self.ipv4_packet_ref().destination_addr()
     }
+
+    pub fn set_source_addr(&mut self, addr: Ipv4Addr) {
+        self.ipv4_packet_mut().set_source_addr(addr);
+    }
+
+    pub fn set_destination_addr(&mut self, addr: Ipv4Addr) {
+        self.ipv4_packet_mut().set_destination_addr(addr);
+    }
+
+    pub fn is_echo_request(&self) -> bool {
+        self.icmp_type() == icmpv4_types::ECHO_REQUEST
+    }
+
+    pub fn is_echo_reply(&self) -> bool {
+        self.icmp_type() == icmpv4_types::ECHO_REPLY
+    }
+
+    /// The identifier of an echo request or reply.
+    pub fn echo_identifier(&self) -> Option<u16> {
+        if !self.is_echo_request() && !self.is_echo_reply() {
+            return None;
+        }
+        let header_len = self.ipv4_packet_ref().ipv4_header_len();
+        let identifier = self.data.get((header_len + 4)..(header_len + 6))?;
+        Some(u16::from_be_bytes([identifier[0], identifier[1]]))
+    }
+
+    /// Sets the identifier of an echo request or reply. Does nothing for other message types.
+    pub fn set_echo_identifier(&mut self, identifier: u16) {
+        if self.echo_identifier().is_none() {
+            return;
+        }
+        let header_len = self.ipv4_packet_ref().ipv4_header_len();
+        self.data[(header_len + 4)..(header_len + 6)].copy_from_slice(&identifier.to_be_bytes());
+        self.fix_checksum();
+    }
+
+    /// The offset and length of the IPv4 header of the packet quoted in an error message, if it
+    /// has been quoted along with at least the first 8 bytes of its payload.
+    fn quoted_header_span(&self) -> Option<(usize, usize)> {
+        if !self.is_error() {
+            return None;
+        }
+        let start = self.ipv4_packet_ref().ipv4_header_len() + 8;
+        let first_byte = *self.data.get(start)?;
+        if first_byte >> 4 != 4 {
+            return None;
+        }
+        let header_len = 4 * usize::from(first_byte & 0x0f);
+        if header_len < 20 || self.data.len() < start + header_len + 8 {
+            return None;
+        }
+        Some((start, header_len))
+    }
+
+    /// The protocol, source address and destination address of the TCP, UDP or ICMP echo packet
+    /// quoted in an error message. For ICMP echo packets the echo identifier is used as the source
+    /// port and the destination port is zero.
+    pub(crate) fn quoted_addrs(&self) -> Option<(IpProtocol, SocketAddrV4, SocketAddrV4)> {
+        let (start, header_len) = self.quoted_header_span()?;
+        let header = &self.data[start..];
+        let transport = &header[header_len..];
+        let source_ip = Ipv4Addr::from(slice!(header, 12..16));
+        let destination_ip = Ipv4Addr::from(slice!(header, 16..20));
+        let (protocol, source_port, destination_port) = match header[9] {
+            protocol_numbers::TCP => {
+                (IpProtocol::Tcp, u16::from_be_bytes(slice!(transport, 0..2)), u16::from_be_bytes(slice!(transport, 2..4)))
+            },
+            protocol_numbers::UDP => {
+                (IpProtocol::Udp, u16::from_be_bytes(slice!(transport, 0..2)), u16::from_be_bytes(slice!(transport, 2..4)))
+            },
+            protocol_numbers::ICMP_V4 => {
+                if transport[0] != icmpv4_types::ECHO_REQUEST && transport[0] != icmpv4_types::ECHO_REPLY {
+                    return None;
+                }
+                (IpProtocol::Icmp, u16::from_be_bytes(slice!(transport, 4..6)), 0)
+            },
+            _ => return None,
+        };
+        Some((
+            protocol,
+            SocketAddrV4::new(source_ip, source_port),
+            SocketAddrV4::new(destination_ip, destination_port),
+        ))
+    }
+
+    /// Rewrites the source address of the packet quoted in an error message, as a NAT does when
+    /// translating ICMP errors (RFC 5508). See [`quoted_addrs`](Icmpv4Packet::quoted_addrs) for
+    /// how ICMP echo packets are handled.
+    pub(crate) fn set_quoted_source_addr(&mut self, addr: SocketAddrV4) {
+        self.set_quoted_addr(true, addr);
+    }
+
+    /// Rewrites the destination address of the packet quoted in an error message. See
+    /// [`set_quoted_source_addr`](Icmpv4Packet::set_quoted_source_addr).
+    pub(crate) fn set_quoted_destination_addr(&mut self, addr: SocketAddrV4) {
+        self.set_quoted_addr(false, addr);
+    }
+
+    fn set_quoted_addr(&mut self, is_source: bool, addr: SocketAddrV4) {
+        let Some((protocol, _, _)) = self.quoted_addrs() else { return };
+        let (start, header_len) = self.quoted_header_span().unwrap();
+        let transport_start = start + header_len;
+        let ip_offset = if is_source { 12 } else { 16 };
+        let (port_offset_opt, checksum_offset) = match (protocol, is_source) {
+            (IpProtocol::Tcp, true) => (Some(0), 16),
+            (IpProtocol::Tcp, false) => (Some(2), 16),
+            (IpProtocol::Udp, true) => (Some(0), 6),
+            (IpProtocol::Udp, false) => (Some(2), 6),
+            (_, true) => (Some(4), 2),
+            (_, false) => (None, 2),
+        };
+
+        // The quoted transport checksum covers the port and, for TCP and UDP, the IP addresses
+        // through the pseudo-header. It's updated incrementally since the quoted packet is
+        // usually truncated.
+        let mut old_bytes = Vec::new();
+        let mut new_bytes = Vec::new();
+        let ip_range = (start + ip_offset)..(start + ip_offset + 4);
+        if protocol != IpProtocol::Icmp {
+            old_bytes.extend(&self.data[ip_range.clone()]);
+            new_bytes.extend(addr.ip().octets());
+        }
+        self.data[ip_range].copy_from_slice(&addr.ip().octets());
+        if let Some(port_offset) = port_offset_opt {
+            let port_range = (transport_start + port_offset)..(transport_start + port_offset + 2);
+            old_bytes.extend(&self.data[port_range.clone()]);
+            new_bytes.extend(addr.port().to_be_bytes());
+            self.data[port_range].copy_from_slice(&addr.port().to_be_bytes());
+        }
+        let checksum_range = (transport_start + checksum_offset)..(transport_start + checksum_offset + 2);
+        if let Some(checksum_bytes) = self.data.get(checksum_range.clone()) {
+            let checksum = u16::from_be_bytes([checksum_bytes[0], checksum_bytes[1]]);
+            // A zero UDP checksum means that no checksum was computed.
+            if !(protocol == IpProtocol::Udp && checksum == 0) {
+                let mut checksum = update_checksum(checksum, &old_bytes, &new_bytes);
+                if protocol == IpProtocol::Udp && checksum == 0 {
+                    checksum = 0xffff;
+                }
+                self.data[checksum_range].copy_from_slice(&checksum.to_be_bytes());
+            }
+        }
+
+        let mut hasher = Ipv4Hasher::new();
+        let mut i = start;
+        while i < start + header_len {
+            if i != start + 10 {
+                hasher.write_u16(u16::from_be_bytes(slice!(&self.data[i..], 0..2)));
+            }
+            i += 2;
+        }
+        *slice_mut!(&mut self.data[start..], 10..12) = hasher.finish().to_be_bytes();
+        self.fix_checksum();
+    }
+
+    pub fn icmp_type(&self) -> u8 {
+        let header_len = self.ipv4_packet_ref().ipv4_header_len();
+        self.data[header_len]
+    }
+
+    pub fn code(&self) -> u8 {
+        let header_len = self.ipv4_packet_ref().ipv4_header_len();
+        self.data[header_len + 1]
+    }
+
+    /// Whether this is an ICMP error message (as opposed to an informational message such as an
+    /// echo request).
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self.icmp_type(),
+            icmpv4_types::DESTINATION_UNREACHABLE |
+            icmpv4_types::SOURCE_QUENCH |
+            icmpv4_types::REDIRECT |
+            icmpv4_types::TIME_EXCEEDED |
+            icmpv4_types::PARAMETER_PROBLEM
+        )
+    }
+
+    /// Creates a destination unreachable error in response to `invoking_packet`. Commonly used
+    /// codes are 0 (net unreachable), 1 (host unreachable), 3 (port unreachable) and 13
+    /// (communication administratively prohibited).
+    pub fn new_destination_unreachable(
+        source_addr: Ipv4Addr,
+        code: u8,
+        invoking_packet: &Ipv4Packet,
+    ) -> Box<Icmpv4Packet> {
+        Icmpv4Packet::new_error(
+            source_addr,
+            icmpv4_types::DESTINATION_UNREACHABLE,
+            code,
+            [0; 4],
+            invoking_packet,
+        )
+    }
+
+    /// Creates a "fragmentation needed and DF set" error in response to `invoking_packet`.
+    pub fn new_fragmentation_needed(
+        source_addr: Ipv4Addr,
+        next_hop_mtu: u16,
+        invoking_packet: &Ipv4Packet,
+    ) -> Box<Icmpv4Packet> {
+        let [mtu_hi, mtu_lo] = next_hop_mtu.to_be_bytes();
+        Icmpv4Packet::new_error(
+            source_addr,
+            icmpv4_types::DESTINATION_UNREACHABLE,
+            4,
+            [0, 0, mtu_hi, mtu_lo],
+            invoking_packet,
+        )
+    }
+
+    /// Creates a "time to live exceeded in transit" error in response to `invoking_packet`.
+    pub fn new_time_exceeded(source_addr: Ipv4Addr, invoking_packet: &Ipv4Packet) -> Box<Icmpv4Packet> {
+        Icmpv4Packet::new_error(
+            source_addr,
+            icmpv4_types::TIME_EXCEEDED,
+            0,
+            [0; 4],
+            invoking_packet,
+        )
+    }
+
+    fn new_error(
+        source_addr: Ipv4Addr,
+        icmp_type: u8,
+        code: u8,
+        rest_of_header: [u8; 4],
+        invoking_packet: &Ipv4Packet,
+    ) -> Box<Icmpv4Packet> {
+        let quoted_len = cmp::min(invoking_packet.len(), Icmpv4Packet::MAX_ERROR_LEN - 20 - 8);
+        let mut data = new_ipv4_data(
+            protocol_numbers::ICMP_V4,
+            source_addr,
+            invoking_packet.source_addr(),
+            8 + quoted_len,
+        );
+        data.push(icmp_type);
+        data.push(code);
+        data.extend([0, 0]);
+        data.extend(rest_of_header);
+        data.extend(&invoking_packet.as_bytes()[..quoted_len]);
+
+        let ret: Box<[u8]> = data.into();
+        let mut ret: Box<Icmpv4Packet> = unsafe { mem::transmute(ret) };
+        ret.ipv4_packet_mut().fix_checksum();
+        ret.fix_checksum();
+        ret
+    }
+
+    fn fix_checksum(&mut self) {
+        let ipv4_header_len = self.ipv4_packet_ref().ipv4_header_len();
+        let mut hasher = Ipv4Hasher::new();
+        let mut i = ipv4_header_len;
+        while i + 1 < self.data.len() {
+            if i != ipv4_header_len + 2 {
+                hasher.write_u16(u16::from_be_bytes(slice!(&self.data[i..], 0..2)));
+            }
+            i += 2;
+        }
+        if i < self.data.len() {
+            debug_assert_eq!(i + 1, self.data.len());
+            hasher.write_u16((self.data[i] as u16) << 8);
+        }
+        *slice_mut!(&mut self.data[ipv4_header_len..], 2..4) = hasher.finish().to_be_bytes();
+    }
 }
 
 impl Icmpv6Packet {
+    /// The maximum size of an ICMPv6 error packet, including the IPv6 header.
+    const MAX_ERROR_LEN: usize = 1280;
+
     pub fn source_addr(&self) -> Ipv6Addr {
         self.ipv6_packet_ref().source_addr()
     }
@@ -742,6 +1455,115 @@ impl Icmpv6Packet {
     pub fn destination_addr(&self) -> Ipv6Addr {
         self.ipv6_packet_ref().destination_addr()
     }
+
+    pub fn icmp_type(&self) -> u8 {
+        let header_len = self.ipv6_packet_ref().upper_layer_header_len();
+        self.data[header_len]
+    }
+
+    pub fn code(&self) -> u8 {
+        let header_len = self.ipv6_packet_ref().upper_layer_header_len();
+        self.data[header_len + 1]
+    }
+
+    /// Whether this is an ICMPv6 error message (as opposed to an informational message such as
+    /// an echo request).
+    pub fn is_error(&self) -> bool {
+        self.icmp_type() < 128
+    }
+
+    /// Creates a destination unreachable error in response to `invoking_packet`. Commonly used
+    /// codes are 0 (no route to destination), 1 (communication administratively prohibited), 3
+    /// (address unreachable) and 4 (port unreachable).
+    pub fn new_destination_unreachable(
+        source_addr: Ipv6Addr,
+        code: u8,
+        invoking_packet: &Ipv6Packet,
+    ) -> Box<Icmpv6Packet> {
+        Icmpv6Packet::new_error(
+            source_addr,
+            icmpv6_types::DESTINATION_UNREACHABLE,
+            code,
+            [0; 4],
+            invoking_packet,
+        )
+    }
+
+    /// Creates a packet too big error in response to `invoking_packet`.
+    pub fn new_packet_too_big(
+        source_addr: Ipv6Addr,
+        mtu: u32,
+        invoking_packet: &Ipv6Packet,
+    ) -> Box<Icmpv6Packet> {
+        Icmpv6Packet::new_error(
+            source_addr,
+            icmpv6_types::PACKET_TOO_BIG,
+            0,
+            mtu.to_be_bytes(),
+            invoking_packet,
+        )
+    }
+
+    /// Creates a "hop limit exceeded in transit" error in response to `invoking_packet`.
+    pub fn new_time_exceeded(source_addr: Ipv6Addr, invoking_packet: &Ipv6Packet) -> Box<Icmpv6Packet> {
+        Icmpv6Packet::new_error(
+            source_addr,
+            icmpv6_types::TIME_EXCEEDED,
+            0,
+            [0; 4],
+            invoking_packet,
+        )
+    }
+
+    fn new_error(
+        source_addr: Ipv6Addr,
+        icmp_type: u8,
+        code: u8,
+        rest_of_header: [u8; 4],
+        invoking_packet: &Ipv6Packet,
+    ) -> Box<Icmpv6Packet> {
+        let quoted_len = cmp::min(invoking_packet.len(), Icmpv6Packet::MAX_ERROR_LEN - 40 - 8);
+        let mut data = new_ipv6_data(
+            protocol_numbers::ICMP_V6,
+            source_addr,
+            invoking_packet.source_addr(),
+            8 + quoted_len,
+        );
+        data.push(icmp_type);
+        data.push(code);
+        data.extend([0, 0]);
+        data.extend(rest_of_header);
+        data.extend(&invoking_packet.as_bytes()[..quoted_len]);
+
+        let ret: Box<[u8]> = data.into();
+        let mut ret: Box<Icmpv6Packet> = unsafe { mem::transmute(ret) };
+        ret.fix_checksum();
+        ret
+    }
+
+    fn fix_checksum(&mut self) {
+        let header_len = self.ipv6_packet_ref().upper_layer_header_len();
+        let mut hasher = Ipv4Hasher::new();
+        for addr in [self.source_addr(), self.destination_addr()] {
+            for segment in addr.segments() {
+                hasher.write_u16(segment);
+            }
+        }
+        hasher.write_u32((self.data.len() - header_len) as u32);
+        hasher.write_u32(protocol_numbers::ICMP_V6 as u32);
+        let mut i = header_len;
+        while i + 1 < self.data.len() {
+            if i != header_len + 2 {
+                hasher.write_u16(u16::from_be_bytes(slice!(&self.data[i..], 0..2)));
+            }
+            i += 2;
+        }
+        if i < self.data.len() {
+            debug_assert_eq!(i + 1, self.data.len());
+            hasher.write_u16((self.data[i] as u16) << 8);
+        }
+        *slice_mut!(&mut self.data[header_len..], 2..4) = hasher.finish().to_be_bytes();
+    }
 }
 
 impl fmt::Debug for IpPacket {
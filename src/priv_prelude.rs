@@ -1,7 +1,7 @@
 pub(crate) use {
     std::{
         cmp, fmt, io, mem, panic, ptr, slice, task, thread, str,
-        collections::{VecDeque, hash_map, HashMap, BTreeMap, HashSet},
+        collections::{VecDeque, HashMap, BTreeMap, HashSet},
         ffi::{CStr, CString},
         future::{Future, IntoFuture},
         fs::File,
@@ -10,7 +10,7 @@ pub(crate) use {
         net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4},
         os::fd::{OwnedFd, FromRawFd, AsRawFd},
         pin::Pin,
-        sync::Arc,
+        sync::{Arc, Mutex},
         task::Poll,
         time::{Duration, Instant},
     },
@@ -35,23 +35,24 @@ pub(crate) use {
             create::IpIfaceBuilder,
             stream::{IpIface, IpSinkStream},
         },
-        network::{Ipv4Network, Ipv6Network},
+        network::{IpNetwork, Ipv4Network, Ipv6Network},
         packet::{
-            IpPacket, IpPacketVersion, Ipv4PacketProtocol, Tcpv4Packet, TcpPacketFlags,
+            IpPacket, IpPacketVersion, Ipv4PacketProtocol, Ipv6PacketProtocol, Tcpv4Packet,
+            TcpPacketFlags,
         },
     },
 };
 
 #[cfg(test)]
 pub(crate) use {
-    net_literals::{ipv4, addrv4},
+    net_literals::{ipv4, ipv6, addrv4},
     tokio::{
         io::{AsyncReadExt, AsyncWriteExt},
         net::{TcpStream, TcpListener},
     },
     futures::{join, SinkExt},
     crate::{
-        device::{BiChannel, IpHub, NatBuilder},
+        device::{BiChannel, IpHub, IpRouter, IpSwitch, NatBuilder},
         SinkStreamExt,
     },
 };
@@ -36,6 +36,143 @@ pub trait SinkStreamExt<T>: Stream + Sink<T> {
     {
         crate::adapter::Loss::new(self, loss_rate, jitter_period)
     }
+
+    /// Allows the link formed by this `Sink`/`Stream` to be brought down and back up. Use the
+    /// adapter's [`handle`](crate::adapter::Switch::handle) to switch the link. Items sent or
+    /// received while the link is down are dropped unless
+    /// [`queue_while_down`](crate::adapter::Switch::queue_while_down) is set.
+    fn with_switch(self) -> crate::adapter::Switch<Self, T>
+    where
+        Self: Sized,
+    {
+        crate::adapter::Switch::new(self)
+    }
+
+    /// Drops packets sent/received through this `Sink`/`Stream` which are larger than `mtu`
+    /// bytes, replying to them with ICMP errors. Use
+    /// [`black_hole`](crate::adapter::Mtu::black_hole) to drop them without replying.
+    fn with_mtu(self, mtu: usize) -> crate::adapter::Mtu<Self>
+    where
+        Self: IpSinkStream + Sized,
+    {
+        crate::adapter::Mtu::new(self, mtu)
+    }
+
+    /// Clamps the MSS option of TCP SYN and SYN-ACK packets sent/received through this
+    /// `Sink`/`Stream` to at most `max_mss`. Use
+    /// [`max_window`](crate::adapter::MssClamp::max_window) to also cap advertised receive
+    /// windows.
+    fn with_mss_clamp(self, max_mss: u16) -> crate::adapter::MssClamp<Self>
+    where
+        Self: IpSinkStream + Sized,
+    {
+        crate::adapter::MssClamp::new(self, max_mss)
+    }
+
+    /// Filters packets sent/received through this `Sink`/`Stream` according to an ordered list of
+    /// rules. The first rule which matches a packet decides what happens to it.
+    fn with_firewall(
+        self,
+        rules: Vec<crate::adapter::FirewallRule>,
+    ) -> crate::adapter::Firewall<Self>
+    where
+        Self: IpSinkStream + Sized,
+    {
+        crate::adapter::Firewall::new(self, rules)
+    }
+
+    /// Injects faults into specific packets sent/received through this `Sink`/`Stream` according
+    /// to a list of rules. See [`FaultInjector`](crate::adapter::FaultInjector).
+    fn with_fault_injection(
+        self,
+        rules: Vec<crate::adapter::FaultRule>,
+    ) -> crate::adapter::FaultInjector<Self>
+    where
+        Self: IpSinkStream + Sized,
+    {
+        crate::adapter::FaultInjector::new(self, rules)
+    }
+
+    /// Passes every packet sent through this `Sink` through `sent` and every packet received from
+    /// this `Stream` through `received`.
+    fn map_packets<FS, FR>(self, sent: FS, received: FR) -> crate::adapter::MapPackets<Self, FS, FR>
+    where
+        Self: IpSinkStream + Sized,
+        FS: FnMut(Box<IpPacket>) -> Box<IpPacket> + Send + 'static,
+        FR: FnMut(Box<IpPacket>) -> Box<IpPacket> + Send + 'static,
+    {
+        crate::adapter::MapPackets::new(self, sent, received)
+    }
+
+    /// Replaces every packet sent through this `Sink` with the packets returned by `sent` and
+    /// every packet received from this `Stream` with the packets returned by `received`. The
+    /// functions can return any number of packets, eg. `None` to drop the packet or a `Vec` to
+    /// split it.
+    fn filter_map_packets<FS, FR, IS, IR>(
+        self,
+        sent: FS,
+        received: FR,
+    ) -> crate::adapter::FilterMapPackets<Self, FS, FR>
+    where
+        Self: IpSinkStream + Sized,
+        FS: FnMut(Box<IpPacket>) -> IS + Send + 'static,
+        FR: FnMut(Box<IpPacket>) -> IR + Send + 'static,
+        IS: IntoIterator<Item = Box<IpPacket>>,
+        IR: IntoIterator<Item = Box<IpPacket>>,
+    {
+        crate::adapter::FilterMapPackets::new(self, sent, received)
+    }
+
+    /// Limits the bandwidth of packets sent/received through this `Sink`/`Stream` to
+    /// `bits_per_second`. Up to `queue_len` packets are queued while waiting to be transmitted and
+    /// packets which arrive while the queue is full are dropped.
+    fn with_rate_limit(
+        self,
+        bits_per_second: u64,
+        queue_len: usize,
+    ) -> crate::adapter::RateLimit<Self>
+    where
+        Self: IpSinkStream + Sized,
+    {
+        crate::adapter::RateLimit::new(self, bits_per_second, queue_len)
+    }
+
+    /// Simulates a link with separately configured delay, loss, bandwidth and queueing for
+    /// packets sent through this `Sink` and packets received from this `Stream`. When this is an
+    /// [`IpIface`](crate::IpIface), `sent` configures the downstream direction (into the machine)
+    /// and `received` configures the upstream direction (out of the machine).
+    fn with_link(
+        self,
+        sent: crate::adapter::LinkConfig,
+        received: crate::adapter::LinkConfig,
+    ) -> crate::adapter::Link<Self>
+    where
+        Self: IpSinkStream + Sized,
+    {
+        crate::adapter::Link::new(self, sent, received)
+    }
+
+    /// Counts the packets and bytes sent/received through this `Sink`/`Stream`, per direction and
+    /// protocol. Use the adapter's [`handle`](crate::adapter::Stats::handle) to read the counters.
+    fn with_stats(self) -> crate::adapter::Stats<Self>
+    where
+        Self: IpSinkStream + Sized,
+    {
+        crate::adapter::Stats::new(self)
+    }
+
+    /// Copies every packet sent/received through this `Sink`/`Stream` onto the returned
+    /// receiver, tagged with the direction it was travelling and the time it passed through. The
+    /// packets themselves pass through unaffected. Dropping the receiver is harmless.
+    fn tap(self) -> (
+        crate::adapter::Tap<Self>,
+        mpsc::UnboundedReceiver<crate::adapter::TappedPacket>,
+    )
+    where
+        Self: IpSinkStream + Sized,
+    {
+        crate::adapter::Tap::new(self)
+    }
 }
 
 impl<S, T> SinkStreamExt<T> for S
@@ -131,3 +131,30 @@ async fn random_delays_are_approx_correct() {
     drop((chan_0, chan_1));
 }
 
+
+#[tokio::test(flavor = "multi_thread")]
+async fn min_delay_can_be_changed_at_runtime() {
+    const SHORT_DELAY: Duration = Duration::from_millis(50);
+    const LONG_DELAY: Duration = Duration::from_millis(500);
+
+    let (chan_0, mut chan_1) = BiChannel::new(1);
+    let chan_0 = chan_0.with_delay(SHORT_DELAY, Duration::ZERO);
+    let handle = chan_0.handle();
+    let mut chan_0 = Box::pin(chan_0);
+
+    let send_instant = Instant::now();
+    chan_0.send(()).await.unwrap();
+    let () = chan_1.next().await.unwrap();
+    let delay = Instant::now() - send_instant;
+    assert!(SHORT_DELAY <= delay);
+    assert!(delay < LONG_DELAY);
+
+    handle.set_min_delay(LONG_DELAY);
+    assert_eq!(handle.min_delay(), LONG_DELAY);
+
+    let send_instant = Instant::now();
+    chan_0.send(()).await.unwrap();
+    let () = chan_1.next().await.unwrap();
+    let delay = Instant::now() - send_instant;
+    assert!(LONG_DELAY <= delay);
+}
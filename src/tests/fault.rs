@@ -0,0 +1,113 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{FaultAction, FaultRule, Occurrence, PacketMatch},
+    tests::udp_packet,
+};
+
+/// Returns the offset of the only byte in which `corrupted` differs from `packet`, asserting that
+/// exactly one bit was flipped.
+fn flipped_byte_index(packet: &IpPacket, corrupted: &IpPacket) -> usize {
+    let flipped: Vec<(usize, u32)> = {
+        packet.as_bytes().iter()
+        .zip(corrupted.as_bytes())
+        .map(|(byte, corrupted_byte)| (byte ^ corrupted_byte).count_ones())
+        .enumerate()
+        .filter(|(_, flipped_bits)| *flipped_bits > 0)
+        .collect()
+    };
+    assert_eq!(flipped.len(), 1);
+    assert_eq!(flipped[0].1, 1);
+    flipped[0].0
+}
+
+#[tokio::test]
+async fn nth_packet_is_dropped() {
+    let rules = vec![
+        FaultRule::new(PacketMatch::any(), FaultAction::Drop).occurrence(Occurrence::Nth(2)),
+    ];
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let chan_0 = chan_0.with_fault_injection(rules);
+    let handle = chan_0.handle();
+    let mut chan_0 = Box::pin(chan_0);
+
+    for len in [100, 101, 102] {
+        chan_0.send(udp_packet(len)).await.unwrap();
+    }
+    assert_eq!(chan_1.next().await.unwrap().unwrap().len(), 100);
+    assert_eq!(chan_1.next().await.unwrap().unwrap().len(), 102);
+    assert_eq!(handle.fire_counts(), [1]);
+}
+
+#[tokio::test]
+async fn packets_are_duplicated_and_delayed() {
+    const DELAY: Duration = Duration::from_millis(200);
+
+    let rules = vec![
+        FaultRule::new(PacketMatch::any(), FaultAction::Duplicate).occurrence(Occurrence::FirstN(1)),
+        FaultRule::new(PacketMatch::any(), FaultAction::Delay(DELAY)).occurrence(Occurrence::EveryKth(2)),
+    ];
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let mut chan_0 = Box::pin(chan_0.with_fault_injection(rules));
+
+    let send_instant = Instant::now();
+    for len in [100, 101, 102] {
+        chan_1.send(udp_packet(len)).await.unwrap();
+    }
+    let mut lens = Vec::new();
+    for _ in 0..4 {
+        lens.push(chan_0.next().await.unwrap().unwrap().len());
+    }
+    assert_eq!(lens, [100, 100, 101, 102]);
+    assert!(Instant::now() - send_instant >= DELAY);
+}
+
+#[tokio::test]
+async fn corrupting_a_header_only_packet_keeps_it_parseable() {
+    let rules = vec![FaultRule::new(PacketMatch::any(), FaultAction::Corrupt)];
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let mut chan_0 = Box::pin(chan_0.with_fault_injection(rules));
+
+    let mut packet = udp_packet(20);
+    packet.as_bytes_mut()[9] = 253;
+    for _ in 0..50 {
+        chan_0.send(packet.clone()).await.unwrap();
+        let corrupted = chan_1.next().await.unwrap().unwrap();
+        let flipped_bits: u32 = {
+            packet.as_bytes().iter()
+            .zip(corrupted.as_bytes())
+            .map(|(byte, corrupted_byte)| (byte ^ corrupted_byte).count_ones())
+            .sum()
+        };
+        assert_eq!(flipped_bits, 1);
+        for index in [0, 2, 3, 6, 7, 9].into_iter().chain(12..20) {
+            assert_eq!(corrupted.as_bytes()[index], packet.as_bytes()[index]);
+        }
+        let IpPacketVersion::V4(_) = corrupted.version_ref() else { panic!("expected ipv4") };
+    }
+}
+
+#[tokio::test]
+async fn corruption_never_touches_transport_headers() {
+    let rules = vec![FaultRule::new(PacketMatch::any(), FaultAction::Corrupt)];
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let mut chan_0 = Box::pin(chan_0.with_fault_injection(rules));
+
+    // A UDP packet with a payload has the bit flipped in its payload.
+    let packet = udp_packet(100);
+    for _ in 0..50 {
+        chan_0.send(packet.clone()).await.unwrap();
+        let corrupted = chan_1.next().await.unwrap().unwrap();
+        assert!(flipped_byte_index(&packet, &corrupted) >= 28);
+    }
+
+    // Header-only UDP and TCP packets have the bit flipped in their checksum.
+    let header_only_udp = udp_packet(28);
+    let header_only_tcp = Tcpv4Packet::new().ip_packet_box();
+    for (packet, checksum_range) in [(header_only_udp, 26..28), (header_only_tcp, 36..38)] {
+        for _ in 0..50 {
+            chan_0.send(packet.clone()).await.unwrap();
+            let corrupted = chan_1.next().await.unwrap().unwrap();
+            assert!(checksum_range.contains(&flipped_byte_index(&packet, &corrupted)));
+        }
+    }
+}
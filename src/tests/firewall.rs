@@ -0,0 +1,138 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{Direction, FirewallAction, FirewallRule, PacketMatch},
+    packet::{Icmpv4Packet, IpProtocol, Ipv4PacketProtocol},
+};
+
+#[tokio::test]
+async fn firewall_rejects_blocked_port() {
+    let addr_0 = addrv4!("10.0.0.1:45000");
+    let open_addr = addrv4!("10.0.0.2:81");
+    let blocked_addr = addrv4!("10.0.0.2:80");
+
+    let machine_0 = Machine::new().unwrap();
+    let machine_1 = Machine::new().unwrap();
+    let iface_0 = {
+        machine_0
+        .add_ip_iface()
+        .ipv4_addr(*addr_0.ip())
+        .ipv4_default_route()
+        .build()
+        .unwrap()
+    };
+    let iface_1 = {
+        machine_1
+        .add_ip_iface()
+        .ipv4_addr(*open_addr.ip())
+        .ipv4_default_route()
+        .build()
+        .unwrap()
+    };
+
+    let rules = vec![
+        FirewallRule::new(
+            PacketMatch::any()
+            .direction(Direction::Received)
+            .protocol(IpProtocol::Tcp)
+            .destination_ports(80..=80),
+            FirewallAction::RejectWithTcpReset,
+        ),
+    ];
+    let iface_0 = iface_0.with_firewall(rules);
+    let handle = iface_0.handle();
+    crate::connect(iface_0, iface_1);
+
+    let listen_task = machine_1.spawn(async move {
+        let open_listener = TcpListener::bind(open_addr).await.unwrap();
+        let _blocked_listener = TcpListener::bind(blocked_addr).await.unwrap();
+        let (_stream, _addr) = open_listener.accept().await.unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let connect_task = machine_0.spawn(async move {
+        let res = TcpStream::connect(blocked_addr).await;
+        match res {
+            Ok(_) => panic!("connected through firewall"),
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused),
+        }
+        let _stream = TcpStream::connect(open_addr).await.unwrap();
+    });
+
+    let () = connect_task.await.unwrap().unwrap();
+    let () = listen_task.await.unwrap().unwrap();
+    assert_eq!(handle.hit_counts(), vec![1]);
+}
+
+#[tokio::test]
+async fn firewall_rejects_udp_with_icmp() {
+    let addr_0 = addrv4!("10.0.0.1:45000");
+    let addr_1 = addrv4!("10.0.0.2:5000");
+
+    let machine_0 = Machine::new().unwrap();
+    let machine_1 = Machine::new().unwrap();
+    let iface_0 = {
+        machine_0
+        .add_ip_iface()
+        .ipv4_addr(*addr_0.ip())
+        .ipv4_default_route()
+        .build()
+        .unwrap()
+    };
+    let iface_1 = {
+        machine_1
+        .add_ip_iface()
+        .ipv4_addr(*addr_1.ip())
+        .ipv4_default_route()
+        .build()
+        .unwrap()
+    };
+
+    let rules = vec![
+        FirewallRule::new(
+            PacketMatch::any().protocol(IpProtocol::Udp),
+            FirewallAction::RejectWithIcmpUnreachable,
+        ),
+    ];
+    crate::connect(iface_0.with_firewall(rules), iface_1);
+
+    let res = machine_0.spawn(async move {
+        // NOTE: Using a std socket since tokio doesn't wake a pending `recv` when the socket gets
+        // an error.
+        let socket = std::net::UdpSocket::bind(addr_0).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        socket.connect(addr_1).unwrap();
+        socket.send(b"hello").unwrap();
+        let mut buffer = [0u8; 16];
+        socket.recv(&mut buffer)
+    }).await.unwrap().unwrap();
+    match res {
+        Ok(_) => panic!("received through firewall"),
+        Err(err) => assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused),
+    }
+}
+
+#[tokio::test]
+async fn firewall_rejects_other_protocols_as_protocol_unreachable() {
+    let addr_0 = ipv4!("10.0.0.1");
+    let addr_1 = ipv4!("10.0.0.2");
+
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let rules = vec![
+        FirewallRule::new(
+            PacketMatch::any().protocol(IpProtocol::Icmp),
+            FirewallAction::RejectWithTcpReset,
+        ),
+    ];
+    let mut chan_0 = Box::pin(chan_0.with_firewall(rules));
+
+    let request = Icmpv4Packet::new_echo_request(addr_0, addr_1, 1, 1, b"ping");
+    chan_0.send(request.ip_packet_box()).await.unwrap();
+    let reply = chan_0.next().await.unwrap().unwrap();
+    let IpPacketVersion::V4(reply) = reply.version_box() else { panic!("expected ipv4") };
+    assert_eq!(reply.source_addr(), addr_1);
+    let Ipv4PacketProtocol::Icmp(reply) = reply.protocol_box() else { panic!("expected icmp") };
+    assert_eq!(reply.icmp_type(), 3);
+    assert_eq!(reply.code(), 2);
+    let res = tokio::time::timeout(Duration::from_millis(100), chan_1.next()).await;
+    assert!(res.is_err());
+}
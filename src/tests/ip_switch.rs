@@ -0,0 +1,59 @@
+use crate::{
+    priv_prelude::*,
+    tests::udp_packet,
+};
+
+fn udp_packet_between(source_addr: Ipv4Addr, destination_addr: Ipv4Addr) -> Box<IpPacket> {
+    let mut packet = udp_packet(100);
+    let IpPacketVersion::V4(ipv4_packet) = packet.version_mut() else { unreachable!() };
+    ipv4_packet.set_source_addr(source_addr);
+    ipv4_packet.set_destination_addr(destination_addr);
+    packet
+}
+
+async fn assert_nothing_received(chan: &mut IpChannel) {
+    let res = tokio::time::timeout(Duration::from_millis(50), chan.next()).await;
+    assert!(res.is_err());
+}
+
+#[tokio::test]
+async fn learns_floods_and_ages_out() {
+    const ENTRY_TIMEOUT: Duration = Duration::from_millis(200);
+
+    let addr_a = ipv4!("10.0.0.1");
+    let addr_b = ipv4!("10.0.0.2");
+
+    let mut switch = IpSwitch::with_entry_timeout(ENTRY_TIMEOUT);
+    let (chan_a, mut switch_chan_a) = IpChannel::new(10);
+    let (chan_b, mut switch_chan_b) = IpChannel::new(10);
+    let (chan_c, mut switch_chan_c) = IpChannel::new(10);
+    switch.insert_iface(chan_a);
+    switch.insert_iface(chan_b);
+    switch.insert_iface(chan_c);
+
+    // B hasn't been seen yet so this gets flooded.
+    switch_chan_a.send(udp_packet_between(addr_a, addr_b)).await.unwrap();
+    let _packet = switch_chan_b.next().await.unwrap().unwrap();
+    let _packet = switch_chan_c.next().await.unwrap().unwrap();
+
+    // The switch has learned where A and B are, so these are unicast.
+    switch_chan_b.send(udp_packet_between(addr_b, addr_a)).await.unwrap();
+    let packet = switch_chan_a.next().await.unwrap().unwrap();
+    assert_eq!(packet.source_addr(), addr_b);
+    switch_chan_a.send(udp_packet_between(addr_a, addr_b)).await.unwrap();
+    let packet = switch_chan_b.next().await.unwrap().unwrap();
+    assert_eq!(packet.source_addr(), addr_a);
+    assert_nothing_received(&mut switch_chan_c).await;
+
+    // Broadcasts are always flooded.
+    switch_chan_a.send(udp_packet_between(addr_a, Ipv4Addr::BROADCAST)).await.unwrap();
+    let _packet = switch_chan_b.next().await.unwrap().unwrap();
+    let _packet = switch_chan_c.next().await.unwrap().unwrap();
+
+    // Once B's entry has aged out, packets to B are flooded again.
+    tokio::time::sleep(ENTRY_TIMEOUT).await;
+    switch_chan_a.send(udp_packet_between(addr_a, addr_b)).await.unwrap();
+    let _packet = switch_chan_b.next().await.unwrap().unwrap();
+    let _packet = switch_chan_c.next().await.unwrap().unwrap();
+    assert_nothing_received(&mut switch_chan_a).await;
+}
@@ -0,0 +1,46 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{Direction, LinkConfig},
+    tests::udp_packet,
+};
+
+#[tokio::test]
+async fn directions_are_configured_independently() {
+    const SENT_DELAY: Duration = Duration::from_millis(300);
+
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let sent = LinkConfig::new().delay(SENT_DELAY, Duration::ZERO);
+    let received = LinkConfig::new();
+    let chan_0 = chan_0.with_link(sent, received);
+    let received_delay = chan_0.delay_handle(Direction::Received);
+    let mut chan_0 = Box::pin(chan_0);
+
+    let send_instant = Instant::now();
+    chan_0.send(udp_packet(100)).await.unwrap();
+    let _packet = chan_1.next().await.unwrap().unwrap();
+    assert!(Instant::now() - send_instant >= SENT_DELAY);
+
+    let send_instant = Instant::now();
+    chan_1.send(udp_packet(100)).await.unwrap();
+    let _packet = chan_0.next().await.unwrap().unwrap();
+    assert!(Instant::now() - send_instant < SENT_DELAY);
+
+    received_delay.set_min_delay(SENT_DELAY);
+    let send_instant = Instant::now();
+    chan_1.send(udp_packet(100)).await.unwrap();
+    let _packet = chan_0.next().await.unwrap().unwrap();
+    assert!(Instant::now() - send_instant >= SENT_DELAY);
+}
+
+#[tokio::test]
+async fn loss_only_applies_to_one_direction() {
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let mut chan_0 = Box::pin(chan_0.with_loss(1.0, Duration::from_secs(1)).direction(Direction::Sent));
+
+    chan_1.send(udp_packet(100)).await.unwrap();
+    let _packet = chan_0.next().await.unwrap().unwrap();
+
+    chan_0.send(udp_packet(100)).await.unwrap();
+    let res = tokio::time::timeout(Duration::from_millis(100), chan_1.next()).await;
+    assert!(res.is_err());
+}
@@ -98,3 +98,45 @@ async fn non_zero_jitter_is_locally_non_random() {
     assert!(after_received_loss_rate < LOSS_RATE);
 }
 
+
+#[tokio::test(flavor = "multi_thread")]
+async fn loss_rate_can_be_changed_at_runtime() {
+    const NUM_MSGS_PER_PHASE: usize = 100;
+
+    let (chan_0, mut chan_1) = BiChannel::new(NUM_MSGS_PER_PHASE * 3);
+    let chan_0 = chan_0.with_loss(0.0, Duration::ZERO);
+    let handle = chan_0.handle();
+    let mut chan_0 = Box::pin(chan_0);
+    let sender = async move {
+        for val in 0..NUM_MSGS_PER_PHASE {
+            chan_0.feed(val).await.unwrap();
+        }
+        handle.set_loss_rate(1.0);
+        for val in NUM_MSGS_PER_PHASE..(NUM_MSGS_PER_PHASE * 2) {
+            chan_0.feed(val).await.unwrap();
+        }
+        handle.set_loss_rate(0.0);
+        for val in (NUM_MSGS_PER_PHASE * 2)..(NUM_MSGS_PER_PHASE * 3) {
+            chan_0.feed(val).await.unwrap();
+        }
+        chan_0.flush().await.unwrap();
+        chan_0.close().await.unwrap();
+    };
+    let receiver = async move {
+        let mut received = vec![false; NUM_MSGS_PER_PHASE * 3];
+
+        while let Some(val) = chan_1.next().await {
+            received[val] = true;
+        }
+        received
+    };
+    let sender = tokio::spawn(sender);
+    let receiver = tokio::spawn(receiver);
+    let (sender_res, receiver_res) = join!(sender, receiver);
+    let () = sender_res.unwrap();
+    let received = receiver_res.unwrap();
+
+    assert!(received[..NUM_MSGS_PER_PHASE].iter().all(|x| *x));
+    assert!(received[NUM_MSGS_PER_PHASE..(NUM_MSGS_PER_PHASE * 2)].iter().all(|x| !*x));
+    assert!(received[(NUM_MSGS_PER_PHASE * 2)..].iter().all(|x| *x));
+}
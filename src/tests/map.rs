@@ -0,0 +1,40 @@
+use crate::{
+    priv_prelude::*,
+    tests::udp_packet,
+};
+
+#[tokio::test]
+async fn map_packets_applies_per_direction() {
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let mut chan_0 = Box::pin(chan_0.map_packets(
+        |packet| udp_packet(packet.len() + 1),
+        |packet| packet,
+    ));
+
+    chan_0.send(udp_packet(100)).await.unwrap();
+    assert_eq!(chan_1.next().await.unwrap().unwrap().len(), 101);
+
+    chan_1.send(udp_packet(100)).await.unwrap();
+    assert_eq!(chan_0.next().await.unwrap().unwrap().len(), 100);
+}
+
+#[tokio::test]
+async fn filter_map_packets_can_drop_and_duplicate() {
+    fn boxed<S: IpSinkStream>(iface: S) -> Pin<Box<dyn IpSinkStream>> {
+        Box::pin(iface)
+    }
+
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let mut chan_0 = boxed(chan_0.filter_map_packets(
+        |packet| (packet.len() != 100).then_some(packet),
+        |packet| vec![packet.clone(), packet],
+    ));
+
+    chan_0.send(udp_packet(100)).await.unwrap();
+    chan_0.send(udp_packet(101)).await.unwrap();
+    assert_eq!(chan_1.next().await.unwrap().unwrap().len(), 101);
+
+    chan_1.send(udp_packet(100)).await.unwrap();
+    assert_eq!(chan_0.next().await.unwrap().unwrap().len(), 100);
+    assert_eq!(chan_0.next().await.unwrap().unwrap().len(), 100);
+}
@@ -0,0 +1,107 @@
+use crate::{
+    priv_prelude::*,
+    device::MiddleboxBuilder,
+    packet::EcnCodepoint,
+    tests::udp_packet,
+};
+
+fn tcp_packet_with_options() -> Box<IpPacket> {
+    let mut data = vec![0u8; 48];
+    data[0] = (4 << 4) | 5;
+    data[1] = 0b10;
+    data[2..4].copy_from_slice(&48u16.to_be_bytes());
+    data[8] = 64;
+    data[9] = 6;
+    data[12..16].copy_from_slice(&ipv4!("10.0.0.1").octets());
+    data[16..20].copy_from_slice(&ipv4!("10.0.0.2").octets());
+    data[32] = 7 << 4;
+    data[33] = 0x02;
+    data[40..44].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+    data[44..48].copy_from_slice(&[253, 4, 0, 0]);
+    IpPacket::new_box(data.into())
+}
+
+fn udp_packet_between(source_addr: SocketAddrV4, destination_addr: SocketAddrV4) -> Box<IpPacket> {
+    let packet = udp_packet(100);
+    let IpPacketVersion::V4(packet) = packet.version_box() else { unreachable!() };
+    let Ipv4PacketProtocol::Udp(mut packet) = packet.protocol_box() else { unreachable!() };
+    packet.set_source_addr(source_addr);
+    packet.set_destination_addr(destination_addr);
+    packet.ip_packet_box()
+}
+
+#[tokio::test]
+async fn headers_are_rewritten() {
+    let (mut middlebox, mut external) = {
+        MiddleboxBuilder::new()
+        .strip_unknown_tcp_options()
+        .clear_ecn()
+        .rewrite_ttl(5)
+        .normalize_ip_ids()
+        .build()
+    };
+    let (internal, mut machine) = IpChannel::new(10);
+    middlebox.insert_iface(internal);
+
+    for expected_id in 0..2 {
+        machine.send(tcp_packet_with_options()).await.unwrap();
+        let packet = external.next().await.unwrap().unwrap();
+        let IpPacketVersion::V4(packet) = packet.version_box() else { panic!("expected ipv4") };
+        assert_eq!(packet.ecn(), EcnCodepoint::NotEct);
+        assert_eq!(packet.ttl(), 5);
+        assert_eq!(packet.identification(), expected_id);
+        let Ipv4PacketProtocol::Tcp(packet) = packet.protocol_box() else { panic!("expected tcp") };
+        assert_eq!(packet.option_kinds(), [2, 1, 1, 1, 1]);
+        assert_eq!(packet.mss(), Some(1460));
+    }
+}
+
+#[tokio::test]
+async fn ports_blocked_and_idle_udp_dropped() {
+    const IDLE_TIMEOUT: Duration = Duration::from_millis(200);
+
+    let internal_addr = addrv4!("10.0.0.1:1234");
+    let (mut middlebox, mut external) = {
+        MiddleboxBuilder::new()
+        .block_non_standard_ports()
+        .udp_idle_timeout(IDLE_TIMEOUT)
+        .build()
+    };
+    let (internal, mut machine) = IpChannel::new(10);
+    middlebox.insert_iface(internal);
+
+    machine.send(udp_packet_between(internal_addr, addrv4!("1.2.3.4:5000"))).await.unwrap();
+    machine.send(udp_packet_between(internal_addr, addrv4!("1.2.3.4:443"))).await.unwrap();
+    let packet = external.next().await.unwrap().unwrap();
+    assert_eq!(packet.ports(), Some((1234, 443)));
+
+    external.send(udp_packet_between(addrv4!("1.2.3.4:443"), internal_addr)).await.unwrap();
+    let packet = machine.next().await.unwrap().unwrap();
+    assert_eq!(packet.ports(), Some((443, 1234)));
+
+    tokio::time::sleep(IDLE_TIMEOUT * 2).await;
+    external.send(udp_packet_between(addrv4!("1.2.3.4:443"), internal_addr)).await.unwrap();
+    let res = tokio::time::timeout(Duration::from_millis(100), machine.next()).await;
+    assert!(res.is_err());
+}
+
+#[tokio::test]
+async fn removed_ifaces_are_forgotten() {
+    let machine_addr = addrv4!("10.0.0.1:1234");
+    let remote_addr = addrv4!("10.0.1.1:80");
+
+    let (mut middlebox, mut external) = MiddleboxBuilder::new().build();
+    let (iface_0, mut machine_0) = IpChannel::new(10);
+    let port_0 = middlebox.insert_iface(iface_0);
+    machine_0.send(udp_packet_between(machine_addr, remote_addr)).await.unwrap();
+    let _packet = external.next().await.unwrap().unwrap();
+
+    // Once the iface the middlebox learned the machine's address on is removed, packets for the
+    // machine reach it on its new iface.
+    let _iface_0 = port_0.remove();
+    let (iface_1, mut machine_1) = IpChannel::new(10);
+    let _port_1 = middlebox.insert_iface(iface_1);
+    external.send(udp_packet_between(remote_addr, machine_addr)).await.unwrap();
+    let res = tokio::time::timeout(Duration::from_millis(100), machine_1.next()).await;
+    assert!(res.is_ok());
+}
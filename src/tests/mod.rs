@@ -1,4 +1,32 @@
 mod loss;
+mod map;
+mod middlebox;
+mod mss_clamp;
+mod mtu;
 mod delay;
+mod fault;
+mod firewall;
+mod ip_switch;
+mod link;
 mod nat;
+mod port_handle;
+mod rate_limit;
+mod router;
+mod stats;
+mod switch;
+mod tap;
 
+use crate::priv_prelude::*;
+
+/// Builds a minimal IPv4/UDP packet of the given length from 10.0.0.1 to 10.0.0.2.
+pub(crate) fn udp_packet(len: usize) -> Box<IpPacket> {
+    let mut data = vec![0u8; len];
+    data[0] = (4 << 4) | 5;
+    data[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    data[6] = 0x40;
+    data[8] = 64;
+    data[9] = 17;
+    data[12..16].copy_from_slice(&ipv4!("10.0.0.1").octets());
+    data[16..20].copy_from_slice(&ipv4!("10.0.0.2").octets());
+    IpPacket::new_box(data.into())
+}
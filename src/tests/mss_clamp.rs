@@ -0,0 +1,68 @@
+use crate::{
+    priv_prelude::*,
+    adapter::Direction,
+};
+
+#[tokio::test]
+async fn syn_mss_is_clamped() {
+    const MAX_MSS: u16 = 536;
+    const MAX_WINDOW: u16 = 1000;
+    const DATA_LEN: usize = 10_000;
+
+    let addr_0 = addrv4!("10.0.0.1:45000");
+    let addr_1 = addrv4!("10.0.0.2:80");
+
+    let machine_0 = Machine::new().unwrap();
+    let machine_1 = Machine::new().unwrap();
+    let iface_0 = {
+        machine_0
+        .add_ip_iface()
+        .ipv4_addr(*addr_0.ip())
+        .ipv4_default_route()
+        .build()
+        .unwrap()
+    };
+    let iface_1 = {
+        machine_1
+        .add_ip_iface()
+        .ipv4_addr(*addr_1.ip())
+        .ipv4_default_route()
+        .build()
+        .unwrap()
+    };
+
+    let (iface_0, mut tapped) = iface_0.with_mss_clamp(MAX_MSS).max_window(MAX_WINDOW).tap();
+    crate::connect(iface_0, iface_1);
+
+    let listen_task = machine_1.spawn(async move {
+        let listener = TcpListener::bind(addr_1).await.unwrap();
+        let (mut stream, _addr) = listener.accept().await.unwrap();
+        let mut data = vec![0u8; DATA_LEN];
+        stream.read_exact(&mut data).await.unwrap();
+        assert!(data.iter().all(|byte| *byte == 0xaa));
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let connect_task = machine_0.spawn(async move {
+        let mut stream = TcpStream::connect(addr_1).await.unwrap();
+        stream.write_all(&[0xaa; DATA_LEN]).await.unwrap();
+    });
+
+    let () = connect_task.await.unwrap().unwrap();
+    let () = listen_task.await.unwrap().unwrap();
+
+    let mut saw_syn = false;
+    while let Ok(Some(tapped)) = tapped.try_next() {
+        if tapped.direction != Direction::Received {
+            continue;
+        }
+        let IpPacketVersion::V4(packet) = tapped.packet.version_box() else { continue };
+        let Ipv4PacketProtocol::Tcp(packet) = packet.protocol_box() else { continue };
+        assert!(packet.window_size() <= MAX_WINDOW);
+        if packet.flags().syn {
+            assert_eq!(packet.mss(), Some(MAX_MSS));
+            saw_syn = true;
+        }
+    }
+    assert!(saw_syn);
+}
@@ -0,0 +1,47 @@
+use crate::{
+    priv_prelude::*,
+    packet::Ipv4PacketProtocol,
+    tests::udp_packet,
+};
+
+#[tokio::test]
+async fn oversize_packets_get_fragmentation_needed() {
+    const MTU: usize = 1000;
+
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let mut chan_0 = Box::pin(chan_0.with_mtu(MTU));
+
+    chan_0.send(udp_packet(MTU)).await.unwrap();
+    let packet = chan_1.next().await.unwrap().unwrap();
+    assert_eq!(packet.len(), MTU);
+
+    chan_0.send(udp_packet(MTU + 1)).await.unwrap();
+    let reply = chan_0.next().await.unwrap().unwrap();
+    let IpPacketVersion::V4(reply) = reply.version_box() else { panic!("expected ipv4") };
+    assert_eq!(reply.source_addr(), ipv4!("10.0.0.2"));
+    assert_eq!(reply.destination_addr(), ipv4!("10.0.0.1"));
+    let Ipv4PacketProtocol::Icmp(reply) = reply.protocol_box() else { panic!("expected icmp") };
+    assert_eq!(reply.icmp_type(), 3);
+    assert_eq!(reply.code(), 4);
+
+    chan_1.send(udp_packet(MTU + 1)).await.unwrap();
+    let res = tokio::time::timeout(Duration::from_millis(100), chan_0.next()).await;
+    assert!(res.is_err());
+    let reply = chan_1.next().await.unwrap().unwrap();
+    assert_eq!(reply.destination_addr(), IpAddr::V4(ipv4!("10.0.0.1")));
+}
+
+#[tokio::test]
+async fn black_hole_drops_silently() {
+    const MTU: usize = 1000;
+
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let mut chan_0 = Box::pin(chan_0.with_mtu(MTU).black_hole());
+
+    chan_0.send(udp_packet(MTU + 1)).await.unwrap();
+    chan_0.send(udp_packet(MTU)).await.unwrap();
+    let packet = chan_1.next().await.unwrap().unwrap();
+    assert_eq!(packet.len(), MTU);
+    let res = tokio::time::timeout(Duration::from_millis(100), chan_0.next()).await;
+    assert!(res.is_err());
+}
@@ -1,4 +1,56 @@
-use crate::priv_prelude::*;
+use crate::{
+    priv_prelude::*,
+    device::{InboundDropReason, Nat, NatEvent, PermittedRemotes},
+    packet::{Icmpv4Packet, IpProtocol, Udpv4Packet},
+    tests::udp_packet,
+};
+
+fn udp_packet_between(source_addr: SocketAddrV4, destination_addr: SocketAddrV4) -> Box<IpPacket> {
+    let packet = udp_packet(100);
+    let IpPacketVersion::V4(packet) = packet.version_box() else { unreachable!() };
+    let Ipv4PacketProtocol::Udp(mut packet) = packet.protocol_box() else { unreachable!() };
+    packet.set_source_addr(source_addr);
+    packet.set_destination_addr(destination_addr);
+    packet.ip_packet_box()
+}
+
+fn tcp_packet_between(
+    source_addr: SocketAddrV4,
+    destination_addr: SocketAddrV4,
+    flags: TcpPacketFlags,
+) -> Box<IpPacket> {
+    let mut packet = Tcpv4Packet::new();
+    packet.set_source_addr(source_addr);
+    packet.set_destination_addr(destination_addr);
+    packet.set_flags(flags);
+    packet.ip_packet_box()
+}
+
+/// Sends a packet into the NAT's external side and returns whether it came out the internal side.
+async fn passes_inbound(nat_iface: &mut IpChannel, internal_chan: &mut IpChannel, packet: Box<IpPacket>) -> bool {
+    nat_iface.send(packet).await.unwrap();
+    tokio::time::timeout(Duration::from_millis(50), internal_chan.next()).await.is_ok()
+}
+
+/// Sends a packet into the NAT's internal side and returns the external port it came out of the
+/// external side with, if it did.
+async fn passes_outbound(nat_iface: &mut IpChannel, internal_chan: &mut IpChannel, packet: Box<IpPacket>) -> Option<u16> {
+    internal_chan.send(packet).await.unwrap();
+    let packet = tokio::time::timeout(Duration::from_millis(50), nat_iface.next()).await.ok()?;
+    Some(packet.unwrap().unwrap().ports().unwrap().0)
+}
+
+/// Sends a NAT-PMP or PCP request to a NAT at 192.168.0.1 and returns the response.
+async fn pcp_request(internal_chan: &mut IpChannel, client_addr: SocketAddrV4, request: &[u8]) -> Vec<u8> {
+    let packet = Udpv4Packet::new(client_addr, addrv4!("192.168.0.1:5351"), request);
+    internal_chan.send(packet.ip_packet_box()).await.unwrap();
+    let packet = internal_chan.next().await.unwrap().unwrap();
+    let IpPacketVersion::V4(packet) = packet.version_box() else { panic!("expected ipv4") };
+    let Ipv4PacketProtocol::Udp(packet) = packet.protocol_box() else { panic!("expected udp") };
+    assert_eq!(packet.source_addr(), addrv4!("192.168.0.1:5351"));
+    assert_eq!(packet.destination_addr(), client_addr);
+    packet.data().to_vec()
+}
 
 #[tokio::test]
 async fn connect_to_outside_world() {
@@ -105,3 +157,1005 @@ async fn nat_tcp_reset() {
     }
 }
 
+
+#[tokio::test]
+async fn ping_through_nat() {
+    let internal_ip = ipv4!("192.168.1.5");
+    let external_ip = ipv4!("115.70.254.200");
+    let remote_ip = ipv4!("115.70.254.190");
+    const IDENTIFIER: u16 = 77;
+
+    let (mut nat, mut nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .port_restricted()
+        .build()
+    };
+    let (iface, mut internal_chan) = IpChannel::new(10);
+    let _port = nat.insert_iface(iface);
+
+    let request = Icmpv4Packet::new_echo_request(internal_ip, remote_ip, IDENTIFIER, 1, b"ping");
+    internal_chan.send(request.ip_packet_box()).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    let IpPacketVersion::V4(packet) = packet.version_box() else { panic!("expected IPv4") };
+    let Ipv4PacketProtocol::Icmp(packet) = packet.protocol_box() else { panic!("expected ICMP") };
+    assert!(packet.is_echo_request());
+    assert_eq!(packet.source_addr(), external_ip);
+    let external_identifier = packet.echo_identifier().unwrap();
+
+    // A reply from a host we didn't ping doesn't get through the restrictions.
+    let reply = Icmpv4Packet::new_echo_reply(ipv4!("1.1.1.1"), external_ip, external_identifier, 1, b"ping");
+    nat_iface.send(reply.ip_packet_box()).await.unwrap();
+    let reply = Icmpv4Packet::new_echo_reply(remote_ip, external_ip, external_identifier, 1, b"ping");
+    nat_iface.send(reply.ip_packet_box()).await.unwrap();
+
+    let packet = internal_chan.next().await.unwrap().unwrap();
+    assert_eq!(packet.source_addr(), remote_ip);
+    let IpPacketVersion::V4(packet) = packet.version_box() else { panic!("expected IPv4") };
+    let Ipv4PacketProtocol::Icmp(packet) = packet.protocol_box() else { panic!("expected ICMP") };
+    assert!(packet.is_echo_reply());
+    assert_eq!(packet.destination_addr(), internal_ip);
+    assert_eq!(packet.echo_identifier(), Some(IDENTIFIER));
+    let expected = Icmpv4Packet::new_echo_reply(remote_ip, internal_ip, IDENTIFIER, 1, b"ping");
+    assert_eq!(packet.as_bytes(), expected.as_bytes());
+}
+
+#[tokio::test]
+async fn icmp_echo_mappings_expire() {
+    let internal_ip = ipv4!("192.168.1.5");
+    let external_ip = ipv4!("115.70.254.200");
+    let remote_ip = ipv4!("115.70.254.190");
+    const IDENTIFIER: u16 = 77;
+    const ICMP_TIMEOUT: Duration = Duration::from_millis(100);
+
+    let (mut nat, mut nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .icmp_mapping_timeout(ICMP_TIMEOUT)
+        .build()
+    };
+    let (iface, mut internal_chan) = IpChannel::new(10);
+    let _port = nat.insert_iface(iface);
+
+    let request = Icmpv4Packet::new_echo_request(internal_ip, remote_ip, IDENTIFIER, 1, b"ping");
+    internal_chan.send(request.ip_packet_box()).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    let IpPacketVersion::V4(packet) = packet.version_box() else { panic!("expected IPv4") };
+    let Ipv4PacketProtocol::Icmp(packet) = packet.protocol_box() else { panic!("expected ICMP") };
+    let external_identifier = packet.echo_identifier().unwrap();
+
+    let reply = Icmpv4Packet::new_echo_reply(remote_ip, external_ip, external_identifier, 1, b"ping");
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, reply.clone().ip_packet_box()).await);
+    tokio::time::sleep(ICMP_TIMEOUT * 3).await;
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, reply.ip_packet_box()).await);
+}
+
+#[tokio::test]
+async fn icmp_errors_reach_internal_hosts() {
+    let internal_addr = addrv4!("192.168.1.5:45666");
+    let external_ip = ipv4!("115.70.254.200");
+    let remote_addr = addrv4!("115.70.254.190:45000");
+
+    let machine_0 = Machine::new().unwrap();
+    let machine_1 = Machine::new().unwrap();
+    let iface_0 = {
+        machine_0
+        .add_ip_iface()
+        .ipv4_addr(*internal_addr.ip())
+        .ipv4_default_route()
+        .build()
+        .unwrap()
+    };
+    let iface_1 = {
+        machine_1
+        .add_ip_iface()
+        .ipv4_addr(*remote_addr.ip())
+        .ipv4_default_route()
+        .build()
+        .unwrap()
+    };
+
+    let (mut nat, nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .build()
+    };
+    let _port = nat.insert_iface(iface_0);
+    crate::connect(nat_iface, iface_1);
+
+    // Nothing is listening on the remote machine, so it replies with a port unreachable error
+    // which has to be translated by the NAT.
+    let res = machine_0.spawn(async move {
+        // NOTE: Using a std socket since tokio doesn't wake a pending `recv` when the socket gets
+        // an error.
+        let socket = std::net::UdpSocket::bind(internal_addr).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        socket.connect(remote_addr).unwrap();
+        socket.send(b"hello").unwrap();
+        let mut buffer = [0u8; 16];
+        socket.recv(&mut buffer)
+    }).await.unwrap().unwrap();
+    match res {
+        Ok(_) => panic!("unexpected reply"),
+        Err(err) => assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused),
+    }
+}
+
+#[tokio::test]
+async fn udp_mappings_expire() {
+    let internal_addr = addrv4!("192.168.1.5:5000");
+    let external_ip = ipv4!("115.70.254.200");
+    let remote_addr = addrv4!("115.70.254.190:45000");
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    let (mut nat, mut nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .udp_mapping_timeout(TIMEOUT)
+        .build()
+    };
+    let (iface, mut internal_chan) = IpChannel::new(10);
+    let _port = nat.insert_iface(iface);
+
+    internal_chan.send(udp_packet_between(internal_addr, remote_addr)).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    let (external_port, _) = packet.ports().unwrap();
+    let mapped_addr = SocketAddrV4::new(external_ip, external_port);
+
+    // Outbound traffic keeps the mapping alive.
+    tokio::time::sleep(TIMEOUT / 2).await;
+    internal_chan.send(udp_packet_between(internal_addr, remote_addr)).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    assert_eq!(packet.ports().unwrap().0, external_port);
+    tokio::time::sleep(TIMEOUT / 2).await;
+    let packet = udp_packet_between(remote_addr, mapped_addr);
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+
+    // Inbound traffic doesn't, so the mapping expires.
+    tokio::time::sleep(TIMEOUT).await;
+    let packet = udp_packet_between(remote_addr, mapped_addr);
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+
+    internal_chan.send(udp_packet_between(internal_addr, remote_addr)).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    assert_ne!(packet.ports().unwrap().0, external_port);
+}
+
+#[tokio::test]
+async fn tcp_mapping_timeouts_depend_on_connection_state() {
+    let internal_addr = addrv4!("192.168.1.5:5000");
+    let external_ip = ipv4!("115.70.254.200");
+    let remote_addr = addrv4!("115.70.254.190:45000");
+    let other_remote_addr = addrv4!("115.70.254.191:45000");
+    const TRANSITORY_TIMEOUT: Duration = Duration::from_millis(200);
+
+    let (mut nat, mut nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .tcp_mapping_timeouts(Duration::from_secs(60), TRANSITORY_TIMEOUT)
+        .build()
+    };
+    let (iface, mut internal_chan) = IpChannel::new(10);
+    let _port = nat.insert_iface(iface);
+
+    let syn = TcpPacketFlags { syn: true, .. TcpPacketFlags::default() };
+    let syn_ack = TcpPacketFlags { syn: true, ack: true, .. TcpPacketFlags::default() };
+    let ack = TcpPacketFlags { ack: true, .. TcpPacketFlags::default() };
+    let fin = TcpPacketFlags { fin: true, ack: true, .. TcpPacketFlags::default() };
+
+    internal_chan.send(tcp_packet_between(internal_addr, remote_addr, syn)).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    let mapped_addr = SocketAddrV4::new(external_ip, packet.ports().unwrap().0);
+    let packet = tcp_packet_between(remote_addr, mapped_addr, syn_ack);
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    internal_chan.send(tcp_packet_between(internal_addr, remote_addr, ack)).await.unwrap();
+    let _packet = nat_iface.next().await.unwrap().unwrap();
+
+    // The connection is established so the mapping outlives the transitory timeout.
+    tokio::time::sleep(TRANSITORY_TIMEOUT * 2).await;
+    let packet = tcp_packet_between(remote_addr, mapped_addr, ack);
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+
+    // A second connection through the same mapping keeps it established while the first closes.
+    internal_chan.send(tcp_packet_between(internal_addr, other_remote_addr, syn)).await.unwrap();
+    let _packet = nat_iface.next().await.unwrap().unwrap();
+    let packet = tcp_packet_between(other_remote_addr, mapped_addr, syn_ack);
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    internal_chan.send(tcp_packet_between(internal_addr, other_remote_addr, ack)).await.unwrap();
+    let _packet = nat_iface.next().await.unwrap().unwrap();
+    internal_chan.send(tcp_packet_between(internal_addr, remote_addr, fin)).await.unwrap();
+    let _packet = nat_iface.next().await.unwrap().unwrap();
+    tokio::time::sleep(TRANSITORY_TIMEOUT * 2).await;
+    let packet = tcp_packet_between(other_remote_addr, mapped_addr, ack);
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+
+    // Once every connection is closing the mapping only lasts for the transitory timeout.
+    internal_chan.send(tcp_packet_between(internal_addr, other_remote_addr, fin)).await.unwrap();
+    let _packet = nat_iface.next().await.unwrap().unwrap();
+    tokio::time::sleep(TRANSITORY_TIMEOUT * 2).await;
+    let packet = tcp_packet_between(other_remote_addr, mapped_addr, ack);
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+}
+
+#[tokio::test]
+async fn tcp_connection_tracking() {
+    let internal_addr = addrv4!("192.168.1.5:5000");
+    let external_ip = ipv4!("115.70.254.200");
+    let remote_addr = addrv4!("115.70.254.190:45000");
+    let other_remote_addr = addrv4!("115.70.254.191:45000");
+    const TIME_WAIT: Duration = Duration::from_millis(200);
+
+    let (mut nat, mut nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .tcp_connection_tracking(TIME_WAIT)
+        .build()
+    };
+    let (iface, mut internal_chan) = IpChannel::new(10);
+    let _port = nat.insert_iface(iface);
+
+    let syn = TcpPacketFlags { syn: true, .. TcpPacketFlags::default() };
+    let syn_ack = TcpPacketFlags { syn: true, ack: true, .. TcpPacketFlags::default() };
+    let ack = TcpPacketFlags { ack: true, .. TcpPacketFlags::default() };
+    let fin = TcpPacketFlags { fin: true, ack: true, .. TcpPacketFlags::default() };
+
+    // Only a SYN can create a mapping.
+    let packet = tcp_packet_between(internal_addr, remote_addr, ack);
+    assert_eq!(passes_outbound(&mut nat_iface, &mut internal_chan, packet).await, None);
+    let packet = tcp_packet_between(internal_addr, remote_addr, syn);
+    let port = passes_outbound(&mut nat_iface, &mut internal_chan, packet).await.unwrap();
+    let mapped_addr = SocketAddrV4::new(external_ip, port);
+
+    // The remote side can't send anything but a SYN or RST until it has sent a SYN. A SYN from
+    // the remote side is a simultaneous open, as used for hole punching.
+    let packet = tcp_packet_between(remote_addr, mapped_addr, ack);
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    let packet = tcp_packet_between(remote_addr, mapped_addr, syn);
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    let packet = tcp_packet_between(internal_addr, remote_addr, syn_ack);
+    assert_eq!(passes_outbound(&mut nat_iface, &mut internal_chan, packet).await, Some(port));
+    let packet = tcp_packet_between(remote_addr, mapped_addr, ack);
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+
+    // Packets which don't belong to a connection are dropped.
+    let packet = tcp_packet_between(other_remote_addr, mapped_addr, ack);
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+
+    // After FINs in both directions the connection lingers in TIME_WAIT, then the mapping is
+    // removed.
+    let packet = tcp_packet_between(internal_addr, remote_addr, fin);
+    assert_eq!(passes_outbound(&mut nat_iface, &mut internal_chan, packet).await, Some(port));
+    let packet = tcp_packet_between(remote_addr, mapped_addr, fin);
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    let packet = tcp_packet_between(internal_addr, remote_addr, ack);
+    assert_eq!(passes_outbound(&mut nat_iface, &mut internal_chan, packet).await, Some(port));
+    tokio::time::sleep(TIME_WAIT + Duration::from_millis(100)).await;
+    let packet = tcp_packet_between(remote_addr, mapped_addr, ack);
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    let packet = tcp_packet_between(internal_addr, remote_addr, ack);
+    assert_eq!(passes_outbound(&mut nat_iface, &mut internal_chan, packet).await, None);
+    let packet = tcp_packet_between(internal_addr, remote_addr, syn);
+    let new_port = passes_outbound(&mut nat_iface, &mut internal_chan, packet).await.unwrap();
+    assert_ne!(new_port, port);
+
+    // On a port-restricted NAT, an unsolicited SYN from a remote address the filtering doesn't
+    // allow is dropped without opening a connection, so it doesn't stop the mapping closing once
+    // the real connection is reset.
+    let scanner_addr = other_remote_addr;
+    let rst = TcpPacketFlags { rst: true, .. TcpPacketFlags::default() };
+    let (mut nat, mut nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .port_restricted()
+        .tcp_connection_tracking(TIME_WAIT)
+        .build()
+    };
+    let (iface, mut internal_chan) = IpChannel::new(10);
+    let _port = nat.insert_iface(iface);
+
+    let packet = tcp_packet_between(internal_addr, remote_addr, syn);
+    let port = passes_outbound(&mut nat_iface, &mut internal_chan, packet).await.unwrap();
+    let mapped_addr = SocketAddrV4::new(external_ip, port);
+    let packet = tcp_packet_between(scanner_addr, mapped_addr, syn);
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    let packet = tcp_packet_between(internal_addr, remote_addr, rst);
+    assert_eq!(passes_outbound(&mut nat_iface, &mut internal_chan, packet).await, Some(port));
+    tokio::time::sleep(TIME_WAIT * 2).await;
+    assert!(nat.mappings().await.is_empty());
+}
+
+#[tokio::test]
+async fn mappings_and_events_can_be_inspected() {
+    let internal_addr = addrv4!("192.168.1.5:5000");
+    let external_ip = ipv4!("115.70.254.200");
+    let remote_addr = addrv4!("115.70.254.190:45000");
+    let other_remote_addr = addrv4!("115.70.254.191:45000");
+    const UDP_TIMEOUT: Duration = Duration::from_millis(200);
+
+    let (mut nat, mut nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .port_restricted()
+        .udp_mapping_timeout(UDP_TIMEOUT)
+        .pcp_server()
+        .build()
+    };
+    let (iface, mut internal_chan) = IpChannel::new(10);
+    let _port = nat.insert_iface(iface);
+    let mut events = nat.events();
+    let protocol = IpProtocol::Udp;
+
+    internal_chan.send(udp_packet_between(internal_addr, remote_addr)).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    let external_addr = SocketAddrV4::new(external_ip, packet.ports().unwrap().0);
+    assert_eq!(
+        events.next().await.unwrap(),
+        NatEvent::MappingCreated { protocol, internal_addr, external_addr },
+    );
+    assert_eq!(
+        events.next().await.unwrap(),
+        NatEvent::MappingRefreshed { protocol, internal_addr, external_addr },
+    );
+
+    let mappings = nat.mappings().await;
+    assert_eq!(mappings.len(), 1);
+    assert_eq!(mappings[0].protocol, protocol);
+    assert_eq!(mappings[0].internal_addr, internal_addr);
+    assert_eq!(mappings[0].external_addr, external_addr);
+    assert!(!mappings[0].forwarded);
+    assert_eq!(mappings[0].permitted_remotes, PermittedRemotes::Addrs(vec![remote_addr]));
+
+    let packet = udp_packet_between(other_remote_addr, external_addr);
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    assert_eq!(
+        events.next().await.unwrap(),
+        NatEvent::InboundDropped {
+            protocol,
+            remote_addr: other_remote_addr,
+            external_addr,
+            reason: InboundDropReason::Filtered,
+        },
+    );
+    let unmapped_addr = SocketAddrV4::new(external_ip, external_addr.port().wrapping_add(1));
+    let packet = udp_packet_between(remote_addr, unmapped_addr);
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    assert_eq!(
+        events.next().await.unwrap(),
+        NatEvent::InboundDropped {
+            protocol,
+            remote_addr,
+            external_addr: unmapped_addr,
+            reason: InboundDropReason::Unmapped,
+        },
+    );
+
+    // Packets sent to the NAT's own external address are dropped since hair-pinning is disabled.
+    internal_chan.send(udp_packet_between(internal_addr, external_addr)).await.unwrap();
+    assert_eq!(
+        events.next().await.unwrap(),
+        NatEvent::MappingRefreshed { protocol, internal_addr, external_addr },
+    );
+    assert_eq!(
+        events.next().await.unwrap(),
+        NatEvent::InboundDropped {
+            protocol,
+            remote_addr: external_addr,
+            external_addr,
+            reason: InboundDropReason::HairPinningDisabled,
+        },
+    );
+
+    // The mapping expires on time even though nothing else passes through the NAT.
+    assert_eq!(
+        tokio::time::timeout(UDP_TIMEOUT * 2, events.next()).await.unwrap().unwrap(),
+        NatEvent::MappingExpired { protocol, internal_addr, external_addr },
+    );
+    assert!(nat.mappings().await.is_empty());
+
+    // Mappings requested through NAT-PMP report when they're created and deleted.
+    let mut map_request = vec![0, 1, 0, 0];
+    map_request.extend(internal_addr.port().to_be_bytes());
+    map_request.extend(7000u16.to_be_bytes());
+    map_request.extend(3600u32.to_be_bytes());
+    let response = pcp_request(&mut internal_chan, internal_addr, &map_request).await;
+    assert_eq!(response[..4], [0, 129, 0, 0]);
+    let external_addr = SocketAddrV4::new(external_ip, u16::from_be_bytes([response[10], response[11]]));
+    assert_eq!(
+        events.next().await.unwrap(),
+        NatEvent::MappingCreated { protocol, internal_addr, external_addr },
+    );
+    map_request[8..12].copy_from_slice(&0u32.to_be_bytes());
+    let response = pcp_request(&mut internal_chan, internal_addr, &map_request).await;
+    assert_eq!(response[..4], [0, 129, 0, 0]);
+    assert_eq!(
+        events.next().await.unwrap(),
+        NatEvent::MappingRemoved { protocol, internal_addr, external_addr },
+    );
+    assert!(nat.mappings().await.is_empty());
+}
+
+#[tokio::test]
+async fn mapping_can_depend_on_remote_endpoint() {
+    let internal_addr = addrv4!("192.168.1.5:5000");
+    let external_ip = ipv4!("115.70.254.200");
+    let remote_addr_0 = addrv4!("115.70.254.190:45000");
+    let remote_addr_1 = addrv4!("115.70.254.190:45001");
+    let remote_addr_2 = addrv4!("115.70.254.191:45000");
+
+    async fn external_ports(nat_builder: NatBuilder, remote_addrs: &[SocketAddrV4]) -> Vec<u16> {
+        let (mut nat, mut nat_iface) = nat_builder.build();
+        let (iface, mut internal_chan) = IpChannel::new(10);
+        let _port = nat.insert_iface(iface);
+        let mut external_ports = Vec::new();
+        for remote_addr in remote_addrs {
+            internal_chan.send(udp_packet_between(addrv4!("192.168.1.5:5000"), *remote_addr)).await.unwrap();
+            let packet = nat_iface.next().await.unwrap().unwrap();
+            external_ports.push(packet.ports().unwrap().0);
+        }
+        external_ports
+    }
+
+    let remote_addrs = [remote_addr_0, remote_addr_1, remote_addr_2, remote_addr_0];
+    let nat_builder = || NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16));
+
+    let ports = external_ports(nat_builder(), &remote_addrs).await;
+    assert!(ports.iter().all(|port| *port == ports[0]));
+
+    let ports = external_ports(nat_builder().address_dependent_mapping(), &remote_addrs).await;
+    assert_eq!(ports[0], ports[1]);
+    assert_ne!(ports[0], ports[2]);
+    assert_eq!(ports[0], ports[3]);
+
+    let ports = external_ports(nat_builder().address_and_port_dependent_mapping(), &remote_addrs).await;
+    assert_ne!(ports[0], ports[1]);
+    assert_ne!(ports[0], ports[2]);
+    assert_ne!(ports[1], ports[2]);
+    assert_eq!(ports[0], ports[3]);
+
+    // Replies are only accepted on the port that was used to reach the remote endpoint.
+    let (mut nat, mut nat_iface) = nat_builder().address_and_port_dependent_mapping().build();
+    let (iface, mut internal_chan) = IpChannel::new(10);
+    let _port = nat.insert_iface(iface);
+    internal_chan.send(udp_packet_between(internal_addr, remote_addr_0)).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    let mapped_addr = SocketAddrV4::new(external_ip, packet.ports().unwrap().0);
+    let packet = udp_packet_between(remote_addr_0, mapped_addr);
+    nat_iface.send(packet).await.unwrap();
+    let packet = internal_chan.next().await.unwrap().unwrap();
+    assert_eq!(packet.destination_addr(), IpAddr::V4(*internal_addr.ip()));
+    assert_eq!(packet.ports().unwrap().1, internal_addr.port());
+}
+
+#[tokio::test]
+async fn external_address_pooling() {
+    let internal_addr_0 = addrv4!("192.168.1.5:5000");
+    let internal_addr_1 = addrv4!("192.168.1.6:5000");
+    let external_ip = ipv4!("115.70.254.200");
+    let external_network = Ipv4Network::new(ipv4!("115.70.254.204"), 30);
+    let remote_addrs = [
+        addrv4!("115.70.254.190:45000"),
+        addrv4!("115.70.254.191:45000"),
+        addrv4!("115.70.254.192:45000"),
+    ];
+
+    async fn external_addrs(
+        nat_builder: NatBuilder,
+        internal_addr: SocketAddrV4,
+        remote_addrs: &[SocketAddrV4],
+    ) -> (Nat, IpChannel, IpChannel, Vec<SocketAddrV4>) {
+        let (mut nat, mut nat_iface) = nat_builder.address_dependent_mapping().build();
+        let (iface, mut internal_chan) = IpChannel::new(10);
+        let _port = nat.insert_iface(iface);
+        let mut external_addrs = Vec::new();
+        for remote_addr in remote_addrs {
+            internal_chan.send(udp_packet_between(internal_addr, *remote_addr)).await.unwrap();
+            let packet = nat_iface.next().await.unwrap().unwrap();
+            let IpPacketVersion::V4(packet) = packet.version_box() else { unreachable!() };
+            let Ipv4PacketProtocol::Udp(packet) = packet.protocol_box() else { unreachable!() };
+            external_addrs.push(packet.source_addr());
+        }
+        (nat, nat_iface, internal_chan, external_addrs)
+    }
+
+    let nat_builder = || {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .external_ipv4_pool(external_network)
+        .pcp_server()
+    };
+
+    // With paired pooling all of a host's mappings use the same external address, but other hosts
+    // are given other addresses.
+    let (_nat, mut nat_iface, mut internal_chan, addrs) = {
+        external_addrs(nat_builder(), internal_addr_0, &remote_addrs).await
+    };
+    assert!(addrs.iter().all(|addr| addr.ip() == addrs[0].ip()));
+    assert_eq!(*addrs[0].ip(), external_ip);
+    internal_chan.send(udp_packet_between(internal_addr_1, remote_addrs[0])).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    let IpPacketVersion::V4(packet) = packet.version_box() else { unreachable!() };
+    let Ipv4PacketProtocol::Udp(packet) = packet.protocol_box() else { unreachable!() };
+    let other_host_addr = packet.source_addr();
+    assert!(external_network.contains(*other_host_addr.ip()));
+
+    // Each address has its own range of ports, so the other host's first mapping uses the same
+    // port as this host's first mapping.
+    assert_eq!(other_host_addr.port(), addrs[0].port());
+
+    // The NAT answers on all its addresses, and each mapping only on the address it uses.
+    for (external_addr, internal_addr) in [(other_host_addr, internal_addr_1), (addrs[0], internal_addr_0)] {
+        nat_iface.send(udp_packet_between(remote_addrs[0], external_addr)).await.unwrap();
+        let packet = internal_chan.next().await.unwrap().unwrap();
+        let IpPacketVersion::V4(packet) = packet.version_box() else { unreachable!() };
+        let Ipv4PacketProtocol::Udp(packet) = packet.protocol_box() else { unreachable!() };
+        assert_eq!(packet.destination_addr(), internal_addr);
+    }
+    let wrong_addr = SocketAddrV4::new(*other_host_addr.ip(), addrs[1].port());
+    let packet = udp_packet_between(remote_addrs[1], wrong_addr);
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+
+    // NAT-PMP reports each host's paired address as the NAT's external address, and maps ports
+    // on it.
+    let client_addr = SocketAddrV4::new(*internal_addr_1.ip(), 7000);
+    let response = pcp_request(&mut internal_chan, client_addr, &[0, 0]).await;
+    assert_eq!(response[8..12], other_host_addr.ip().octets());
+    let mut map_request = vec![0, 1, 0, 0];
+    map_request.extend(6000u16.to_be_bytes());
+    map_request.extend(0u16.to_be_bytes());
+    map_request.extend(3600u32.to_be_bytes());
+    let response = pcp_request(&mut internal_chan, client_addr, &map_request).await;
+    let mapped_addr = SocketAddrV4::new(*other_host_addr.ip(), u16::from_be_bytes([response[10], response[11]]));
+    let packet = udp_packet_between(remote_addrs[2], mapped_addr);
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+
+    // With arbitrary pooling each mapping can use a different address.
+    let (_nat, mut nat_iface, mut internal_chan, addrs) = {
+        external_addrs(nat_builder().arbitrary_pooling(), internal_addr_0, &remote_addrs).await
+    };
+    assert_ne!(addrs[0].ip(), addrs[1].ip());
+    assert_ne!(addrs[1].ip(), addrs[2].ip());
+    assert_ne!(addrs[0].ip(), addrs[2].ip());
+    for (remote_addr, addr) in remote_addrs.iter().zip(&addrs) {
+        let packet = udp_packet_between(*remote_addr, *addr);
+        assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    }
+}
+
+#[tokio::test]
+async fn port_allocation_strategies() {
+    async fn external_ports(nat_builder: NatBuilder, internal_ports: &[u16]) -> Vec<u16> {
+        let (mut nat, mut nat_iface) = nat_builder.build();
+        let (iface, mut internal_chan) = IpChannel::new(10);
+        let _port = nat.insert_iface(iface);
+        let mut external_ports = Vec::new();
+        for internal_port in internal_ports {
+            let internal_addr = SocketAddrV4::new(ipv4!("192.168.1.5"), *internal_port);
+            internal_chan.send(udp_packet_between(internal_addr, addrv4!("115.70.254.190:45000"))).await.unwrap();
+            let packet = nat_iface.next().await.unwrap().unwrap();
+            external_ports.push(packet.ports().unwrap().0);
+        }
+        external_ports
+    }
+
+    let nat_builder = || NatBuilder::new(ipv4!("115.70.254.200"), Ipv4Network::new(ipv4!("192.168.0.0"), 16));
+
+    let ports = external_ports(nat_builder(), &[5000, 5001, 5002]).await;
+    assert_eq!(ports, [1025, 1026, 1027]);
+
+    let ports = {
+        external_ports(nat_builder().port_range(2000..=2009).sequential_port_allocation(3), &[5000, 5001, 5002, 5003, 5004]).await
+    };
+    assert_eq!(ports, [2000, 2003, 2006, 2009, 2002]);
+
+    let ports = external_ports(nat_builder().port_range(2000..=2009).random_port_allocation(), &[5000, 5001, 5002]).await;
+    assert!(ports.iter().all(|port| (2000..=2009).contains(port)));
+    assert_ne!(ports[0], ports[1]);
+    assert_ne!(ports[1], ports[2]);
+
+    let ports = external_ports(nat_builder().port_preserving(), &[5000, 5001, 80]).await;
+    assert_eq!(ports, [5000, 5001, 1025]);
+
+    let ports = external_ports(nat_builder().port_parity(), &[5001, 5002, 5003]).await;
+    assert_eq!(ports, [1025, 1026, 1027]);
+    let ports = external_ports(nat_builder().port_parity(), &[5000, 5002, 5001]).await;
+    assert_eq!(ports, [1026, 1028, 1029]);
+
+    let ports = {
+        external_ports(nat_builder().sequential_port_allocation(10).port_contiguity(), &[5000, 5001, 6000, 5002]).await
+    };
+    assert_eq!(ports, [1025, 1026, 1035, 1027]);
+}
+
+#[tokio::test]
+async fn exhausted_port_range_evicts_mappings() {
+    let internal_addr = addrv4!("192.168.1.5:5000");
+    let other_internal_addr = addrv4!("192.168.1.5:5001");
+    let server_addr = addrv4!("192.168.1.6:80");
+    let external_ip = ipv4!("115.70.254.200");
+    let remote_addr = addrv4!("115.70.254.190:45000");
+    let other_remote_addr = addrv4!("115.70.254.191:45000");
+
+    let (mut nat, mut nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .port_restricted()
+        .port_range(2000..=2001)
+        .forward_port(IpProtocol::Udp, 2001, server_addr)
+        .build()
+    };
+    let (iface, mut internal_chan) = IpChannel::new(10);
+    let _port = nat.insert_iface(iface);
+    let mut events = nat.events();
+    let protocol = IpProtocol::Udp;
+    let external_addr = SocketAddrV4::new(external_ip, 2000);
+
+    let packet = udp_packet_between(internal_addr, remote_addr);
+    assert_eq!(passes_outbound(&mut nat_iface, &mut internal_chan, packet).await, Some(2000));
+    assert_eq!(
+        events.next().await.unwrap(),
+        NatEvent::MappingCreated { protocol, internal_addr, external_addr },
+    );
+    assert!(matches!(events.next().await.unwrap(), NatEvent::MappingRefreshed { .. }));
+
+    // The only port left is taken over, rather than the forwarded port, and the old mapping's
+    // restrictions are forgotten.
+    let packet = udp_packet_between(other_internal_addr, other_remote_addr);
+    assert_eq!(passes_outbound(&mut nat_iface, &mut internal_chan, packet).await, Some(2000));
+    assert_eq!(
+        events.next().await.unwrap(),
+        NatEvent::MappingExpired { protocol, internal_addr, external_addr },
+    );
+    assert_eq!(
+        events.next().await.unwrap(),
+        NatEvent::MappingCreated { protocol, internal_addr: other_internal_addr, external_addr },
+    );
+    let packet = udp_packet_between(remote_addr, external_addr);
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    let packet = udp_packet_between(other_remote_addr, external_addr);
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+
+    // No mapping can be made once every port is forwarded.
+    let (mut nat, mut nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .port_range(2000..=2000)
+        .forward_port(IpProtocol::Udp, 2000, server_addr)
+        .build()
+    };
+    let (iface, mut internal_chan) = IpChannel::new(10);
+    let _port = nat.insert_iface(iface);
+    let packet = udp_packet_between(internal_addr, remote_addr);
+    assert_eq!(passes_outbound(&mut nat_iface, &mut internal_chan, packet).await, None);
+    let packet = udp_packet_between(server_addr, remote_addr);
+    assert_eq!(passes_outbound(&mut nat_iface, &mut internal_chan, packet).await, Some(2000));
+}
+
+#[tokio::test]
+async fn forwarded_ports_and_dmz_host_accept_unsolicited_packets() {
+    let server_addr = addrv4!("192.168.1.5:80");
+    let dmz_ip = ipv4!("192.168.1.6");
+    let external_ip = ipv4!("115.70.254.200");
+    let remote_addr = addrv4!("115.70.254.190:45000");
+
+    let (mut nat, mut nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .port_restricted()
+        .forward_port(IpProtocol::Udp, 8080, server_addr)
+        .dmz_host(dmz_ip)
+        .build()
+    };
+    let (iface, mut internal_chan) = IpChannel::new(10);
+    let _port = nat.insert_iface(iface);
+
+    // The forwarded port reaches the server even though it hasn't sent anything yet.
+    let packet = udp_packet_between(remote_addr, SocketAddrV4::new(external_ip, 8080));
+    nat_iface.send(packet).await.unwrap();
+    let packet = internal_chan.next().await.unwrap().unwrap();
+    assert_eq!(packet.destination_addr(), IpAddr::V4(*server_addr.ip()));
+    assert_eq!(packet.ports().unwrap().1, server_addr.port());
+
+    // The server's replies, and anything else it sends, go out through the forwarded port.
+    internal_chan.send(udp_packet_between(server_addr, remote_addr)).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    assert_eq!(packet.ports().unwrap().0, 8080);
+    internal_chan.send(udp_packet_between(server_addr, addrv4!("115.70.254.191:1234"))).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    assert_eq!(packet.ports().unwrap().0, 8080);
+
+    // Other unsolicited packets go to the DMZ host, which can reply through the same port.
+    let packet = udp_packet_between(remote_addr, SocketAddrV4::new(external_ip, 9000));
+    nat_iface.send(packet).await.unwrap();
+    let packet = internal_chan.next().await.unwrap().unwrap();
+    assert_eq!(packet.destination_addr(), IpAddr::V4(dmz_ip));
+    assert_eq!(packet.ports().unwrap().1, 9000);
+    let dmz_addr = SocketAddrV4::new(dmz_ip, 9000);
+    internal_chan.send(udp_packet_between(dmz_addr, remote_addr)).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    assert_eq!(packet.ports().unwrap().0, 9000);
+
+    // The DMZ host's mapping isn't restricted to the remote address which created it.
+    let other_remote_addr = addrv4!("115.70.254.192:45000");
+    let packet = udp_packet_between(other_remote_addr, SocketAddrV4::new(external_ip, 9000));
+    nat_iface.send(packet).await.unwrap();
+    let packet = internal_chan.next().await.unwrap().unwrap();
+    assert_eq!(packet.destination_addr(), IpAddr::V4(dmz_ip));
+    assert_eq!(packet.ports().unwrap().1, 9000);
+
+    // Dynamic mappings still work, and are still restricted.
+    let client_addr = addrv4!("192.168.1.7:5000");
+    internal_chan.send(udp_packet_between(client_addr, remote_addr)).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    let mapped_addr = SocketAddrV4::new(external_ip, packet.ports().unwrap().0);
+    let packet = udp_packet_between(remote_addr, mapped_addr);
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    let packet = udp_packet_between(addrv4!("115.70.254.191:1234"), mapped_addr);
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+}
+
+#[tokio::test]
+async fn dmz_mappings_expire() {
+    let dmz_ip = ipv4!("192.168.1.6");
+    let external_ip = ipv4!("115.70.254.200");
+    let remote_addr = addrv4!("115.70.254.190:45000");
+    const UDP_TIMEOUT: Duration = Duration::from_millis(100);
+
+    let (mut nat, mut nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .dmz_host(dmz_ip)
+        .udp_mapping_timeout(UDP_TIMEOUT)
+        .build()
+    };
+    let (iface, mut internal_chan) = IpChannel::new(10);
+    let _port = nat.insert_iface(iface);
+
+    let packet = udp_packet_between(remote_addr, SocketAddrV4::new(external_ip, 80));
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    assert_eq!(nat.mappings().await.len(), 1);
+    tokio::time::sleep(UDP_TIMEOUT * 4).await;
+    assert!(nat.mappings().await.is_empty());
+}
+
+#[tokio::test]
+async fn nat_pmp_and_pcp_requests() {
+    let client_ip = ipv4!("192.168.1.5");
+    let external_ip = ipv4!("115.70.254.200");
+    let remote_addr = addrv4!("115.70.254.190:45000");
+
+    let (mut nat, mut nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .port_restricted()
+        .pcp_server()
+        .build()
+    };
+    let (iface, mut internal_chan) = IpChannel::new(10);
+    let _port = nat.insert_iface(iface);
+
+    // NAT-PMP external address request.
+    let client_addr = SocketAddrV4::new(client_ip, 5000);
+    let response = pcp_request(&mut internal_chan, client_addr, &[0, 0]).await;
+    assert_eq!(response.len(), 12);
+    assert_eq!(response[..4], [0, 128, 0, 0]);
+    assert_eq!(response[8..12], external_ip.octets());
+
+    // NAT-PMP UDP mapping for internal port 5000, asking for external port 7000 for an hour.
+    let mut map_request = vec![0, 1, 0, 0];
+    map_request.extend(5000u16.to_be_bytes());
+    map_request.extend(7000u16.to_be_bytes());
+    map_request.extend(3600u32.to_be_bytes());
+    let response = pcp_request(&mut internal_chan, client_addr, &map_request).await;
+    assert_eq!(response.len(), 16);
+    assert_eq!(response[..4], [0, 129, 0, 0]);
+    assert_eq!(u16::from_be_bytes([response[8], response[9]]), 5000);
+    assert_eq!(u16::from_be_bytes([response[10], response[11]]), 7000);
+    assert_eq!(u32::from_be_bytes([response[12], response[13], response[14], response[15]]), 3600);
+
+    // The mapping accepts packets from anywhere, despite the NAT being port restricted.
+    let packet = udp_packet_between(remote_addr, SocketAddrV4::new(external_ip, 7000));
+    nat_iface.send(packet).await.unwrap();
+    let packet = internal_chan.next().await.unwrap().unwrap();
+    assert_eq!(packet.destination_addr(), IpAddr::V4(client_ip));
+    assert_eq!(packet.ports().unwrap().1, 5000);
+    internal_chan.send(udp_packet_between(client_addr, remote_addr)).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    assert_eq!(packet.ports().unwrap().0, 7000);
+
+    // Deleting the mapping closes the port.
+    map_request[8..12].copy_from_slice(&0u32.to_be_bytes());
+    let response = pcp_request(&mut internal_chan, client_addr, &map_request).await;
+    assert_eq!(response[..4], [0, 129, 0, 0]);
+    let packet = udp_packet_between(remote_addr, SocketAddrV4::new(external_ip, 7000));
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+
+    // PCP TCP mapping with a one second lifetime.
+    let nonce = [7u8; 12];
+    let mut map_request = vec![2, 1, 0, 0];
+    map_request.extend(1u32.to_be_bytes());
+    map_request.extend(client_ip.to_ipv6_mapped().octets());
+    map_request.extend(nonce);
+    map_request.extend([6, 0, 0, 0]);
+    map_request.extend(6000u16.to_be_bytes());
+    map_request.extend(0u16.to_be_bytes());
+    map_request.extend([0; 16]);
+    let response = pcp_request(&mut internal_chan, client_addr, &map_request).await;
+    assert_eq!(response.len(), 60);
+    assert_eq!(response[..4], [2, 129, 0, 0]);
+    assert_eq!(u32::from_be_bytes([response[4], response[5], response[6], response[7]]), 1);
+    assert_eq!(response[24..36], nonce);
+    assert_eq!(u16::from_be_bytes([response[40], response[41]]), 6000);
+    let external_port = u16::from_be_bytes([response[42], response[43]]);
+    assert_eq!(response[44..60], external_ip.to_ipv6_mapped().octets());
+
+    let ack = TcpPacketFlags { ack: true, .. TcpPacketFlags::default() };
+    let mapped_addr = SocketAddrV4::new(external_ip, external_port);
+    let packet = tcp_packet_between(remote_addr, mapped_addr, ack);
+    assert!(passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let packet = tcp_packet_between(remote_addr, mapped_addr, ack);
+    assert!(!passes_inbound(&mut nat_iface, &mut internal_chan, packet).await);
+
+    // PCP requests must say who they're from.
+    map_request[8..24].copy_from_slice(&ipv4!("192.168.1.6").to_ipv6_mapped().octets());
+    let response = pcp_request(&mut internal_chan, client_addr, &map_request).await;
+    assert_eq!(response[..4], [2, 129, 0, 12]);
+
+    // A mapping requested for an address which already has a mapping reuses its port.
+    let mapped_client_addr = SocketAddrV4::new(client_ip, 8000);
+    internal_chan.send(udp_packet_between(mapped_client_addr, remote_addr)).await.unwrap();
+    let packet = nat_iface.next().await.unwrap().unwrap();
+    let mapped_port = packet.ports().unwrap().0;
+    let mut map_request = vec![0, 1, 0, 0];
+    map_request.extend(8000u16.to_be_bytes());
+    map_request.extend(9000u16.to_be_bytes());
+    map_request.extend(3600u32.to_be_bytes());
+    let response = pcp_request(&mut internal_chan, client_addr, &map_request).await;
+    assert_eq!(response[..4], [0, 129, 0, 0]);
+    assert_eq!(u16::from_be_bytes([response[10], response[11]]), mapped_port);
+    let packet = udp_packet_between(mapped_client_addr, addrv4!("115.70.254.191:45000"));
+    assert_eq!(passes_outbound(&mut nat_iface, &mut internal_chan, packet).await, Some(mapped_port));
+}
+
+#[tokio::test]
+async fn upnp_igd_maps_ports() {
+    let internal_addr = addrv4!("192.168.1.5:9000");
+    let external_ip = ipv4!("115.70.254.200");
+    let remote_addr = addrv4!("115.70.254.190:45000");
+
+    let machine_0 = Machine::new().unwrap();
+    let machine_1 = Machine::new().unwrap();
+    let iface_0 = {
+        machine_0
+        .add_ip_iface()
+        .ipv4_addr(*internal_addr.ip())
+        .ipv4_default_route()
+        .build()
+        .unwrap()
+    };
+    let iface_1 = {
+        machine_1
+        .add_ip_iface()
+        .ipv4_addr(*remote_addr.ip())
+        .ipv4_default_route()
+        .build()
+        .unwrap()
+    };
+
+    let (mut nat, nat_iface) = {
+        NatBuilder::new(external_ip, Ipv4Network::new(ipv4!("192.168.0.0"), 16))
+        .port_restricted()
+        .upnp_igd()
+        .build()
+    };
+    let _port = nat.insert_iface(iface_0);
+    crate::connect(nat_iface, iface_1);
+    let events = nat.events();
+
+    fn soap_request(location: &str, action: &str, args: &str) -> String {
+        use std::io::Read;
+
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+            s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+            <s:Body><u:{action} xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">{args}</u:{action}></s:Body>\
+            </s:Envelope>\r\n"
+        );
+        let request = format!(
+            "POST /ctl/IPConn HTTP/1.1\r\n\
+            Host: {location}\r\n\
+            Content-Type: text/xml; charset=\"utf-8\"\r\n\
+            Content-Length: {}\r\n\
+            SOAPAction: \"urn:schemas-upnp-org:service:WANIPConnection:1#{action}\"\r\n\
+            \r\n\
+            {body}",
+            body.len(),
+        );
+        let mut stream = std::net::TcpStream::connect(location).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    let task_0 = machine_0.spawn(async move {
+        // Find the gateway with SSDP.
+        let socket = std::net::UdpSocket::bind(addrv4!("0.0.0.0:0")).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let search = {
+            "M-SEARCH * HTTP/1.1\r\n\
+            HOST: 239.255.255.250:1900\r\n\
+            MAN: \"ssdp:discover\"\r\n\
+            MX: 1\r\n\
+            ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+            \r\n"
+        };
+        socket.send_to(search.as_bytes(), addrv4!("239.255.255.250:1900")).unwrap();
+        let mut buffer = [0u8; 1024];
+        let (len, _) = socket.recv_from(&mut buffer).unwrap();
+        let response = str::from_utf8(&buffer[..len]).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n"));
+        assert!(response.contains("LOCATION: http://192.168.0.1:5000/rootDesc.xml\r\n"));
+        let location = "192.168.0.1:5000";
+
+        let response = soap_request(location, "GetExternalIPAddress", "");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("<NewExternalIPAddress>115.70.254.200</NewExternalIPAddress>"));
+
+        let add_args = {
+            "<NewRemoteHost></NewRemoteHost>\
+            <NewExternalPort>8000</NewExternalPort>\
+            <NewProtocol>UDP</NewProtocol>\
+            <NewInternalPort>9000</NewInternalPort>\
+            <NewInternalClient>192.168.1.5</NewInternalClient>\
+            <NewEnabled>1</NewEnabled>\
+            <NewPortMappingDescription>test</NewPortMappingDescription>\
+            <NewLeaseDuration>0</NewLeaseDuration>"
+        };
+        let response = soap_request(location, "AddPortMapping", add_args);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("AddPortMappingResponse"));
+
+        // The mapping accepts packets from anywhere, despite the NAT being port restricted.
+        let socket = tokio::net::UdpSocket::bind(internal_addr).await.unwrap();
+        let mut buffer = [0u8; 16];
+        let (len, addr) = socket.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..len], b"hello");
+        assert_eq!(addr, std::net::SocketAddr::V4(remote_addr));
+
+        let delete_args = "<NewRemoteHost></NewRemoteHost><NewExternalPort>8000</NewExternalPort><NewProtocol>UDP</NewProtocol>";
+        let response = soap_request(location, "DeletePortMapping", delete_args);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let response = soap_request(location, "DeletePortMapping", delete_args);
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(response.contains("<errorCode>714</errorCode>"));
+
+        // Mappings can only be deleted by the host they forward to.
+        let add_args = {
+            "<NewRemoteHost></NewRemoteHost>\
+            <NewExternalPort>8001</NewExternalPort>\
+            <NewProtocol>UDP</NewProtocol>\
+            <NewInternalPort>9000</NewInternalPort>\
+            <NewInternalClient>192.168.1.6</NewInternalClient>\
+            <NewEnabled>1</NewEnabled>\
+            <NewPortMappingDescription>test</NewPortMappingDescription>\
+            <NewLeaseDuration>0</NewLeaseDuration>"
+        };
+        let response = soap_request(location, "AddPortMapping", add_args);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let delete_args = "<NewRemoteHost></NewRemoteHost><NewExternalPort>8001</NewExternalPort><NewProtocol>UDP</NewProtocol>";
+        let response = soap_request(location, "DeletePortMapping", delete_args);
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(response.contains("<errorCode>606</errorCode>"));
+    });
+
+    let task_1 = machine_1.spawn(async move {
+        let socket = tokio::net::UdpSocket::bind(remote_addr).await.unwrap();
+        // Keep sending until the mapping has been added on the other machine.
+        for _ in 0..50 {
+            socket.send_to(b"hello", addrv4!("115.70.254.200:8000")).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    });
+
+    let () = task_0.join().await.unwrap().unwrap();
+    drop(task_1);
+
+    // Deleting a mapping through UPnP is reported.
+    let removed = NatEvent::MappingRemoved {
+        protocol: IpProtocol::Udp,
+        internal_addr,
+        external_addr: SocketAddrV4::new(external_ip, 8000),
+    };
+    let events: Vec<NatEvent> = events.take_until(tokio::time::sleep(Duration::from_millis(100))).collect().await;
+    assert!(events.contains(&removed));
+}
@@ -0,0 +1,46 @@
+use crate::{
+    priv_prelude::*,
+    tests::udp_packet,
+};
+
+#[tokio::test]
+async fn iface_roams_between_hubs() {
+    let mut hub_0 = IpHub::new();
+    let mut hub_1 = IpHub::new();
+
+    let (roaming_iface, mut roaming_chan) = IpChannel::new(10);
+    let (peer_iface_0, mut peer_chan_0) = IpChannel::new(10);
+    let (peer_iface_1, mut peer_chan_1) = IpChannel::new(10);
+    let _peer_port_0 = hub_0.insert_iface(peer_iface_0);
+    let _peer_port_1 = hub_1.insert_iface(peer_iface_1);
+
+    let port = hub_0.insert_iface(roaming_iface);
+    assert!(port.is_live());
+    peer_chan_0.send(udp_packet(100)).await.unwrap();
+    let _packet = roaming_chan.next().await.unwrap().unwrap();
+
+    let roaming_iface = port.remove();
+    let port = hub_1.insert_iface(roaming_iface);
+    assert!(port.is_live());
+    peer_chan_0.send(udp_packet(100)).await.unwrap();
+    peer_chan_1.send(udp_packet(200)).await.unwrap();
+    let packet = roaming_chan.next().await.unwrap().unwrap();
+    assert_eq!(packet.len(), 200);
+    let res = tokio::time::timeout(Duration::from_millis(100), roaming_chan.next()).await;
+    assert!(res.is_err());
+}
+
+#[tokio::test]
+async fn port_dies_when_iface_closes() {
+    let (mut nat, _nat_iface) = {
+        NatBuilder::new(ipv4!("1.2.3.4"), ipv4_network!("10.0.0.0/8"))
+        .build()
+    };
+    let (iface, chan) = IpChannel::new(10);
+    let port = nat.insert_iface(iface);
+    assert!(port.is_live());
+
+    drop(chan);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!port.is_live());
+}
@@ -0,0 +1,82 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{DropReason, EcnMarking, PacketCount, StatsHandle},
+    packet::EcnCodepoint,
+    tests::udp_packet,
+};
+
+#[tokio::test]
+async fn packets_are_paced_and_excess_dropped() {
+    const BITS_PER_SECOND: u64 = 8_000;
+    const PACKET_LEN: usize = 100;
+    const QUEUE_LEN: usize = 3;
+    const TRANSMIT_TIME: Duration = Duration::from_millis(100);
+
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let mut chan_0 = Box::pin(chan_0.with_rate_limit(BITS_PER_SECOND, QUEUE_LEN));
+
+    let send_instant = Instant::now();
+    for _ in 0..(QUEUE_LEN + 2) {
+        chan_0.feed(udp_packet(PACKET_LEN)).await.unwrap();
+    }
+    chan_0.flush().await.unwrap();
+    for _ in 0..QUEUE_LEN {
+        let _packet = chan_1.next().await.unwrap().unwrap();
+    }
+    assert!(Instant::now() - send_instant >= TRANSMIT_TIME * QUEUE_LEN as u32);
+
+    let res = tokio::time::timeout(TRANSMIT_TIME * 3, chan_1.next()).await;
+    assert!(res.is_err());
+}
+
+fn ipv4_header_checksum_is_valid(packet: &IpPacket) -> bool {
+    let header = &packet.as_bytes()[..20];
+    let mut sum = 0u32;
+    for word in header.chunks(2) {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum == 0xffff
+}
+
+#[tokio::test]
+async fn congestion_is_signalled_with_step_marking() {
+    const BITS_PER_SECOND: u64 = 8_000;
+    const PACKET_LEN: usize = 100;
+    const THRESHOLD: Duration = Duration::from_millis(150);
+
+    let stats = StatsHandle::new();
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let mut chan_0 = Box::pin({
+        chan_0
+        .with_rate_limit(BITS_PER_SECOND, 10)
+        .ecn_marking(EcnMarking::Step { threshold: THRESHOLD })
+        .count_drops(&stats)
+    });
+
+    // Each packet takes 100ms to transmit, so the third and fourth packets arrive to find a queue
+    // delay above the threshold and get marked. The last packet isn't ECN-capable so it's dropped.
+    for _ in 0..4 {
+        let mut packet = udp_packet(PACKET_LEN);
+        packet.set_ecn(EcnCodepoint::Ect0);
+        chan_0.feed(packet).await.unwrap();
+    }
+    chan_0.feed(udp_packet(PACKET_LEN)).await.unwrap();
+    chan_0.flush().await.unwrap();
+
+    let mut codepoints = Vec::new();
+    for _ in 0..4 {
+        let packet = chan_1.next().await.unwrap().unwrap();
+        assert!(ipv4_header_checksum_is_valid(&packet));
+        codepoints.push(packet.ecn());
+    }
+    assert_eq!(
+        codepoints,
+        [EcnCodepoint::Ect0, EcnCodepoint::Ect0, EcnCodepoint::Ce, EcnCodepoint::Ce],
+    );
+
+    let dropped = stats.snapshot().sent.dropped(DropReason::Congestion);
+    assert_eq!(dropped, PacketCount { packets: 1, bytes: PACKET_LEN as u64 });
+}
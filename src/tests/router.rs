@@ -0,0 +1,57 @@
+use crate::{
+    priv_prelude::*,
+    tests::udp_packet,
+};
+
+fn udp_packet_to(destination_addr: Ipv4Addr, ttl: u8) -> Box<IpPacket> {
+    let mut packet = udp_packet(100);
+    let IpPacketVersion::V4(ipv4_packet) = packet.version_mut() else { unreachable!() };
+    ipv4_packet.set_destination_addr(destination_addr);
+    ipv4_packet.set_ttl(ttl);
+    packet
+}
+
+fn icmpv4_type_and_code(packet: Box<IpPacket>) -> (u8, u8) {
+    let IpPacketVersion::V4(packet) = packet.version_box() else { panic!("expected IPv4") };
+    let Ipv4PacketProtocol::Icmp(packet) = packet.protocol_box() else { panic!("expected ICMP") };
+    (packet.icmp_type(), packet.code())
+}
+
+#[tokio::test]
+async fn longest_prefix_match_and_icmp_errors() {
+    let router_addr = ipv4!("10.255.255.254");
+    let mut router = IpRouter::new(router_addr, ipv6!("fd00::1"));
+
+    let (chan_a, mut router_chan_a) = IpChannel::new(10);
+    let (chan_b, mut router_chan_b) = IpChannel::new(10);
+    let (chan_c, mut router_chan_c) = IpChannel::new(10);
+    router.insert_iface(chan_a, [ipv4_network!("10.0.0.0/16")]);
+    router.insert_iface(chan_b, [ipv4_network!("10.1.0.0/16")]);
+    router.insert_iface(chan_c, [ipv4_network!("10.1.1.0/24")]);
+
+    router_chan_a.send(udp_packet_to(ipv4!("10.1.1.5"), 64)).await.unwrap();
+    let packet = router_chan_c.next().await.unwrap().unwrap();
+    assert_eq!(packet.destination_addr(), ipv4!("10.1.1.5"));
+    let IpPacketVersion::V4(ipv4_packet) = packet.version_ref() else { panic!("expected IPv4") };
+    assert_eq!(ipv4_packet.ttl(), 63);
+
+    router_chan_a.send(udp_packet_to(ipv4!("10.1.2.5"), 64)).await.unwrap();
+    let packet = router_chan_b.next().await.unwrap().unwrap();
+    assert_eq!(packet.destination_addr(), ipv4!("10.1.2.5"));
+
+    router_chan_a.send(udp_packet_to(ipv4!("192.168.0.1"), 64)).await.unwrap();
+    let packet = router_chan_a.next().await.unwrap().unwrap();
+    assert_eq!(packet.source_addr(), router_addr);
+    assert_eq!(packet.destination_addr(), ipv4!("10.0.0.1"));
+    assert_eq!(icmpv4_type_and_code(packet), (3, 0));
+
+    router_chan_a.send(udp_packet_to(ipv4!("10.1.1.5"), 1)).await.unwrap();
+    let packet = router_chan_a.next().await.unwrap().unwrap();
+    assert_eq!(packet.source_addr(), router_addr);
+    assert_eq!(icmpv4_type_and_code(packet), (11, 0));
+
+    let res = tokio::time::timeout(Duration::from_millis(100), router_chan_c.next()).await;
+    assert!(res.is_err());
+    let res = tokio::time::timeout(Duration::from_millis(100), router_chan_b.next()).await;
+    assert!(res.is_err());
+}
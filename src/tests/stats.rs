@@ -0,0 +1,39 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{Direction, DropReason, PacketCount, Stats, StatsHandle},
+    tests::udp_packet,
+};
+
+#[tokio::test]
+async fn packets_and_drops_are_counted() {
+    const PACKET_LEN: usize = 100;
+
+    let stats = StatsHandle::new();
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let chan_0 = {
+        chan_0
+        .with_loss(1.0, Duration::from_secs(1))
+        .direction(Direction::Sent)
+        .count_drops(&stats)
+    };
+    let mut chan_0 = Box::pin(Stats::with_handle(chan_0, &stats));
+
+    for _ in 0..2 {
+        chan_1.send(udp_packet(PACKET_LEN)).await.unwrap();
+        let _packet = chan_0.next().await.unwrap().unwrap();
+    }
+    chan_0.send(udp_packet(PACKET_LEN)).await.unwrap();
+
+    let snapshot = stats.snapshot();
+    let two_packets = PacketCount { packets: 2, bytes: 2 * PACKET_LEN as u64 };
+    let one_packet = PacketCount { packets: 1, bytes: PACKET_LEN as u64 };
+    assert_eq!(snapshot.received.total, two_packets);
+    assert_eq!(snapshot.received.udp, two_packets);
+    assert_eq!(snapshot.received.tcp, PacketCount::default());
+    assert_eq!(snapshot.direction(Direction::Sent).total, one_packet);
+    assert_eq!(snapshot.sent.dropped(DropReason::Loss), one_packet);
+    assert_eq!(snapshot.received.dropped(DropReason::Loss), PacketCount::default());
+
+    stats.reset();
+    assert_eq!(stats.snapshot(), Default::default());
+}
@@ -0,0 +1,111 @@
+use crate::{
+    priv_prelude::*,
+    adapter::{DropReason, PacketCount, StatsHandle},
+    tests::udp_packet,
+};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn items_dropped_while_down() {
+    let (chan_0, mut chan_1) = BiChannel::new(10);
+    let chan_0 = chan_0.with_switch();
+    let handle = chan_0.handle();
+    let mut chan_0 = Box::pin(chan_0);
+
+    chan_0.send(0).await.unwrap();
+    assert_eq!(chan_1.next().await, Some(0));
+
+    handle.down();
+    assert!(!handle.is_up());
+    chan_0.send(1).await.unwrap();
+    chan_1.send(2).await.unwrap();
+    let res = tokio::time::timeout(Duration::from_millis(100), chan_1.next()).await;
+    assert!(res.is_err());
+    let res = tokio::time::timeout(Duration::from_millis(100), chan_0.next()).await;
+    assert!(res.is_err());
+
+    handle.up();
+    chan_0.send(3).await.unwrap();
+    assert_eq!(chan_1.next().await, Some(3));
+    chan_1.send(4).await.unwrap();
+    assert_eq!(chan_0.next().await, Some(4));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn packets_dropped_while_down_are_counted() {
+    const PACKET_LEN: usize = 100;
+
+    let stats = StatsHandle::new();
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let chan_0 = chan_0.with_switch().count_drops(&stats);
+    let handle = chan_0.handle();
+    let mut chan_0 = Box::pin(chan_0);
+
+    handle.down();
+    chan_0.send(udp_packet(PACKET_LEN)).await.unwrap();
+    chan_1.send(udp_packet(PACKET_LEN)).await.unwrap();
+    let res = tokio::time::timeout(Duration::from_millis(100), chan_0.next()).await;
+    assert!(res.is_err());
+
+    let snapshot = stats.snapshot();
+    let one_packet = PacketCount { packets: 1, bytes: PACKET_LEN as u64 };
+    assert_eq!(snapshot.sent.dropped(DropReason::LinkDown), one_packet);
+    assert_eq!(snapshot.received.dropped(DropReason::LinkDown), one_packet);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn items_queued_while_down() {
+    let (chan_0, mut chan_1) = BiChannel::new(10);
+    let chan_0 = chan_0.with_switch().queue_while_down();
+    let handle = chan_0.handle();
+    let mut chan_0 = Box::pin(chan_0);
+
+    handle.down();
+    chan_0.feed(0).await.unwrap();
+    chan_0.feed(1).await.unwrap();
+    chan_1.send(2).await.unwrap();
+    let res = tokio::time::timeout(Duration::from_millis(100), chan_1.next()).await;
+    assert!(res.is_err());
+
+    let up_task = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        handle.up();
+    });
+    assert_eq!(chan_0.next().await, Some(2));
+    chan_0.flush().await.unwrap();
+    assert_eq!(chan_1.next().await, Some(0));
+    assert_eq!(chan_1.next().await, Some(1));
+    up_task.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn close_sends_queued_items() {
+    let (chan_0, mut chan_1) = BiChannel::new(10);
+    let chan_0 = chan_0.with_switch().queue_while_down();
+    let handle = chan_0.handle();
+    let mut chan_0 = Box::pin(chan_0);
+
+    handle.down();
+    chan_0.feed(0).await.unwrap();
+    let up_task = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        handle.up();
+    });
+    chan_0.close().await.unwrap();
+    assert_eq!(chan_1.next().await, Some(0));
+    up_task.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn flap_follows_schedule() {
+    const DOWN_FOR: Duration = Duration::from_millis(200);
+
+    let (chan_0, _chan_1) = BiChannel::<()>::new(1);
+    let chan_0 = chan_0.with_switch();
+    let handle = chan_0.handle();
+
+    handle.flap([(DOWN_FOR, Duration::ZERO)]);
+    tokio::time::sleep(DOWN_FOR / 2).await;
+    assert!(!handle.is_up());
+    tokio::time::sleep(DOWN_FOR).await;
+    assert!(handle.is_up());
+}
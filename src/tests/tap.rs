@@ -0,0 +1,32 @@
+use crate::{
+    priv_prelude::*,
+    adapter::Direction,
+};
+
+#[tokio::test]
+async fn tap_copies_packets_in_both_directions() {
+    let (chan_0, mut chan_1) = IpChannel::new(10);
+    let (chan_0, mut tapped) = chan_0.tap();
+    let mut chan_0 = Box::pin(chan_0);
+
+    let mut packet_0 = Tcpv4Packet::new();
+    packet_0.set_source_addr(addrv4!("10.0.0.1:1000"));
+    let mut packet_1 = Tcpv4Packet::new();
+    packet_1.set_source_addr(addrv4!("10.0.0.2:2000"));
+
+    chan_0.send(packet_0.ip_packet_box()).await.unwrap();
+    let received = chan_1.next().await.unwrap().unwrap();
+    assert_eq!(received.source_addr(), IpAddr::V4(ipv4!("10.0.0.1")));
+
+    chan_1.send(packet_1.ip_packet_box()).await.unwrap();
+    let received = chan_0.next().await.unwrap().unwrap();
+    assert_eq!(received.source_addr(), IpAddr::V4(ipv4!("10.0.0.2")));
+
+    let tapped_0 = tapped.next().await.unwrap();
+    assert_eq!(tapped_0.direction, Direction::Sent);
+    assert_eq!(tapped_0.packet.source_addr(), IpAddr::V4(ipv4!("10.0.0.1")));
+    let tapped_1 = tapped.next().await.unwrap();
+    assert_eq!(tapped_1.direction, Direction::Received);
+    assert_eq!(tapped_1.packet.source_addr(), IpAddr::V4(ipv4!("10.0.0.2")));
+    assert!(tapped_0.instant <= tapped_1.instant);
+}